@@ -0,0 +1,553 @@
+use crate::data::compression::Compression;
+use crate::data::csv_schema::CsvOptions;
+use crate::data::trip_struct::FIELD_NAMES;
+use encoding_rs::{Encoding, UTF_8};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+// Si no se indica otro con --config o CONFIG_PATH, se usa este cuando existe
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+const MIN_BUFFER_BYTES: usize = 4 * 1024;
+const MAX_BUFFER_BYTES: usize = 64 * 1024 * 1024;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// Qué hacer al arrancar si el índice no corresponde al dataset actual
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RebuildPolicy {
+    // Reconstruir solo si el dataset o el formato cambiaron
+    #[default]
+    Auto,
+    // Reconstruir en cada arranque
+    Always,
+    // No reconstruir nunca: si el índice no sirve, el servidor no arranca
+    Never,
+}
+
+impl FromStr for RebuildPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "auto" => Ok(RebuildPolicy::Auto),
+            "always" => Ok(RebuildPolicy::Always),
+            "never" => Ok(RebuildPolicy::Never),
+            _ => Err(format!(
+                "Política de reconstrucción inválida: {} (auto, always o never)",
+                value
+            )),
+        }
+    }
+}
+
+// Compresión de los CSV del dataset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CsvCompression {
+    // Por extensión o bytes mágicos de cada archivo
+    #[default]
+    Auto,
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+impl FromStr for CsvCompression {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "auto" => Ok(CsvCompression::Auto),
+            "none" => Ok(CsvCompression::None),
+            "gzip" => Ok(CsvCompression::Gzip),
+            "zstd" => Ok(CsvCompression::Zstd),
+            "bzip2" => Ok(CsvCompression::Bzip2),
+            _ => Err(format!(
+                "Compresión inválida: {} (auto, none, gzip, zstd o bzip2)",
+                value
+            )),
+        }
+    }
+}
+
+// Configuración de una instancia. Se arma en este orden, cada fuente pisa a la
+// anterior: valores por defecto, archivo TOML, variables de entorno y flags.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Archivo, directorio de particiones o glob
+    pub dataset_path: String,
+    // Caché con filas y rango de fechas de cada partición
+    pub partitions_cache: String,
+    pub index_dir: String,
+    pub bind_address: String,
+    pub default_per_page: usize,
+    pub max_per_page: usize,
+    // Buffer de lectura del dataset
+    pub read_buffer_bytes: usize,
+    // Buffer de escritura de los archivos del índice al construirlo
+    pub write_buffer_bytes: usize,
+    pub rebuild_policy: RebuildPolicy,
+    // Dialecto de los CSV del dataset, los Parquet solo usan los alias
+    pub csv_delimiter: char,
+    pub csv_quote: char,
+    pub csv_quoting: bool,
+    pub csv_escape: Option<char>,
+    pub csv_has_headers: bool,
+    // Nombres de columna si el archivo no tiene cabecera, vacío usa el orden canónico
+    pub csv_columns: Vec<String>,
+    // Cabecera del archivo -> campo de Trip, se suman a los alias de NYC TLC
+    pub csv_aliases: BTreeMap<String, String>,
+    // Etiqueta WHATWG (latin1, windows-1252...), None lee UTF-8
+    pub csv_encoding: Option<String>,
+    pub csv_compression: CsvCompression,
+    // Sin token no se montan las rutas de /admin; con él cada petición tiene que traer
+    // Authorization: Bearer <token>. No se muestra en /admin/config.
+    #[serde(skip_serializing)]
+    pub admin_token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            dataset_path: "src/data/data.csv".to_string(),
+            partitions_cache: "tmp/dataset_partitions.json".to_string(),
+            index_dir: "tmp/hash_index".to_string(),
+            bind_address: "0.0.0.0:3000".to_string(),
+            default_per_page: 50,
+            max_per_page: 1000,
+            read_buffer_bytes: 64 * 1024,
+            write_buffer_bytes: 1024 * 1024,
+            rebuild_policy: RebuildPolicy::Auto,
+            csv_delimiter: ',',
+            csv_quote: '"',
+            csv_quoting: true,
+            csv_escape: None,
+            csv_has_headers: true,
+            csv_columns: Vec::new(),
+            csv_aliases: BTreeMap::new(),
+            csv_encoding: None,
+            csv_compression: CsvCompression::Auto,
+            admin_token: None,
+        }
+    }
+}
+
+// (flag, variable de entorno) de cada campo
+const SETTINGS: [(&str, &str); 19] = [
+    ("--dataset", "DATASET_PATH"),
+    ("--partitions-cache", "PARTITIONS_CACHE"),
+    ("--index-dir", "INDEX_DIR"),
+    ("--bind", "BIND_ADDRESS"),
+    ("--default-per-page", "DEFAULT_PER_PAGE"),
+    ("--max-per-page", "MAX_PER_PAGE"),
+    ("--read-buffer-bytes", "READ_BUFFER_BYTES"),
+    ("--write-buffer-bytes", "WRITE_BUFFER_BYTES"),
+    ("--rebuild-policy", "REBUILD_POLICY"),
+    ("--csv-delimiter", "CSV_DELIMITER"),
+    ("--csv-quote", "CSV_QUOTE"),
+    ("--csv-quoting", "CSV_QUOTING"),
+    ("--csv-escape", "CSV_ESCAPE"),
+    ("--csv-has-headers", "CSV_HAS_HEADERS"),
+    ("--csv-columns", "CSV_COLUMNS"),
+    ("--csv-aliases", "CSV_ALIASES"),
+    ("--csv-encoding", "CSV_ENCODING"),
+    ("--csv-compression", "CSV_COMPRESSION"),
+    ("--admin-token", "ADMIN_TOKEN"),
+];
+
+// Flags de los subcomandos, pasan sin ser de configuración
+const COMMAND_FLAGS: [&str; 1] = ["--repair"];
+
+fn parse_value<T: FromStr>(source: &str, value: &str) -> Result<T, Box<dyn Error>>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("Valor inválido en {}: {} ({})", source, value, e).into())
+}
+
+impl Config {
+    // Arma la configuración desde los argumentos del proceso. Devuelve además los
+    // argumentos que no son de configuración (el subcomando y sus flags).
+    pub fn load(args: &[String]) -> Result<(Config, Vec<String>), Box<dyn Error>> {
+        Self::load_from(args, |name| env::var(name).ok())
+    }
+
+    // Igual que load, con las variables de entorno leídas a través de `var`
+    fn load_from(
+        args: &[String],
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<(Config, Vec<String>), Box<dyn Error>> {
+        let mut overrides = Vec::new();
+        let mut rest = Vec::new();
+        let mut unknown = Vec::new();
+        let mut config_file = var("CONFIG_PATH");
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let known = flag == "--config" || SETTINGS.iter().any(|(name, _)| *name == flag);
+            if !known {
+                if flag.starts_with("--") && !COMMAND_FLAGS.contains(&flag) {
+                    unknown.push(flag.to_string());
+                }
+                rest.push(arg.clone());
+                continue;
+            }
+            let value = match inline {
+                Some(value) => value,
+                None => args
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| format!("Falta el valor de {}", flag))?,
+            };
+            if flag == "--config" {
+                config_file = Some(value);
+            } else {
+                overrides.push((flag, value));
+            }
+        }
+
+        let mut config = match config_file {
+            Some(path) => Config::from_file(Path::new(&path))?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+
+        for (flag, name) in SETTINGS {
+            if let Some(value) = var(name) {
+                config.set(flag, name, &value)?;
+            }
+        }
+        // Cloud Run solo indica el puerto
+        if let Some(port) = var("PORT") {
+            let port: u16 = parse_value("PORT", &port)?;
+            let mut addr: SocketAddr = parse_value("bind_address", &config.bind_address)?;
+            addr.set_port(port);
+            config.bind_address = addr.to_string();
+        }
+        for (flag, value) in overrides {
+            config.set(flag, flag, &value)?;
+        }
+
+        config.validate(&unknown)?;
+        Ok((config, rest))
+    }
+
+    fn from_file(path: &Path) -> Result<Config, Box<dyn Error>> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("No se pudo leer {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("Error en {}: {}", path.display(), e).into())
+    }
+
+    fn set(&mut self, flag: &str, source: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        match flag {
+            "--dataset" => self.dataset_path = value.to_string(),
+            "--partitions-cache" => self.partitions_cache = value.to_string(),
+            "--index-dir" => self.index_dir = value.to_string(),
+            "--bind" => self.bind_address = value.to_string(),
+            "--default-per-page" => self.default_per_page = parse_value(source, value)?,
+            "--max-per-page" => self.max_per_page = parse_value(source, value)?,
+            "--read-buffer-bytes" => self.read_buffer_bytes = parse_value(source, value)?,
+            "--write-buffer-bytes" => self.write_buffer_bytes = parse_value(source, value)?,
+            "--rebuild-policy" => self.rebuild_policy = parse_value(source, value)?,
+            "--csv-delimiter" => self.csv_delimiter = parse_value(source, value)?,
+            "--csv-quote" => self.csv_quote = parse_value(source, value)?,
+            "--csv-quoting" => self.csv_quoting = parse_value(source, value)?,
+            // Vacío quita el carácter de escape
+            "--csv-escape" => {
+                self.csv_escape = match value {
+                    "" => None,
+                    value => Some(parse_value(source, value)?),
+                }
+            }
+            "--csv-has-headers" => self.csv_has_headers = parse_value(source, value)?,
+            "--csv-columns" => {
+                self.csv_columns = value
+                    .split(',')
+                    .map(|column| column.trim().to_string())
+                    .filter(|column| !column.is_empty())
+                    .collect()
+            }
+            // Pares cabecera=campo separados por comas
+            "--csv-aliases" => {
+                let mut aliases = BTreeMap::new();
+                for pair in value.split(',').filter(|pair| !pair.trim().is_empty()) {
+                    let Some((header, field)) = pair.split_once('=') else {
+                        return Err(format!(
+                            "Valor inválido en {}: {} (se espera cabecera=campo)",
+                            source, pair
+                        )
+                        .into());
+                    };
+                    aliases.insert(header.trim().to_string(), field.trim().to_string());
+                }
+                self.csv_aliases = aliases;
+            }
+            "--csv-encoding" => {
+                self.csv_encoding = Some(value.to_string()).filter(|value| !value.is_empty())
+            }
+            "--csv-compression" => self.csv_compression = parse_value(source, value)?,
+            "--admin-token" => self.admin_token = Some(value.to_string()),
+            _ => return Err(format!("Opción desconocida: {}", flag).into()),
+        }
+        Ok(())
+    }
+
+    // Junta todos los problemas para reportarlos de una vez al arrancar, incluidas las
+    // flags que no son de configuración ni de ningún subcomando
+    fn validate(&self, unknown_flags: &[String]) -> Result<(), Box<dyn Error>> {
+        let mut errors: Vec<String> = unknown_flags
+            .iter()
+            .map(|flag| format!("opción desconocida {}", flag))
+            .collect();
+        for (name, value) in [
+            ("dataset_path", &self.dataset_path),
+            ("partitions_cache", &self.partitions_cache),
+            ("index_dir", &self.index_dir),
+        ] {
+            if value.trim().is_empty() {
+                errors.push(format!("{} no puede estar vacío", name));
+            }
+        }
+        if self
+            .admin_token
+            .as_ref()
+            .is_some_and(|token| token.trim().is_empty())
+        {
+            errors.push("admin_token no puede estar vacío".to_string());
+        }
+        if let Err(e) = self.bind_address.parse::<SocketAddr>() {
+            errors.push(format!(
+                "bind_address inválido: {} ({})",
+                self.bind_address, e
+            ));
+        }
+        if self.default_per_page == 0 {
+            errors.push("default_per_page debe ser mayor que 0".to_string());
+        }
+        if self.default_per_page > self.max_per_page {
+            errors.push(format!(
+                "default_per_page ({}) no puede superar max_per_page ({})",
+                self.default_per_page, self.max_per_page
+            ));
+        }
+        for (name, value) in [
+            ("read_buffer_bytes", self.read_buffer_bytes),
+            ("write_buffer_bytes", self.write_buffer_bytes),
+        ] {
+            if !(MIN_BUFFER_BYTES..=MAX_BUFFER_BYTES).contains(&value) {
+                errors.push(format!(
+                    "{} debe estar entre {} y {} (es {})",
+                    name, MIN_BUFFER_BYTES, MAX_BUFFER_BYTES, value
+                ));
+            }
+        }
+        if let Err(e) = self.csv_options() {
+            errors.push(e);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Configuración inválida: {}", errors.join("; ")).into())
+        }
+    }
+
+    // Opciones con las que se leen los archivos del dataset
+    pub fn csv_options(&self) -> Result<CsvOptions, String> {
+        let byte = |name: &str, value: char| {
+            u8::try_from(value)
+                .ok()
+                .filter(u8::is_ascii)
+                .ok_or_else(|| format!("{} debe ser un carácter ASCII (es '{}')", name, value))
+        };
+        let mut options = CsvOptions {
+            delimiter: byte("csv_delimiter", self.csv_delimiter)?,
+            quote: byte("csv_quote", self.csv_quote)?,
+            quoting: self.csv_quoting,
+            escape: self
+                .csv_escape
+                .map(|escape| byte("csv_escape", escape))
+                .transpose()?,
+            has_headers: self.csv_has_headers,
+            columns: (!self.csv_columns.is_empty()).then(|| self.csv_columns.clone()),
+            compression: match self.csv_compression {
+                CsvCompression::Auto => None,
+                CsvCompression::None => Some(Compression::None),
+                CsvCompression::Gzip => Some(Compression::Gzip),
+                CsvCompression::Zstd => Some(Compression::Zstd),
+                CsvCompression::Bzip2 => Some(Compression::Bzip2),
+            },
+            ..CsvOptions::default()
+        };
+        for (header, field) in &self.csv_aliases {
+            if !FIELD_NAMES.contains(&field.as_str()) {
+                return Err(format!(
+                    "csv_aliases: '{}' apunta a un campo desconocido '{}'",
+                    header, field
+                ));
+            }
+        }
+        options.aliases.extend(self.csv_aliases.clone());
+        if let Some(label) = &self.csv_encoding {
+            let encoding = Encoding::for_label(label.as_bytes())
+                .ok_or_else(|| format!("csv_encoding desconocido: {}", label))?;
+            // UTF-8 se lee directamente, así se puede seguir saltando por bytes
+            options.encoding = Some(encoding).filter(|encoding| *encoding != UTF_8);
+        }
+        if options.has_headers && options.columns.is_some() {
+            return Err("csv_columns solo se usa con csv_has_headers = false".to_string());
+        }
+        Ok(options)
+    }
+}
+
+// Se fija una sola vez al arrancar
+pub fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+// Sin init (por ejemplo antes de cargarla) se usan los valores por defecto
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn config_file(name: &str, text: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("config-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn load(args: &[String], vars: &[(&str, &str)]) -> Result<(Config, Vec<String>), String> {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        Config::load_from(args, |name| vars.get(name).map(|value| value.to_string()))
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn each_source_overrides_the_previous_one() {
+        let path = config_file(
+            "precedence",
+            "dataset_path = \"archivo.csv\"\nindex_dir = \"archivo_idx\"\ndefault_per_page = 20\nmax_per_page = 500\n",
+        );
+        let path = path.to_str().unwrap();
+        let (config, rest) = load(
+            &args(&[
+                "fsck",
+                "--default-per-page=40",
+                "--max-per-page",
+                "600",
+                "--repair",
+            ]),
+            &[
+                ("CONFIG_PATH", path),
+                ("INDEX_DIR", "entorno_idx"),
+                ("DEFAULT_PER_PAGE", "30"),
+                ("PORT", "8080"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(rest, ["fsck", "--repair"]);
+        assert_eq!(config.dataset_path, "archivo.csv");
+        assert_eq!(config.index_dir, "entorno_idx");
+        assert_eq!(config.default_per_page, 40);
+        assert_eq!(config.max_per_page, 600);
+        assert_eq!(config.bind_address, "0.0.0.0:8080");
+        assert_eq!(config.partitions_cache, Config::default().partitions_cache);
+
+        // --config gana a CONFIG_PATH y sin ninguna fuente quedan los valores por defecto
+        let (config, _) = load(
+            &args(&["--config", path]),
+            &[("CONFIG_PATH", "/no/existe.toml")],
+        )
+        .unwrap();
+        assert_eq!(config.max_per_page, 500);
+        let (config, rest) = load(&[], &[]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(config.dataset_path, Config::default().dataset_path);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let error = load(
+            &args(&[
+                "--default-per-page",
+                "0",
+                "--read-buffer-bytes",
+                "10",
+                "--csv-delimiter",
+                "ñ",
+                "--admin-token",
+                " ",
+                "--bogus",
+            ]),
+            &[("INDEX_DIR", "")],
+        )
+        .unwrap_err();
+        for problem in [
+            "opción desconocida --bogus",
+            "index_dir no puede estar vacío",
+            "admin_token no puede estar vacío",
+            "default_per_page debe ser mayor que 0",
+            "read_buffer_bytes debe estar entre",
+            "csv_delimiter debe ser un carácter ASCII",
+        ] {
+            assert!(error.contains(problem), "falta '{}' en: {}", problem, error);
+        }
+        assert!(!error.contains("write_buffer_bytes"));
+
+        let error = load(&args(&["--default-per-page", "2000"]), &[]).unwrap_err();
+        assert!(error.contains("default_per_page (2000) no puede superar max_per_page (1000)"));
+        let error = load(&args(&["--csv-aliases", "Fare=fare"]), &[]).unwrap_err();
+        assert!(error.contains("campo desconocido 'fare'"), "{}", error);
+    }
+
+    #[test]
+    fn unreadable_values_name_their_source() {
+        let error = load(&[], &[("MAX_PER_PAGE", "mil")]).unwrap_err();
+        assert!(
+            error.starts_with("Valor inválido en MAX_PER_PAGE: mil"),
+            "{}",
+            error
+        );
+        let error = load(&args(&["--rebuild-policy=sometimes"]), &[]).unwrap_err();
+        assert!(
+            error.starts_with("Valor inválido en --rebuild-policy"),
+            "{}",
+            error
+        );
+        let error = load(&args(&["--bind"]), &[]).unwrap_err();
+        assert_eq!(error, "Falta el valor de --bind");
+
+        let path = config_file("unknown", "max_per_pages = 10\n");
+        let error = load(&args(&["--config", path.to_str().unwrap()]), &[]).unwrap_err();
+        assert!(error.starts_with("Error en"), "{}", error);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,349 @@
+use super::disk_hash::{map_file, replace_file};
+use super::trip_struct::{StoreAndFwdFlag, Trip};
+use crate::config;
+use memmap2::Mmap;
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const ROWS_FILE: &str = "row_positions.bin";
+pub const BITMAPS_FILE: &str = "bitmaps.bin";
+const DELTA_FILE: &str = "bitmaps_delta.json";
+const ROWS_MAGIC: &[u8; 4] = b"ROW1";
+const BITMAPS_MAGIC: &[u8; 4] = b"BMP1";
+
+// Columnas con pocos valores distintos. Cada valor tiene un bitmap con los números de
+// fila que lo tienen; la fila es el orden del registro vivo en trip_data.bin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitmapColumn {
+    VendorId,
+    PaymentType,
+    RatecodeId,
+    StoreAndFwdFlag,
+    PassengerCount,
+}
+
+impl BitmapColumn {
+    pub const ALL: [BitmapColumn; 5] = [
+        BitmapColumn::VendorId,
+        BitmapColumn::PaymentType,
+        BitmapColumn::RatecodeId,
+        BitmapColumn::StoreAndFwdFlag,
+        BitmapColumn::PassengerCount,
+    ];
+
+    fn value(self, trip: &Trip) -> u32 {
+        match self {
+            BitmapColumn::VendorId => trip.vendor_id,
+            BitmapColumn::PaymentType => trip.payment_type,
+            BitmapColumn::RatecodeId => trip.ratecode_id,
+            BitmapColumn::StoreAndFwdFlag => flag_value(trip.store_and_fwd_flag),
+            BitmapColumn::PassengerCount => trip.passenger_count,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+// La bandera se guarda como el código de su letra
+pub fn flag_value(flag: StoreAndFwdFlag) -> u32 {
+    match flag {
+        StoreAndFwdFlag::Y => b'Y' as u32,
+        StoreAndFwdFlag::N => b'N' as u32,
+    }
+}
+
+type ColumnBitmaps = BTreeMap<u32, RoaringBitmap>;
+
+fn values(trip: &Trip) -> [u32; BitmapColumn::ALL.len()] {
+    BitmapColumn::ALL.map(|column| column.value(trip))
+}
+
+// Se llena en orden de posición al terminar una construcción
+#[derive(Default)]
+pub struct BitmapBuilder {
+    positions: Vec<u64>,
+    columns: [ColumnBitmaps; BitmapColumn::ALL.len()],
+}
+
+impl BitmapBuilder {
+    pub fn add(&mut self, position: u64, trip: &Trip) -> Result<(), Box<dyn Error>> {
+        let row =
+            u32::try_from(self.positions.len()).map_err(|_| "Demasiadas filas para los bitmaps")?;
+        self.positions.push(position);
+        for (column, value) in BitmapColumn::ALL.iter().zip(values(trip)) {
+            self.columns[column.index()]
+                .entry(value)
+                .or_default()
+                .insert(row);
+        }
+        Ok(())
+    }
+
+    // row_positions.bin: [magic][u64 posición por fila]. bitmaps.bin: [magic] y por
+    // columna [u32 cantidad de valores] seguido de [u32 valor][u32 largo][bitmap].
+    pub fn write(self, dir_path: &Path) -> Result<(), Box<dyn Error>> {
+        write_atomic(&dir_path.join(ROWS_FILE), |out| {
+            out.write_all(ROWS_MAGIC)?;
+            for position in &self.positions {
+                out.write_all(&position.to_le_bytes())?;
+            }
+            Ok(())
+        })?;
+
+        write_atomic(&dir_path.join(BITMAPS_FILE), |out| {
+            out.write_all(BITMAPS_MAGIC)?;
+            for bitmaps in &self.columns {
+                out.write_all(&(bitmaps.len() as u32).to_le_bytes())?;
+                for (value, bitmap) in bitmaps {
+                    out.write_all(&value.to_le_bytes())?;
+                    out.write_all(&(bitmap.serialized_size() as u32).to_le_bytes())?;
+                    bitmap.serialize_into(&mut *out)?;
+                }
+            }
+            Ok(())
+        })?;
+
+        let delta_path = dir_path.join(DELTA_FILE);
+        if delta_path.exists() {
+            fs::remove_file(delta_path)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_atomic(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let tmp_path = path.with_extension("bin.tmp");
+    let mut out =
+        BufWriter::with_capacity(config::get().write_buffer_bytes, File::create(&tmp_path)?);
+    write(&mut out)?;
+    out.flush()?;
+    out.get_ref().sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// Filas agregadas y retiradas desde la construcción, se reaplican al abrir
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct BitmapDelta {
+    appended: Vec<(u64, [u32; BitmapColumn::ALL.len()])>,
+    removed: Vec<u32>,
+}
+
+// Lo que dejó la última construcción. No cambia hasta la siguiente, así que todas las
+// copias del índice lo comparten.
+struct BitmapBase {
+    // Posiciones de las filas de la construcción, crecientes
+    rows: Option<Mmap>,
+    len: u32,
+    columns: [ColumnBitmaps; BitmapColumn::ALL.len()],
+}
+
+// Los bitmaps base viven completos en memoria porque son pocos y comprimidos. Los
+// cambios posteriores van en bitmaps aparte (filas agregadas por valor y filas
+// retiradas) que se combinan con la base al consultar.
+#[derive(Clone)]
+pub struct BitmapIndex {
+    dir_path: PathBuf,
+    base: Arc<BitmapBase>,
+    added: [ColumnBitmaps; BitmapColumn::ALL.len()],
+    removed: RoaringBitmap,
+    delta: BitmapDelta,
+}
+
+impl BitmapIndex {
+    pub fn open(dir_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let rows_path = dir_path.join(ROWS_FILE);
+        let (rows, len) = if rows_path.exists() {
+            let map = map_file(&rows_path)?;
+            if map.get(..4) != Some(ROWS_MAGIC.as_slice()) || (map.len() - 4) % 8 != 0 {
+                return Err(format!("{} no es un archivo de filas", rows_path.display()).into());
+            }
+            let len = ((map.len() - 4) / 8) as u32;
+            (Some(map), len)
+        } else {
+            (None, 0)
+        };
+
+        let mut columns: [ColumnBitmaps; BitmapColumn::ALL.len()] = Default::default();
+        let bitmaps_path = dir_path.join(BITMAPS_FILE);
+        if bitmaps_path.exists() {
+            let mut reader = BufReader::new(File::open(&bitmaps_path)?);
+            let mut magic = [0u8; 4];
+            reader.read_exact(&mut magic)?;
+            if &magic != BITMAPS_MAGIC {
+                return Err(
+                    format!("{} no es un archivo de bitmaps", bitmaps_path.display()).into(),
+                );
+            }
+            for bitmaps in &mut columns {
+                for _ in 0..read_u32(&mut reader)? {
+                    let value = read_u32(&mut reader)?;
+                    let len = read_u32(&mut reader)? as u64;
+                    let bitmap = RoaringBitmap::deserialize_from((&mut reader).take(len))?;
+                    bitmaps.insert(value, bitmap);
+                }
+            }
+        }
+
+        let delta: BitmapDelta = match File::open(dir_path.join(DELTA_FILE)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(_) => BitmapDelta::default(),
+        };
+
+        let mut index = Self {
+            dir_path: dir_path.to_path_buf(),
+            base: Arc::new(BitmapBase { rows, len, columns }),
+            added: Default::default(),
+            removed: delta.removed.iter().copied().collect(),
+            delta: BitmapDelta::default(),
+        };
+        for (i, &(_, values)) in delta.appended.iter().enumerate() {
+            index.insert_row(index.base.len + i as u32, values);
+        }
+        index.delta = delta;
+        Ok(index)
+    }
+
+    // Filas vivas con ese valor
+    pub fn rows(&self, column: BitmapColumn, value: u32) -> RoaringBitmap {
+        let mut rows = match self.base.columns[column.index()].get(&value) {
+            Some(base) => base - &self.removed,
+            None => RoaringBitmap::new(),
+        };
+        if let Some(added) = self.added[column.index()].get(&value) {
+            rows |= added - &self.removed;
+        }
+        rows
+    }
+
+    // Filas vivas con un valor entre min y max, ambos incluidos
+    pub fn rows_between(&self, column: BitmapColumn, min: u32, max: u32) -> RoaringBitmap {
+        if min > max {
+            return RoaringBitmap::new();
+        }
+        let mut rows = RoaringBitmap::new();
+        for bitmap in self.base.columns[column.index()]
+            .range(min..=max)
+            .map(|(_, b)| b)
+        {
+            rows |= bitmap;
+        }
+        for bitmap in self.added[column.index()].range(min..=max).map(|(_, b)| b) {
+            rows |= bitmap;
+        }
+        rows - &self.removed
+    }
+
+    pub fn live(&self) -> RoaringBitmap {
+        let mut live = RoaringBitmap::new();
+        live.insert_range(0..self.base.len + self.delta.appended.len() as u32);
+        live - &self.removed
+    }
+
+    pub fn is_live(&self, row: u32) -> bool {
+        !self.removed.contains(row)
+    }
+
+    // Filas de la construcción, las que tienen columnas en el almacén columnar
+    pub fn base_len(&self) -> u32 {
+        self.base.len
+    }
+
+    // Posiciones de los registros escritos después de construir que siguen vivos
+    pub fn appended_positions(&self) -> impl Iterator<Item = u64> + '_ {
+        self.delta
+            .appended
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.is_live(self.base.len + *i as u32))
+            .map(|(_, (position, _))| *position)
+    }
+
+    // Posición en trip_data.bin de una fila
+    pub fn position(&self, row: u32) -> Option<u64> {
+        if row < self.base.len {
+            let start = 4 + row as usize * 8;
+            let bytes = &self.base.rows.as_ref()?[start..start + 8];
+            Some(u64::from_le_bytes(bytes.try_into().ok()?))
+        } else {
+            self.delta
+                .appended
+                .get((row - self.base.len) as usize)
+                .map(|(position, _)| *position)
+        }
+    }
+
+    // Fila de una posición; las posiciones crecen con la fila, alcanza con bisección
+    fn row_of(&self, position: u64) -> Option<u32> {
+        let (mut lo, mut hi) = (0, self.base.len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.position(mid)? < position {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        if lo < self.base.len && self.position(lo) == Some(position) {
+            return Some(lo);
+        }
+        let i = self
+            .delta
+            .appended
+            .binary_search_by_key(&position, |(p, _)| *p)
+            .ok()?;
+        Some(self.base.len + i as u32)
+    }
+
+    fn insert_row(&mut self, row: u32, values: [u32; BitmapColumn::ALL.len()]) {
+        for (bitmaps, value) in self.added.iter_mut().zip(values) {
+            bitmaps.entry(value).or_default().insert(row);
+        }
+    }
+
+    pub fn record_change(
+        &mut self,
+        old: Option<(u64, &Trip)>,
+        new: Option<(u64, &Trip)>,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some((position, _)) = old
+            && let Some(row) = self.row_of(position)
+        {
+            self.removed.insert(row);
+            self.delta.removed.push(row);
+        }
+        if let Some((position, trip)) = new {
+            let row = u32::try_from(self.base.len as usize + self.delta.appended.len())
+                .map_err(|_| "Demasiadas filas para los bitmaps")?;
+            let values = values(trip);
+            self.delta.appended.push((position, values));
+            self.insert_row(row, values);
+        }
+        Ok(())
+    }
+
+    // Los cambios se acumulan en memoria y se guardan una vez por lote de escrituras
+    pub fn save_delta(&self) -> Result<(), Box<dyn Error>> {
+        replace_file(
+            &self.dir_path.join(DELTA_FILE),
+            &serde_json::to_vec(&self.delta)?,
+        )
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, Box<dyn Error>> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
@@ -0,0 +1,489 @@
+use super::bitmap_index::flag_value;
+use super::disk_hash::{map_file, replace_file};
+use super::range_index::{KeyRange, amount_key, time_key};
+use super::record_codec::{FIELD_WIDTHS, decode_trip, encode_trip, field_offset};
+use super::trip_struct::{FIELD_NAMES, Trip, projection_mask};
+use memmap2::Mmap;
+use rust_decimal::prelude::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+pub const COLUMNS_DIR: &str = "columns";
+pub const COLUMNS_META: &str = "columns/meta.json";
+const COLUMN_MAGIC: &[u8; 4] = b"COL1";
+const ROW_GROUP_ROWS: usize = 64 * 1024;
+// Los códigos de diccionario son u8 o u16
+const MAX_DICTIONARY: usize = 1 << 16;
+
+const PLAIN: u8 = 0;
+const DICTIONARY: u8 = 1;
+
+// Clave ordenable del valor de un campo, en el mismo espacio que el índice de rango:
+// los enteros tal cual, importes y distancia como f64 y las fechas en microsegundos.
+pub fn zone_key(field: usize, trip: &Trip) -> Option<u64> {
+    match field {
+        0 => Some(trip.vendor_id as u64),
+        1 => Some(time_key(trip.tpep_pickup_datetime)),
+        2 => Some(time_key(trip.tpep_dropoff_datetime)),
+        3 => Some(trip.passenger_count as u64),
+        4 => amount_key(trip.trip_distance),
+        5 => Some(trip.ratecode_id as u64),
+        6 => Some(flag_value(trip.store_and_fwd_flag) as u64),
+        7 => Some(trip.pu_location_id as u64),
+        8 => Some(trip.do_location_id as u64),
+        9 => Some(trip.payment_type as u64),
+        10 => amount_key(trip.fare_amount.to_f64()?),
+        11 => amount_key(trip.extra.to_f64()?),
+        12 => amount_key(trip.mta_tax.to_f64()?),
+        13 => amount_key(trip.tip_amount.to_f64()?),
+        14 => amount_key(trip.tolls_amount.to_f64()?),
+        15 => amount_key(trip.improvement_surcharge.to_f64()?),
+        16 => amount_key(trip.total_amount.to_f64()?),
+        17 => amount_key(trip.congestion_surcharge.to_f64()?),
+        _ => Some(trip.index),
+    }
+}
+
+fn field_index(field: &str) -> Option<usize> {
+    FIELD_NAMES.iter().position(|name| *name == field)
+}
+
+// Grupo de filas consecutivas. Por columna guarda dónde está su trozo en el archivo
+// de la columna y el mínimo y máximo de sus claves (None si no tiene valores finitos).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RowGroup {
+    rows: u32,
+    chunks: Vec<(u64, u64)>,
+    zones: Vec<Option<(u64, u64)>>,
+}
+
+impl RowGroup {
+    // Si alguna fila del grupo puede tener en `field` un valor dentro del rango. Un grupo
+    // sin valores finitos solo cumple un rango sin extremos, que acepta también NaN.
+    pub fn may_contain(&self, field: &str, range: &KeyRange) -> bool {
+        match field_index(field) {
+            Some(field) => match self.zones[field] {
+                Some((min, max)) => range.overlaps(min, max),
+                None => range.is_unbounded(),
+            },
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ColumnMeta {
+    rows: u64,
+    groups: Vec<RowGroup>,
+}
+
+// Trozo de una columna: [u8 PLAIN][valores] o [u8 DICTIONARY][u32 cantidad][valores
+// distintos][u8 ancho del código][códigos]. Se usa diccionario solo si ocupa menos.
+fn encode_chunk(values: &[u8], width: usize, out: &mut Vec<u8>) {
+    let rows = values.len() / width;
+    let mut dictionary: HashMap<&[u8], u16> = HashMap::new();
+    let mut codes = Vec::with_capacity(rows);
+    for value in values.chunks_exact(width) {
+        let next = dictionary.len();
+        if next == MAX_DICTIONARY && !dictionary.contains_key(value) {
+            break;
+        }
+        codes.push(*dictionary.entry(value).or_insert(next as u16));
+    }
+
+    let code_width = if dictionary.len() <= 256 { 1 } else { 2 };
+    let dictionary_len = 4 + dictionary.len() * width + 1 + rows * code_width;
+    if codes.len() < rows || dictionary_len >= values.len() {
+        out.push(PLAIN);
+        out.extend_from_slice(values);
+        return;
+    }
+
+    let mut distinct = vec![&[][..]; dictionary.len()];
+    for (value, code) in dictionary {
+        distinct[code as usize] = value;
+    }
+    out.push(DICTIONARY);
+    out.extend_from_slice(&(distinct.len() as u32).to_le_bytes());
+    for value in distinct {
+        out.extend_from_slice(value);
+    }
+    out.push(code_width as u8);
+    for code in codes {
+        out.extend_from_slice(&code.to_le_bytes()[..code_width]);
+    }
+}
+
+// Devuelve los valores del trozo uno detrás de otro, sin copiar si están en plano
+fn decode_chunk(chunk: &[u8], width: usize, rows: usize) -> Result<Cow<'_, [u8]>, Box<dyn Error>> {
+    let (&encoding, rest) = chunk.split_first().ok_or("Trozo de columna vacío")?;
+    match encoding {
+        PLAIN => {
+            let values = rest
+                .get(..rows * width)
+                .ok_or("Trozo de columna truncado")?;
+            Ok(Cow::Borrowed(values))
+        }
+        DICTIONARY => {
+            let count = u32::from_le_bytes(rest.get(..4).ok_or("Diccionario truncado")?.try_into()?)
+                as usize;
+            let distinct = rest
+                .get(4..4 + count * width)
+                .ok_or("Diccionario truncado")?;
+            let code_width = *rest.get(4 + count * width).ok_or("Diccionario truncado")? as usize;
+            let codes = rest
+                .get(5 + count * width..5 + count * width + rows * code_width)
+                .ok_or("Códigos de diccionario truncados")?;
+
+            let mut values = Vec::with_capacity(rows * width);
+            for code in codes.chunks_exact(code_width) {
+                let mut bytes = [0u8; 2];
+                bytes[..code_width].copy_from_slice(code);
+                let start = u16::from_le_bytes(bytes) as usize * width;
+                values.extend_from_slice(
+                    distinct
+                        .get(start..start + width)
+                        .ok_or("Código fuera del diccionario")?,
+                );
+            }
+            Ok(Cow::Owned(values))
+        }
+        other => Err(format!("Codificación de columna desconocida: {}", other).into()),
+    }
+}
+
+// Se llena al terminar una construcción con los mismos registros vivos, en el mismo
+// orden, que los bitmaps: la fila de una columna es la fila de bitmap_index. Cada
+// grupo completo se codifica y se agrega al archivo de cada columna.
+pub struct ColumnBuilder {
+    dir_path: PathBuf,
+    files: Vec<BufWriter<File>>,
+    offsets: Vec<u64>,
+    values: Vec<Vec<u8>>,
+    zones: Vec<Option<(u64, u64)>>,
+    group_rows: usize,
+    meta: ColumnMeta,
+}
+
+impl ColumnBuilder {
+    pub fn create(index_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let dir_path = index_dir.join(COLUMNS_DIR);
+        if dir_path.exists() {
+            fs::remove_dir_all(&dir_path)?;
+        }
+        fs::create_dir_all(&dir_path)?;
+
+        let mut files = Vec::with_capacity(FIELD_NAMES.len());
+        for field in FIELD_NAMES {
+            let path = dir_path.join(format!("{}.col", field));
+            let mut out = BufWriter::with_capacity(256 * 1024, File::create(path)?);
+            out.write_all(COLUMN_MAGIC)?;
+            files.push(out);
+        }
+
+        Ok(Self {
+            dir_path,
+            files,
+            offsets: vec![COLUMN_MAGIC.len() as u64; FIELD_NAMES.len()],
+            values: FIELD_WIDTHS
+                .iter()
+                .map(|width| Vec::with_capacity(ROW_GROUP_ROWS * width))
+                .collect(),
+            zones: vec![None; FIELD_NAMES.len()],
+            group_rows: 0,
+            meta: ColumnMeta::default(),
+        })
+    }
+
+    // `trip_bytes` es el Trip ya codificado del registro, sus campos se copian tal cual
+    pub fn add(&mut self, trip_bytes: &[u8], trip: &Trip) -> Result<(), Box<dyn Error>> {
+        for (field, width) in FIELD_WIDTHS.iter().enumerate() {
+            let start = field_offset(field);
+            self.values[field].extend_from_slice(&trip_bytes[start..start + width]);
+            if let Some(key) = zone_key(field, trip) {
+                let zone = self.zones[field].get_or_insert((key, key));
+                *zone = (zone.0.min(key), zone.1.max(key));
+            }
+        }
+        self.group_rows += 1;
+        if self.group_rows == ROW_GROUP_ROWS {
+            self.flush_group()?;
+        }
+        Ok(())
+    }
+
+    fn flush_group(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.group_rows == 0 {
+            return Ok(());
+        }
+        let mut chunks = Vec::with_capacity(FIELD_NAMES.len());
+        let mut chunk = Vec::new();
+        for (field, width) in FIELD_WIDTHS.iter().enumerate() {
+            chunk.clear();
+            encode_chunk(&self.values[field], *width, &mut chunk);
+            self.files[field].write_all(&chunk)?;
+            chunks.push((self.offsets[field], chunk.len() as u64));
+            self.offsets[field] += chunk.len() as u64;
+            self.values[field].clear();
+        }
+        self.meta.groups.push(RowGroup {
+            rows: self.group_rows as u32,
+            chunks,
+            zones: std::mem::replace(&mut self.zones, vec![None; FIELD_NAMES.len()]),
+        });
+        self.meta.rows += self.group_rows as u64;
+        self.group_rows = 0;
+        Ok(())
+    }
+
+    // meta.json va al final, su presencia marca las columnas como completas
+    pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        self.flush_group()?;
+        for file in &mut self.files {
+            file.flush()?;
+            file.get_ref().sync_all()?;
+        }
+        replace_file(
+            &self.dir_path.join("meta.json"),
+            &serde_json::to_vec(&self.meta)?,
+        )
+    }
+}
+
+// Las columnas de la última construcción. Como el resto de los archivos base no
+// cambian hasta la siguiente; lo escrito después se lee del log.
+pub struct ColumnStore {
+    meta: ColumnMeta,
+    files: Vec<Option<Mmap>>,
+}
+
+impl ColumnStore {
+    // Un índice recién creado todavía no tiene columnas, se trata como vacío
+    pub fn open(index_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let dir_path = index_dir.join(COLUMNS_DIR);
+        let meta: ColumnMeta = match File::open(index_dir.join(COLUMNS_META)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(_) => {
+                return Ok(Self {
+                    meta: ColumnMeta::default(),
+                    files: (0..FIELD_NAMES.len()).map(|_| None).collect(),
+                });
+            }
+        };
+
+        let mut files = Vec::with_capacity(FIELD_NAMES.len());
+        for field in FIELD_NAMES {
+            let path = dir_path.join(format!("{}.col", field));
+            let map = map_file(&path)?;
+            if map.get(..4) != Some(COLUMN_MAGIC.as_slice()) {
+                return Err(format!("{} no es un archivo de columna", path.display()).into());
+            }
+            files.push(Some(map));
+        }
+        Ok(Self { meta, files })
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.meta.rows
+    }
+
+    // Recorre las filas leyendo solo las columnas de `fields`; el resto del Trip queda
+    // en su valor por defecto, como al leer el CSV con proyección. Los grupos que `keep`
+    // descarta por sus mapas de zona no se leen. Devuelve cuántos grupos se saltaron.
+    pub fn scan(
+        &self,
+        fields: &[&str],
+        mut keep: impl FnMut(&RowGroup) -> bool,
+        mut process: impl FnMut(u32, &Trip) -> Result<(), Box<dyn Error>>,
+    ) -> Result<usize, Box<dyn Error>> {
+        let wanted: Vec<usize> = projection_mask(fields)
+            .iter()
+            .enumerate()
+            .filter_map(|(field, wanted)| wanted.then_some(field))
+            .collect();
+        let mut record = Vec::new();
+        encode_trip(&Trip::default(), &mut record);
+
+        let mut first_row = 0u32;
+        let mut skipped = 0;
+        for group in &self.meta.groups {
+            if !keep(group) {
+                skipped += 1;
+                first_row += group.rows;
+                continue;
+            }
+
+            let rows = group.rows as usize;
+            let mut columns = Vec::with_capacity(wanted.len());
+            for &field in &wanted {
+                let (start, len) = group.chunks[field];
+                let chunk = self.files[field]
+                    .as_ref()
+                    .and_then(|map| map.get(start as usize..(start + len) as usize))
+                    .ok_or("Trozo fuera del archivo de columna")?;
+                columns.push((field, decode_chunk(chunk, FIELD_WIDTHS[field], rows)?));
+            }
+
+            for row in 0..rows {
+                for (field, values) in &columns {
+                    let width = FIELD_WIDTHS[*field];
+                    let offset = field_offset(*field);
+                    record[offset..offset + width]
+                        .copy_from_slice(&values[row * width..(row + 1) * width]);
+                }
+                process(first_row + row as u32, &decode_trip(&record)?)?;
+            }
+            first_row += group.rows;
+        }
+        Ok(skipped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, TimeDelta};
+    use rust_decimal::Decimal;
+
+    // Directorio vacío y propio de cada prueba
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("columns-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn round_trip(values: &[u8], width: usize) -> u8 {
+        let mut chunk = Vec::new();
+        encode_chunk(values, width, &mut chunk);
+        let decoded = decode_chunk(&chunk, width, values.len() / width).unwrap();
+        assert_eq!(&decoded[..], values);
+        chunk[0]
+    }
+
+    #[test]
+    fn chunks_decode_to_the_encoded_values() {
+        // Pocos valores distintos: diccionario con códigos de un byte
+        let few: Vec<u8> = (0..1000u32).flat_map(|i| (i % 7).to_le_bytes()).collect();
+        assert_eq!(round_trip(&few, 4), DICTIONARY);
+
+        // Más de 256 distintos: códigos de dos bytes
+        let many: Vec<u8> = (0..5000u64).flat_map(|i| (i % 300).to_le_bytes()).collect();
+        assert_eq!(round_trip(&many, 8), DICTIONARY);
+
+        // Todos distintos: el diccionario no ahorra y queda en plano
+        let distinct: Vec<u8> = (0..1000u64).flat_map(|i| i.to_le_bytes()).collect();
+        assert_eq!(round_trip(&distinct, 8), PLAIN);
+
+        let mut chunk = Vec::new();
+        encode_chunk(&few, 4, &mut chunk);
+        assert!(decode_chunk(&chunk[..chunk.len() - 1], 4, 1000).is_err());
+        chunk[0] = 9;
+        assert!(decode_chunk(&chunk, 4, 1000).is_err());
+    }
+
+    #[test]
+    fn scan_returns_the_trips_that_were_added() {
+        let dir = test_dir("round-trip");
+        let start = NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let trips: Vec<Trip> = (0..2000u64)
+            .map(|i| Trip {
+                index: i + 1,
+                vendor_id: (i % 2 + 1) as u32,
+                tpep_pickup_datetime: start + TimeDelta::seconds(i as i64 * 37),
+                tpep_dropoff_datetime: start + TimeDelta::seconds(i as i64 * 37 + 600),
+                trip_distance: i as f64 / 8.0,
+                do_location_id: (i % 265) as u32,
+                total_amount: Decimal::new(i as i64 * 7, 2),
+                congestion_surcharge: Decimal::new(25, 1),
+                ..Trip::default()
+            })
+            .collect();
+
+        let mut builder = ColumnBuilder::create(&dir).unwrap();
+        let mut bytes = Vec::new();
+        for trip in &trips {
+            bytes.clear();
+            encode_trip(trip, &mut bytes);
+            builder.add(&bytes, trip).unwrap();
+        }
+        builder.finish().unwrap();
+
+        let store = ColumnStore::open(&dir).unwrap();
+        assert_eq!(store.rows(), trips.len() as u64);
+        let mut rows = 0;
+        store
+            .scan(
+                &FIELD_NAMES,
+                |_| true,
+                |row, trip| {
+                    let expected = serde_json::to_value(&trips[row as usize])?;
+                    assert_eq!(serde_json::to_value(trip)?, expected);
+                    rows += 1;
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(rows, trips.len());
+
+        // Con proyección el resto de los campos queda en su valor por defecto
+        store
+            .scan(
+                &["do_location_id"],
+                |_| true,
+                |row, trip| {
+                    assert_eq!(trip.do_location_id, trips[row as usize].do_location_id);
+                    assert_eq!(trip.index, 0);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_group_without_finite_values_only_matches_an_unbounded_range() {
+        let dir = test_dir("nan");
+        let mut builder = ColumnBuilder::create(&dir).unwrap();
+        let mut bytes = Vec::new();
+        for index in 1..=10 {
+            let trip = Trip {
+                index,
+                trip_distance: f64::NAN,
+                ..Trip::default()
+            };
+            bytes.clear();
+            encode_trip(&trip, &mut bytes);
+            builder.add(&bytes, &trip).unwrap();
+        }
+        builder.finish().unwrap();
+
+        let store = ColumnStore::open(&dir).unwrap();
+        let mut groups = Vec::new();
+        store
+            .scan(
+                &["index"],
+                |group| {
+                    groups.push(group.clone());
+                    true
+                },
+                |_, _| Ok(()),
+            )
+            .unwrap();
+        assert_eq!(groups.len(), 1);
+        let group = &groups[0];
+        assert!(group.may_contain("trip_distance", &KeyRange::amounts(None, None)));
+        assert!(!group.may_contain("trip_distance", &KeyRange::amounts(Some(0.0), None)));
+        assert!(!group.may_contain("trip_distance", &KeyRange::amounts(None, Some(5.0))));
+        assert!(!group.may_contain("trip_distance", &KeyRange::amounts(Some(f64::NAN), None)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+impl Compression {
+    // Primero por extensión y si no coincide por los bytes mágicos del archivo
+    pub fn detect(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let by_extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match by_extension.as_deref() {
+            Some("gz" | "gzip") => return Ok(Compression::Gzip),
+            Some("zst" | "zstd") => return Ok(Compression::Zstd),
+            Some("bz2" | "bzip2") => return Ok(Compression::Bzip2),
+            _ => {}
+        }
+
+        let mut magic = [0u8; 4];
+        let read = File::open(path)?.read(&mut magic)?;
+        let magic = &magic[..read];
+        Ok(if magic.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if magic.starts_with(BZIP2_MAGIC) {
+            Compression::Bzip2
+        } else {
+            Compression::None
+        })
+    }
+}
+
+// Abre el archivo descomprimiendo al vuelo, los decodificadores trabajan por bloques
+// así que la memoria no depende del tamaño del archivo.
+pub fn open_decompressed(
+    path: &Path,
+    compression: Compression,
+) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    let file = File::open(path)?;
+    Ok(match compression {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(file)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(file)?),
+        Compression::Bzip2 => Box::new(MultiBzDecoder::new(file)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    // Directorio vacío y propio de cada prueba
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("compression-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const TEXT: &[u8] = b"vendor_id,index\n1,1\n2,2\n";
+
+    fn compress(compression: Compression) -> Vec<u8> {
+        match compression {
+            Compression::None => TEXT.to_vec(),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(TEXT).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Zstd => zstd::encode_all(TEXT, 0).unwrap(),
+            Compression::Bzip2 => {
+                let mut encoder =
+                    bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+                encoder.write_all(TEXT).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    fn read_all(path: &Path, compression: Compression) -> Vec<u8> {
+        let mut text = Vec::new();
+        open_decompressed(path, compression)
+            .unwrap()
+            .read_to_end(&mut text)
+            .unwrap();
+        text
+    }
+
+    #[test]
+    fn magic_bytes_identify_files_without_an_extension() {
+        let dir = test_dir("magic");
+        for compression in [
+            Compression::None,
+            Compression::Gzip,
+            Compression::Zstd,
+            Compression::Bzip2,
+        ] {
+            let path = dir.join(format!("{:?}.csv", compression));
+            fs::write(&path, compress(compression)).unwrap();
+            assert_eq!(Compression::detect(&path).unwrap(), compression);
+            assert_eq!(read_all(&path, compression), TEXT);
+        }
+
+        // Más corto que cualquier número mágico
+        let path = dir.join("short.csv");
+        fs::write(&path, b"a").unwrap();
+        assert_eq!(Compression::detect(&path).unwrap(), Compression::None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_extension_wins_over_the_contents() {
+        let dir = test_dir("extension");
+        for (name, compression) in [
+            ("trips.csv.gz", Compression::Gzip),
+            ("trips.csv.GZIP", Compression::Gzip),
+            ("trips.csv.zst", Compression::Zstd),
+            ("trips.csv.bz2", Compression::Bzip2),
+        ] {
+            let path = dir.join(name);
+            fs::write(&path, TEXT).unwrap();
+            assert_eq!(Compression::detect(&path).unwrap(), compression, "{}", name);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn concatenated_gzip_members_read_as_one_stream() {
+        let dir = test_dir("members");
+        let path = dir.join("trips.csv.gz");
+        let mut bytes = compress(Compression::Gzip);
+        bytes.extend(compress(Compression::Gzip));
+        fs::write(&path, bytes).unwrap();
+        assert_eq!(read_all(&path, Compression::Gzip), [TEXT, TEXT].concat());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+use super::compression::Compression;
+use super::trip_struct::{FIELD_NAMES, OPTIONAL_FIELDS};
+use csv::{ReaderBuilder, StringRecord};
+use encoding_rs::Encoding;
+use std::collections::HashMap;
+use std::error::Error;
+
+// Alias de los exports de NYC TLC. Los nombres que solo difieren en mayúsculas o
+// guiones bajos ya se resuelven al normalizar, estos quedan explícitos como referencia.
+const DEFAULT_ALIASES: [(&str, &str); 5] = [
+    ("VendorID", "vendor_id"),
+    ("RatecodeID", "ratecode_id"),
+    ("PULocationID", "pu_location_id"),
+    ("DOLocationID", "do_location_id"),
+    ("Index", "index"),
+];
+
+// Opciones de dialecto del CSV. Los valores por defecto leen el archivo de datagen.py.
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    pub delimiter: u8,
+    pub quote: u8,
+    pub quoting: bool,
+    pub escape: Option<u8>,
+    pub has_headers: bool,
+    // Nombres de columna cuando el archivo no tiene cabecera, None usa el orden canónico
+    pub columns: Option<Vec<String>>,
+    // Cabecera del archivo -> nombre de campo de Trip
+    pub aliases: HashMap<String, String>,
+    // None lee UTF-8 directamente
+    pub encoding: Option<&'static Encoding>,
+    // None la detecta en cada archivo
+    pub compression: Option<Compression>,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: b'"',
+            quoting: true,
+            escape: None,
+            has_headers: true,
+            columns: None,
+            aliases: DEFAULT_ALIASES
+                .iter()
+                .map(|(header, field)| (header.to_string(), field.to_string()))
+                .collect(),
+            encoding: None,
+            compression: None,
+        }
+    }
+}
+
+impl CsvOptions {
+    pub fn reader_builder(&self) -> ReaderBuilder {
+        let mut builder = ReaderBuilder::new();
+        builder
+            .buffer_capacity(128 * 1024)
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .quoting(self.quoting)
+            .escape(self.escape)
+            .has_headers(self.has_headers)
+            // Se mapea por nombre, las filas cortas se reportan como campos faltantes
+            .flexible(true);
+        builder
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_start_matches('\u{feff}')
+        .trim()
+        .chars()
+        .filter(|c| *c != '_' && *c != ' ')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// Posición de cada campo de Trip dentro del registro, en el orden de FIELD_NAMES.
+#[derive(Debug, Clone)]
+pub struct ColumnMapping {
+    positions: [Option<usize>; FIELD_NAMES.len()],
+}
+
+impl ColumnMapping {
+    pub fn from_options(
+        headers: Option<&StringRecord>,
+        options: &CsvOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        match (headers, &options.columns) {
+            (Some(headers), _) => Self::from_names(headers.iter(), options),
+            (None, Some(columns)) => Self::from_names(columns.iter().map(|c| c.as_str()), options),
+            (None, None) => Ok(Self {
+                positions: std::array::from_fn(Some),
+            }),
+        }
+    }
+
+    pub fn from_names<'a, I>(names: I, options: &CsvOptions) -> Result<Self, Box<dyn Error>>
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        let mut aliases = HashMap::new();
+        for (header, field) in &options.aliases {
+            let Some(pos) = FIELD_NAMES.iter().position(|f| f == field) else {
+                return Err(format!(
+                    "Alias '{}' apunta a un campo desconocido '{}'",
+                    header, field
+                )
+                .into());
+            };
+            aliases.insert(normalize(header), pos);
+        }
+
+        let mut positions = [None; FIELD_NAMES.len()];
+        let mut ignored = Vec::new();
+        for (column, name) in names.enumerate() {
+            let key = normalize(name);
+            let field = aliases
+                .get(&key)
+                .copied()
+                .or_else(|| FIELD_NAMES.iter().position(|f| normalize(f) == key));
+
+            match field {
+                Some(pos) if positions[pos].is_none() => positions[pos] = Some(column),
+                _ => ignored.push(name.to_string()),
+            }
+        }
+
+        if !ignored.is_empty() {
+            log::debug!("Columnas ignoradas del CSV: {}", ignored.join(", "));
+        }
+
+        for (pos, field) in FIELD_NAMES.iter().enumerate() {
+            if positions[pos].is_none() && !OPTIONAL_FIELDS.contains(field) {
+                return Err(format!("Falta la columna requerida '{}' en el CSV", field).into());
+            }
+        }
+
+        Ok(Self { positions })
+    }
+
+    pub fn contains(&self, field: usize) -> bool {
+        self.positions[field].is_some()
+    }
+
+    // Campos del registro en el orden de FIELD_NAMES, vacíos si la columna no existe.
+    pub fn fields<'r>(&self, record: &'r StringRecord) -> [&'r str; FIELD_NAMES.len()] {
+        self.fields_with(|p| record.get(p))
+    }
+
+    pub fn fields_with<'r>(
+        &self,
+        get: impl Fn(usize) -> Option<&'r str>,
+    ) -> [&'r str; FIELD_NAMES.len()] {
+        self.positions.map(|pos| pos.and_then(&get).unwrap_or(""))
+    }
+
+    pub fn position(&self, field: usize) -> Option<usize> {
+        self.positions[field]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::data_lector::stream_file;
+    use crate::data::trip_struct::{INDEX_FIELD, Trip};
+    use std::fs;
+    use std::path::PathBuf;
+
+    // Directorio vacío y propio de cada prueba
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("csv-schema-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn field(name: &str) -> usize {
+        FIELD_NAMES.iter().position(|f| *f == name).unwrap()
+    }
+
+    fn read_all(path: &std::path::Path, options: &CsvOptions) -> Vec<Trip> {
+        let mut trips = Vec::new();
+        stream_file(path, options, &[true; FIELD_NAMES.len()], 0, |trip| {
+            trips.push(trip.clone());
+            Ok(())
+        })
+        .unwrap();
+        trips
+    }
+
+    const ROW: &str = "2,2024-01-04 22:19:23,2024-01-04 22:30:40,2,9.95,1,N,44,38,2,21.5,0.5,0.5,2.0,0.0,0.3,24.8,2.5";
+
+    #[test]
+    fn headers_map_by_alias_case_and_underscores() {
+        let headers = StringRecord::from(vec![
+            "\u{feff}VendorID",
+            "TPEP_Pickup_Datetime",
+            "tpep dropoff datetime",
+            "passenger_count",
+            "trip_distance",
+            "RatecodeID",
+            "store_and_fwd_flag",
+            "PULocationID",
+            "Dropoff",
+            "payment_type",
+            "fare_amount",
+            "extra",
+            "mta_tax",
+            "tip_amount",
+            "tolls_amount",
+            "improvement_surcharge",
+            "total_amount",
+            "notes",
+            "DOLocationID",
+        ]);
+        let mut options = CsvOptions::default();
+        options
+            .aliases
+            .insert("Dropoff".to_string(), "do_location_id".to_string());
+        let mapping = ColumnMapping::from_options(Some(&headers), &options).unwrap();
+
+        assert_eq!(mapping.position(field("vendor_id")), Some(0));
+        assert_eq!(mapping.position(field("tpep_pickup_datetime")), Some(1));
+        assert_eq!(mapping.position(field("tpep_dropoff_datetime")), Some(2));
+        assert_eq!(mapping.position(field("pu_location_id")), Some(7));
+        // La primera columna que resuelve al campo gana, la repetida se ignora
+        assert_eq!(mapping.position(field("do_location_id")), Some(8));
+        // Los opcionales pueden faltar
+        assert!(!mapping.contains(field("congestion_surcharge")));
+        assert!(!mapping.contains(INDEX_FIELD));
+    }
+
+    #[test]
+    fn missing_columns_and_bad_aliases_are_errors() {
+        let headers = StringRecord::from(vec!["VendorID", "tpep_pickup_datetime"]);
+        let error = ColumnMapping::from_options(Some(&headers), &CsvOptions::default())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("tpep_dropoff_datetime"), "{}", error);
+
+        let mut options = CsvOptions::default();
+        options
+            .aliases
+            .insert("Fare".to_string(), "fare".to_string());
+        let error = ColumnMapping::from_options(Some(&headers), &options)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("'fare'"), "{}", error);
+    }
+
+    #[test]
+    fn a_file_without_header_uses_csv_columns_or_the_canonical_order() {
+        let canonical = ColumnMapping::from_options(None, &CsvOptions::default()).unwrap();
+        for pos in 0..FIELD_NAMES.len() {
+            assert_eq!(canonical.position(pos), Some(pos));
+        }
+
+        // Columnas en otro orden y sin índice: se numera con la fila
+        let dir = test_dir("no-header");
+        let path = dir.join("trips.csv");
+        fs::write(&path, format!("7,{}\n8,{}\n", ROW, ROW)).unwrap();
+        let mut columns = vec!["do_location_id".to_string()];
+        columns.extend(
+            FIELD_NAMES[..INDEX_FIELD]
+                .iter()
+                .map(|name| name.to_string()),
+        );
+        let options = CsvOptions {
+            has_headers: false,
+            columns: Some(columns),
+            ..CsvOptions::default()
+        };
+        let trips = read_all(&path, &options);
+        assert_eq!(trips.len(), 2);
+        assert_eq!(trips[0].do_location_id, 7);
+        assert_eq!(trips[0].vendor_id, 2);
+        assert_eq!(trips[1].index, 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_legacy_encoding_and_dialect_are_decoded() {
+        let dir = test_dir("encoding");
+        let path = dir.join("trips.csv");
+        let header = FIELD_NAMES[..INDEX_FIELD].join(";");
+        let row = ROW.replace(',', ";").replace(";N;", ";'N';");
+        // "Año" en windows-1252: la ñ es el byte 0xF1, que no es UTF-8 válido
+        let text = format!("{};A\u{f1}o\n{};2024\n", header, row);
+        let (bytes, _, _) = encoding_rs::WINDOWS_1252.encode(&text);
+        assert!(std::str::from_utf8(&bytes).is_err());
+        fs::write(&path, &bytes).unwrap();
+
+        let options = CsvOptions {
+            delimiter: b';',
+            quote: b'\'',
+            encoding: Some(encoding_rs::WINDOWS_1252),
+            ..CsvOptions::default()
+        };
+        let trips = read_all(&path, &options);
+        assert_eq!(trips.len(), 1);
+        assert_eq!(trips[0].do_location_id, 38);
+        assert_eq!(trips[0].index, 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,53 +1,215 @@
-use super::trip_struct::Trip;
+use super::compression::{Compression, open_decompressed};
+use super::csv_schema::{ColumnMapping, CsvOptions};
+use super::dataset::{Dataset, TimeRange};
+use super::trip_struct::{FIELD_NAMES, INDEX_FIELD, Trip, projection_mask};
+use crate::config;
+use csv::StringRecord;
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::Path;
 
-// Función para procesar CSV en streaming con bajo consumo de memoria
-pub fn stream_process_csv<P, F>(filename: P, mut process_trip: F) -> Result<(), Box<dyn Error>>
+const PARQUET_MAGIC: &[u8; 4] = b"PAR1";
+
+// Función para procesar CSV en streaming con bajo consumo de memoria.
+// Los archivos Parquet y los CSV comprimidos (gzip, zstd, bzip2) se detectan y se leen
+// con el mismo contrato. La ruta puede ser un archivo, un directorio o un glob.
+pub fn stream_process_csv<P, F>(filename: P, process_trip: F) -> Result<(), Box<dyn Error>>
+where
+    P: AsRef<Path>,
+    F: FnMut(&Trip) -> Result<(), Box<dyn Error>>,
+{
+    stream_process_pruned(filename, &TimeRange::default(), &FIELD_NAMES, process_trip)
+}
+
+// Solo se validan (y en Parquet solo se leen) los campos indicados, el resto del Trip
+// queda en su valor por defecto. Se saltan las particiones cuyo rango de pickup no se
+// cruza con `range`.
+pub fn stream_process_pruned<P, F>(
+    filename: P,
+    range: &TimeRange,
+    fields: &[&str],
+    process_trip: F,
+) -> Result<(), Box<dyn Error>>
 where
     P: AsRef<Path>,
     F: FnMut(&Trip) -> Result<(), Box<dyn Error>>,
 {
-    let file = File::open(filename)?;
-    let buf_reader = BufReader::with_capacity(64 * 1024, file);
-    let mut csv_reader = csv::ReaderBuilder::new()
-        .buffer_capacity(128 * 1024)
-        .has_headers(true)
-        .from_reader(buf_reader);
-
-    for result in csv_reader.records() {
-        match result {
-            Ok(record) => {
-                if record.len() < 19 {
-                    eprintln!("Registro con formato incorrecto: {:?}", record);
+    Dataset::open(filename)?.stream(range, &projection_mask(fields), process_trip)
+}
+
+// Posición de lectura dentro de un archivo: filas ya leídas y, si es un CSV sin
+// comprimir, el byte donde empieza la fila siguiente para poder saltar directamente.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilePosition {
+    pub rows: u64,
+    pub byte: Option<u64>,
+}
+
+// Lee un único archivo. Los índices sintéticos empiezan en `row_offset + 1` y se
+// devuelve el número de filas leídas para que la siguiente partición continúe.
+pub fn stream_file<F>(
+    path: &Path,
+    options: &CsvOptions,
+    wanted: &[bool; FIELD_NAMES.len()],
+    row_offset: u64,
+    mut process_trip: F,
+) -> Result<u64, Box<dyn Error>>
+where
+    F: FnMut(&Trip) -> Result<(), Box<dyn Error>>,
+{
+    stream_file_from(
+        path,
+        options,
+        wanted,
+        row_offset,
+        FilePosition::default(),
+        |trip, _| process_trip(trip),
+    )
+}
+
+// Igual que stream_file pero empezando en `start`. El callback recibe además la
+// posición justo después del viaje, que es desde donde habría que continuar. El total
+// devuelto incluye las filas saltadas.
+pub fn stream_file_from<F>(
+    path: &Path,
+    options: &CsvOptions,
+    wanted: &[bool; FIELD_NAMES.len()],
+    row_offset: u64,
+    start: FilePosition,
+    process_trip: F,
+) -> Result<u64, Box<dyn Error>>
+where
+    F: FnMut(&Trip, FilePosition) -> Result<(), Box<dyn Error>>,
+{
+    if is_parquet(path)? {
+        super::parquet_lector::stream_process_parquet(
+            path,
+            options,
+            wanted,
+            row_offset,
+            start.rows,
+            process_trip,
+        )
+    } else {
+        read_csv(path, options, wanted, row_offset, start, process_trip)
+    }
+}
+
+fn is_parquet(path: &Path) -> Result<bool, Box<dyn Error>> {
+    if path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("parquet"))
+    {
+        return Ok(true);
+    }
+    let mut magic = [0u8; 4];
+    let mut file = File::open(path)?;
+    Ok(file.read_exact(&mut magic).is_ok() && &magic == PARQUET_MAGIC)
+}
+
+fn read_csv<P, F>(
+    filename: P,
+    options: &CsvOptions,
+    wanted: &[bool; FIELD_NAMES.len()],
+    row_offset: u64,
+    start: FilePosition,
+    mut process_trip: F,
+) -> Result<u64, Box<dyn Error>>
+where
+    P: AsRef<Path>,
+    F: FnMut(&Trip, FilePosition) -> Result<(), Box<dyn Error>>,
+{
+    let path = filename.as_ref();
+    // Los bytes del lector solo coinciden con los del archivo si no hay descompresión
+    // ni transcodificación de por medio
+    let compression = match options.compression {
+        Some(compression) => compression,
+        None => Compression::detect(path)?,
+    };
+    let tracks_bytes = compression == Compression::None && options.encoding.is_none();
+
+    let mut skip_rows = start.rows;
+    let mut base_byte = 0;
+    let (mut csv_reader, headers) =
+        match start.byte {
+            Some(byte) if tracks_bytes && start.rows > 0 => {
+                // La cabecera se lee del principio y el resto desde el byte guardado
+                let headers = if options.has_headers {
+                    Some(options.reader_builder().from_path(path)?.headers()?.clone())
+                } else {
+                    None
+                };
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(byte))?;
+                let reader: Box<dyn Read> = Box::new(file);
+                let csv_reader = options.reader_builder().has_headers(false).from_reader(
+                    BufReader::with_capacity(config::get().read_buffer_bytes, reader),
+                );
+                skip_rows = 0;
+                base_byte = byte;
+                (csv_reader, headers)
+            }
+            _ => {
+                let file = open_decompressed(path, compression)?;
+                // La decodificación también es en streaming, no se carga el archivo completo
+                let reader: Box<dyn Read> = match options.encoding {
+                    Some(encoding) => Box::new(
+                        DecodeReaderBytesBuilder::new()
+                            .encoding(Some(encoding))
+                            .build(file),
+                    ),
+                    None => file,
+                };
+                let buf_reader = BufReader::with_capacity(config::get().read_buffer_bytes, reader);
+                let mut csv_reader = options.reader_builder().from_reader(buf_reader);
+                let headers = if options.has_headers {
+                    Some(csv_reader.headers()?.clone())
+                } else {
+                    None
+                };
+                (csv_reader, headers)
+            }
+        };
+    let mapping = ColumnMapping::from_options(headers.as_ref(), options)?;
+    let has_index = mapping.contains(INDEX_FIELD);
+
+    let mut row_number = row_offset + start.rows - skip_rows;
+    let mut record = StringRecord::new();
+    loop {
+        match csv_reader.read_record(&mut record) {
+            Ok(false) => break,
+            Ok(true) => {
+                row_number += 1;
+                // Al reanudar sin posición en bytes se avanza fila por fila sin validar
+                if skip_rows > 0 {
+                    skip_rows -= 1;
                     continue;
                 }
+                let mut fields = mapping.fields(&record);
 
-                let trip = Trip {
-                    vendor_id: record[0].to_string(),
-                    tpep_pickup_datetime: record[1].to_string(),
-                    tpep_dropoff_datetime: record[2].to_string(),
-                    passenger_count: record[3].to_string(),
-                    trip_distance: record[4].to_string(),
-                    ratecode_id: record[5].to_string(),
-                    store_and_fwd_flag: record[6].to_string(),
-                    pu_location_id: record[7].to_string(),
-                    do_location_id: record[8].to_string(),
-                    payment_type: record[9].to_string(),
-                    fare_amount: record[10].to_string(),
-                    extra: record[11].to_string(),
-                    mta_tax: record[12].to_string(),
-                    tip_amount: record[13].to_string(),
-                    tolls_amount: record[14].to_string(),
-                    improvement_surcharge: record[15].to_string(),
-                    total_amount: record[16].to_string(),
-                    congestion_surcharge: record[17].to_string(),
-                    index: record[18].to_string(),
-                };
+                // Sin columna de índice se usa el número de fila
+                let row_index;
+                if !has_index {
+                    row_index = row_number.to_string();
+                    fields[INDEX_FIELD] = &row_index;
+                }
 
-                process_trip(&trip)?;
+                match Trip::from_fields_projected(&fields, wanted) {
+                    Ok(trip) => {
+                        let position = FilePosition {
+                            rows: row_number - row_offset,
+                            byte: tracks_bytes.then(|| base_byte + csv_reader.position().byte()),
+                        };
+                        process_trip(&trip, position)?
+                    }
+                    Err(e) => {
+                        let line = record.position().map_or(0, |p| p.line());
+                        eprintln!("Registro inválido en la línea {}: {}", line, e);
+                    }
+                }
             }
             Err(e) => {
                 eprintln!("Error al leer registro: {}", e);
@@ -55,5 +217,5 @@ where
         }
     }
 
-    Ok(())
+    Ok(row_number - row_offset)
 }
@@ -0,0 +1,437 @@
+use super::csv_schema::CsvOptions;
+use super::data_lector::{FilePosition, stream_file, stream_file_from};
+use super::trip_struct::{FIELD_NAMES, Trip, projection_mask};
+use crate::config;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::UNIX_EPOCH;
+
+// Metadatos de las particiones ya escaneadas, sobreviven a reinicios.
+static PARTITION_CACHE: LazyLock<Mutex<Option<HashMap<PathBuf, Partition>>>> =
+    LazyLock::new(|| Mutex::new(None));
+
+// Rango cerrado sobre tpep_pickup_datetime, None en un extremo significa sin límite.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeRange {
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+}
+
+impl TimeRange {
+    pub fn is_unbounded(&self) -> bool {
+        self.from.is_none() && self.to.is_none()
+    }
+
+    pub fn contains(&self, time: NaiveDateTime) -> bool {
+        self.from.is_none_or(|from| time >= from) && self.to.is_none_or(|to| time <= to)
+    }
+
+    pub fn intersect(&self, other: &TimeRange) -> TimeRange {
+        TimeRange {
+            from: self.from.max(other.from),
+            to: match (self.to, other.to) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+        }
+    }
+
+    // La unión de dos rangos se aproxima por el rango que cubre a ambos
+    pub fn hull(&self, other: &TimeRange) -> TimeRange {
+        TimeRange {
+            from: match (self.from, other.from) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                _ => None,
+            },
+            to: match (self.to, other.to) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn overlaps(&self, partition: &Partition) -> bool {
+        match (partition.min_pickup, partition.max_pickup) {
+            (Some(min), Some(max)) => {
+                self.from.is_none_or(|from| max >= from) && self.to.is_none_or(|to| min <= to)
+            }
+            // Una partición sin viajes válidos no puede cumplir ningún rango
+            _ => self.is_unbounded(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Partition {
+    pub path: PathBuf,
+    // Mes de la partición (YYYY-MM), del nombre del archivo o del primer pickup
+    pub month: Option<String>,
+    pub rows: u64,
+    pub min_pickup: Option<NaiveDateTime>,
+    pub max_pickup: Option<NaiveDateTime>,
+    size: u64,
+    modified: u64,
+}
+
+pub struct Dataset {
+    files: Vec<PathBuf>,
+    // Dialecto de la configuración, igual para todas las particiones
+    options: CsvOptions,
+}
+
+// Posición de lectura de un dataset completo: archivo actual, filas de los archivos
+// anteriores (base de los índices sintéticos) y posición dentro del archivo.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatasetPosition {
+    pub file: usize,
+    pub row_offset: u64,
+    pub within: FilePosition,
+}
+
+impl Dataset {
+    // Acepta un archivo, un directorio (todos sus archivos visibles) o un glob
+    pub fn open<P: AsRef<Path>>(spec: P) -> Result<Self, Box<dyn Error>> {
+        let spec = spec.as_ref();
+        let spec_str = spec.to_string_lossy();
+
+        let mut files = Vec::new();
+        if spec_str.contains(['*', '?', '[']) {
+            for entry in glob::glob(&spec_str)? {
+                let path = entry?;
+                if path.is_file() {
+                    files.push(path);
+                }
+            }
+        } else if spec.is_dir() {
+            for entry in fs::read_dir(spec)? {
+                let path = entry?.path();
+                let hidden = path
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+                if path.is_file() && !hidden {
+                    files.push(path);
+                }
+            }
+        } else {
+            files.push(spec.to_path_buf());
+        }
+
+        if files.is_empty() {
+            return Err(format!("El dataset '{}' no contiene archivos", spec_str).into());
+        }
+        // El orden por nombre coincide con el orden por mes en los exports de NYC TLC
+        files.sort();
+
+        Ok(Self {
+            files,
+            options: config::get().csv_options()?,
+        })
+    }
+
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    pub fn partitions(&self) -> Result<Vec<Partition>, Box<dyn Error>> {
+        self.files
+            .iter()
+            .map(|f| partition_metadata(f, &self.options))
+            .collect()
+    }
+
+    // Recorre las particiones en orden. Con un rango acotado se consultan los metadatos
+    // de cada partición y se saltan las que no pueden tener coincidencias.
+    pub fn stream<F>(
+        &self,
+        range: &TimeRange,
+        wanted: &[bool; FIELD_NAMES.len()],
+        mut process_trip: F,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        F: FnMut(&Trip) -> Result<(), Box<dyn Error>>,
+    {
+        // Los índices sintéticos siguen numerándose entre particiones
+        let mut row_offset = 0;
+        for file in &self.files {
+            if !range.is_unbounded() {
+                let partition = partition_metadata(file, &self.options)?;
+                if !range.overlaps(&partition) {
+                    log::debug!("Partición descartada por rango: {}", file.display());
+                    row_offset += partition.rows;
+                    continue;
+                }
+            }
+            row_offset += stream_file(file, &self.options, wanted, row_offset, &mut process_trip)?;
+        }
+
+        Ok(())
+    }
+
+    // Recorre todo el dataset desde `start`, sin poda. El callback recibe la posición
+    // desde la que continuar después de cada viaje.
+    pub fn stream_from<F>(
+        &self,
+        start: &DatasetPosition,
+        wanted: &[bool; FIELD_NAMES.len()],
+        mut process_trip: F,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        F: FnMut(&Trip, &DatasetPosition) -> Result<(), Box<dyn Error>>,
+    {
+        let mut row_offset = start.row_offset;
+        for (file_number, file) in self.files.iter().enumerate().skip(start.file) {
+            let within = if file_number == start.file {
+                start.within
+            } else {
+                FilePosition::default()
+            };
+            row_offset += stream_file_from(
+                file,
+                &self.options,
+                wanted,
+                row_offset,
+                within,
+                |trip, within| {
+                    let position = DatasetPosition {
+                        file: file_number,
+                        row_offset,
+                        within,
+                    };
+                    process_trip(trip, &position)
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn file_fingerprint(path: &Path) -> Result<(u64, u64), Box<dyn Error>> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Ok((metadata.len(), modified))
+}
+
+pub fn partition_metadata(path: &Path, options: &CsvOptions) -> Result<Partition, Box<dyn Error>> {
+    let (size, modified) = file_fingerprint(path)?;
+
+    // El recorrido de la partición se hace sin el lock, así las consultas sobre otras
+    // particiones no esperan. Si dos hilos calculan la misma, ambos llegan al mismo valor.
+    {
+        let mut cache = PARTITION_CACHE.lock().unwrap();
+        let cache = cache.get_or_insert_with(load_partitions);
+        if let Some(partition) = cache.get(path)
+            && partition.size == size
+            && partition.modified == modified
+        {
+            return Ok(partition.clone());
+        }
+    }
+
+    println!("Calculando metadatos de la partición {}...", path.display());
+    let mut min_pickup: Option<NaiveDateTime> = None;
+    let mut max_pickup: Option<NaiveDateTime> = None;
+    let wanted = projection_mask(&["tpep_pickup_datetime"]);
+    let rows = stream_file(path, options, &wanted, 0, |trip| {
+        let pickup = trip.tpep_pickup_datetime;
+        min_pickup = Some(min_pickup.map_or(pickup, |m| m.min(pickup)));
+        max_pickup = Some(max_pickup.map_or(pickup, |m| m.max(pickup)));
+        Ok(())
+    })?;
+
+    let partition = Partition {
+        path: path.to_path_buf(),
+        month: month_from_name(path).or_else(|| min_pickup.map(|m| m.format("%Y-%m").to_string())),
+        rows,
+        min_pickup,
+        max_pickup,
+        size,
+        modified,
+    };
+    let mut cache = PARTITION_CACHE.lock().unwrap();
+    let cache = cache.get_or_insert_with(load_partitions);
+    cache.insert(path.to_path_buf(), partition.clone());
+    if let Err(e) = save_partitions(cache) {
+        eprintln!("Error al guardar metadatos de particiones: {}", e);
+    }
+
+    Ok(partition)
+}
+
+// Busca un YYYY-MM en el nombre, como en yellow_tripdata_2019-01.parquet
+fn month_from_name(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let bytes = name.as_bytes();
+    (0..bytes.len().saturating_sub(6)).find_map(|i| {
+        let candidate = &bytes[i..i + 7];
+        let is_month = candidate[..4].iter().all(u8::is_ascii_digit)
+            && candidate[4] == b'-'
+            && candidate[5..].iter().all(u8::is_ascii_digit);
+        is_month.then(|| name[i..i + 7].to_string())
+    })
+}
+
+fn load_partitions() -> HashMap<PathBuf, Partition> {
+    File::open(&config::get().partitions_cache)
+        .ok()
+        .and_then(|file| serde_json::from_reader::<_, Vec<Partition>>(BufReader::new(file)).ok())
+        .map(|partitions| {
+            partitions
+                .into_iter()
+                .map(|p| (p.path.clone(), p))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn save_partitions(cache: &HashMap<PathBuf, Partition>) -> Result<(), Box<dyn Error>> {
+    let cache_path = Path::new(&config::get().partitions_cache);
+    if let Some(parent) = cache_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let partitions: Vec<&Partition> = cache.values().collect();
+    let writer = BufWriter::new(File::create(cache_path)?);
+    serde_json::to_writer(writer, &partitions)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    // Directorio vacío y propio de cada prueba
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dataset-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const HEADER: &str = "VendorID,tpep_pickup_datetime,tpep_dropoff_datetime,passenger_count,trip_distance,RatecodeID,store_and_fwd_flag,PULocationID,DOLocationID,payment_type,fare_amount,extra,mta_tax,tip_amount,tolls_amount,improvement_surcharge,total_amount,congestion_surcharge,Index";
+
+    // Una partición con un viaje por cada pickup, con índices a partir de `first_index`
+    fn write_partition(path: &Path, pickups: &[&str], first_index: u64) {
+        let mut csv = format!("{}\n", HEADER);
+        for (i, pickup) in pickups.iter().enumerate() {
+            csv.push_str(&format!(
+                "1,{},{},1,1.5,1,N,10,20,1,7.5,0.5,0.5,0,0,0.3,8.8,2.5,{}\n",
+                pickup,
+                pickup,
+                first_index + i as u64
+            ));
+        }
+        fs::write(path, csv).unwrap();
+    }
+
+    fn at(day: &str) -> NaiveDateTime {
+        NaiveDate::parse_from_str(day, "%Y-%m-%d")
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn a_directory_lists_its_visible_files_in_order() {
+        let dir = test_dir("directory");
+        write_partition(&dir.join("trips_2019-02.csv"), &["2019-02-01 08:00:00"], 1);
+        write_partition(&dir.join("trips_2019-01.csv"), &["2019-01-01 08:00:00"], 2);
+        fs::write(dir.join(".trips_2019-03.csv.swp"), "").unwrap();
+        fs::create_dir(dir.join("old")).unwrap();
+
+        let dataset = Dataset::open(&dir).unwrap();
+        assert_eq!(
+            dataset.files(),
+            [dir.join("trips_2019-01.csv"), dir.join("trips_2019-02.csv")]
+        );
+
+        let glob = Dataset::open(dir.join("*-02.csv")).unwrap();
+        assert_eq!(glob.files(), [dir.join("trips_2019-02.csv")]);
+        assert!(Dataset::open(dir.join("*.parquet")).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_month_comes_from_the_file_name() {
+        assert_eq!(
+            month_from_name(Path::new("data/yellow_tripdata_2019-01.parquet")),
+            Some("2019-01".to_string())
+        );
+        assert_eq!(
+            month_from_name(Path::new("2020-12")),
+            Some("2020-12".to_string())
+        );
+        assert_eq!(month_from_name(Path::new("trips_2019.csv")), None);
+        assert_eq!(month_from_name(Path::new("v1-02.csv")), None);
+    }
+
+    #[test]
+    fn partitions_outside_the_range_are_skipped() {
+        let dir = test_dir("pruning");
+        write_partition(
+            &dir.join("trips_a.csv"),
+            &["2019-01-03 10:00:00", "2019-01-20 10:00:00"],
+            1,
+        );
+        write_partition(
+            &dir.join("trips_b.csv"),
+            &["2019-02-05 10:00:00", "2019-02-25 10:00:00"],
+            3,
+        );
+        let dataset = Dataset::open(&dir).unwrap();
+
+        let partitions = dataset.partitions().unwrap();
+        assert_eq!(partitions[0].rows, 2);
+        // Sin mes en el nombre se toma el del primer pickup
+        assert_eq!(partitions[1].month.as_deref(), Some("2019-02"));
+        assert_eq!(
+            partitions[1].min_pickup,
+            Some(at("2019-02-05") + chrono::Duration::hours(10))
+        );
+
+        let wanted = [true; FIELD_NAMES.len()];
+        let mut indices = Vec::new();
+        let february = TimeRange {
+            from: Some(at("2019-02-01")),
+            to: Some(at("2019-02-10")),
+        };
+        dataset
+            .stream(&february, &wanted, |trip| {
+                indices.push(trip.index);
+                Ok(())
+            })
+            .unwrap();
+        // La partición de febrero se lee entera, la poda no filtra viaje por viaje
+        assert_eq!(indices, [3, 4]);
+
+        indices.clear();
+        dataset
+            .stream(&TimeRange::default(), &wanted, |trip| {
+                indices.push(trip.index);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(indices, [1, 2, 3, 4]);
+
+        let gap = TimeRange {
+            from: Some(at("2019-01-21")),
+            to: Some(at("2019-02-04")),
+        };
+        dataset
+            .stream(&gap, &wanted, |_| {
+                panic!("ninguna partición cae en el hueco")
+            })
+            .unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
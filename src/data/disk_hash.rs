@@ -1,20 +1,35 @@
-use super::trip_struct::Trip;
+use super::bitmap_index::{BitmapBuilder, BitmapIndex};
+use super::column_store::{ColumnBuilder, ColumnStore, RowGroup};
+use super::dataset::{Dataset, DatasetPosition};
+use super::index_manifest::{BuildCheckpoint, IndexManifest, MANIFEST_FILE, SourceFingerprint};
+use super::posting_index::{LocationColumn, LocationIndex, PostingsBuilder};
+use super::range_index::{KeyRange, RangeBuilder, RangeColumn, RangeIndex};
+use super::record_codec::{ENCODED_TRIP_LEN, decode_trip, encode_trip};
+use super::trip_struct::{FIELD_NAMES, Trip};
+use super::wal::{self, WAL_FILE};
+use crate::config;
+use memmap2::Mmap;
 use odht::{Config, FxHashFn, HashTable, HashTableOwned};
-use std::collections::hash_map::DefaultHasher;
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::collections::HashMap;
 use std::error::Error;
-use std::fs::{File, OpenOptions, create_dir_all};
-use std::hash::{Hash, Hasher};
-use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::fs::{self, File, OpenOptions, create_dir_all};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 struct TripHashConfig;
 
+// Los valores son desplazamientos de 64 bits dentro de trip_data.bin, así el archivo de
+// datos puede superar los 4 GiB.
 impl Config for TripHashConfig {
     type Key = u64;
-    type Value = u32; //Se usa U8 porque lo solicita la libreria.
+    type Value = u64;
 
     type EncodedKey = [u8; 8];
-    type EncodedValue = [u8; 4];
+    type EncodedValue = [u8; 8];
 
     type H = FxHashFn;
 
@@ -35,20 +50,243 @@ impl Config for TripHashConfig {
 
     #[inline]
     fn decode_value(v: &Self::EncodedValue) -> Self::Value {
-        u32::from_le_bytes(*v)
+        u64::from_le_bytes(*v)
     }
 }
 
-fn calculate_hash(key: &str) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    key.hash(&mut hasher);
-    hasher.finish()
+// Byte de la cabecera de odht con el tamaño del valor codificado. Se lee a mano porque
+// from_raw_bytes hace un debug_assert en lugar de devolver error si no coincide.
+const HEADER_VALUE_SIZE_OFFSET: usize = 6;
+
+// La tabla se mapea de solo lectura: entre construcciones no cambia, los registros
+// escritos después viven en el overlay de DiskHashTable.
+fn open_table(table_path: &Path) -> Result<HashTable<TripHashConfig, MappedBytes>, Box<dyn Error>> {
+    let mut header = [0u8; HEADER_VALUE_SIZE_OFFSET + 1];
+    File::open(table_path)?.read_exact(&mut header)?;
+    // Las tablas de formatos anteriores no se migran: el manifiesto ya no coincide con
+    // INDEX_FORMAT_VERSION y el arranque reconstruye el índice desde el CSV
+    if header[HEADER_VALUE_SIZE_OFFSET] != size_of::<u64>() as u8 {
+        return Err(format!(
+            "{} usa un formato de índice antiguo, hay que reconstruirlo",
+            table_path.display()
+        )
+        .into());
+    }
+
+    HashTable::from_raw_bytes(MappedBytes(map_file(table_path)?))
+}
+
+// FNV-1a de 64 bits. A diferencia de DefaultHasher su salida está fijada por la
+// especificación, así que un índice persistido sigue siendo válido tras actualizar Rust.
+// El número de sondeo se mezcla al final para obtener posiciones alternativas.
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+const MAX_PROBES: u64 = 16;
+
+// Se guardan en el manifiesto; cambiar cualquiera de los dos obliga a reconstruir.
+pub const INDEX_FORMAT_VERSION: u32 = 9;
+pub const HASH_FUNCTION_ID: &str = "fnv1a-64";
+
+// Mínimo de filas entre checkpoints durante la carga masiva
+const CHECKPOINT_MIN_ROWS: u64 = 1_000_000;
+
+// Por debajo de esto no se compacta aunque la proporción de basura sea alta
+const COMPACTION_MIN_DEAD_BYTES: u64 = 1024 * 1024;
+// Claves escritas desde la última construcción a partir de las que se compacta aunque
+// no haya basura. El overlay y los deltas se copian y se guardan enteros en cada lote,
+// así que su tamaño acota lo que cuesta cada escritura.
+const COMPACTION_MAX_OVERLAY_KEYS: usize = 50_000;
+// Lo escrito y todavía sin mapear se vuelve a mapear al pasar este tamaño aunque el
+// lote no haya terminado
+const TAIL_REMAP_BYTES: usize = 8 * 1024 * 1024;
+
+const LOAD_FACTOR_PERCENT: u8 = 90;
+const CHECKSUM_LEN: usize = 4;
+const STATS_FILE: &str = "data_stats.json";
+
+fn calculate_hash(key: &str, probe: u64) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    let probe_bytes = probe.to_le_bytes();
+    let salt: &[u8] = if probe == 0 { &[] } else { &probe_bytes };
+    for byte in key.as_bytes().iter().chain(salt) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+enum Slot {
+    // Ninguna entrada en la secuencia de sondeo, la clave iría en este hash
+    Free(u64),
+    // La clave ya está indexada en este hash y apunta a esta posición
+    Taken(u64, u64),
+}
+
+// Recorre la secuencia de sondeo de la clave comparando la clave guardada en cada
+// registro, así una colisión de hash nunca devuelve ni pisa el viaje de otra clave.
+fn find_slot(
+    key: &str,
+    lookup: impl Fn(u64) -> Option<u64>,
+    mut key_matches: impl FnMut(u64) -> Result<bool, Box<dyn Error>>,
+) -> Result<Slot, Box<dyn Error>> {
+    for probe in 0..MAX_PROBES {
+        let hash = calculate_hash(key, probe);
+        match lookup(hash) {
+            None => return Ok(Slot::Free(hash)),
+            Some(position) if key_matches(position)? => return Ok(Slot::Taken(hash, position)),
+            Some(_) => {}
+        }
+    }
+    Err(format!("Demasiadas colisiones para la clave {}", key).into())
+}
+
+// Registro en trip_data.bin: [u32 tamaño][u32 crc32][u16 largo de la clave][clave]
+// [trip binario]. El tamaño cuenta todo lo que viene después de él y el crc32 cubre lo
+// que viene después del crc; el trip lleva su propio byte de versión (ver
+// record_codec). Un registro sin trip es una lápida: la clave se borró.
+fn encode_record(key: &str, trip: Option<&Trip>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key_len = u16::try_from(key.len()).map_err(|_| "Clave demasiado larga")?;
+    let trip_len = if trip.is_some() { ENCODED_TRIP_LEN } else { 0 };
+    let size = (CHECKSUM_LEN + 2 + key.len() + trip_len) as u32;
+
+    let mut record = Vec::with_capacity(4 + size as usize);
+    record.extend_from_slice(&size.to_le_bytes());
+    record.extend_from_slice(&[0; CHECKSUM_LEN]);
+    record.extend_from_slice(&key_len.to_le_bytes());
+    record.extend_from_slice(key.as_bytes());
+    if let Some(trip) = trip {
+        encode_trip(trip, &mut record);
+    }
+    let checksum = crc32fast::hash(&record[4 + CHECKSUM_LEN..]);
+    record[4..4 + CHECKSUM_LEN].copy_from_slice(&checksum.to_le_bytes());
+    Ok(record)
+}
+
+// Largo total del registro, incluido su prefijo de tamaño
+fn record_len(data: &[u8], position: u64) -> Result<u64, Box<dyn Error>> {
+    let start = position as usize;
+    let size_bytes = data
+        .get(start..start + 4)
+        .ok_or("Posición fuera del archivo de datos")?;
+    Ok(4 + u32::from_le_bytes(size_bytes.try_into()?) as u64)
+}
+
+// Clave y trip codificado del registro. Si el checksum no coincide los bytes no se
+// interpretan.
+fn decode_record(data: &[u8], position: u64) -> Result<(&str, &[u8]), Box<dyn Error>> {
+    let start = position as usize;
+    let size_bytes = data
+        .get(start..start + 4)
+        .ok_or("Posición fuera del archivo de datos")?;
+    let size = u32::from_le_bytes(size_bytes.try_into()?) as usize;
+    let record = data
+        .get(start + 4..start + 4 + size)
+        .ok_or("Registro truncado en el archivo de datos")?;
+    let (checksum, record) = record
+        .split_first_chunk::<CHECKSUM_LEN>()
+        .ok_or("Registro sin checksum")?;
+    if u32::from_le_bytes(*checksum) != crc32fast::hash(record) {
+        return Err(format!(
+            "Checksum inválido en el registro de la posición {}",
+            position
+        )
+        .into());
+    }
+    let key_len = u16::from_le_bytes(record.get(..2).ok_or("Registro sin clave")?.try_into()?);
+    let key_end = 2 + key_len as usize;
+    let key = std::str::from_utf8(record.get(2..key_end).ok_or("Clave truncada")?)?;
+    Ok((key, &record[key_end..]))
+}
+
+// Los bytes mapeados se pasan a odht, que solo los toma prestados.
+struct MappedBytes(Mmap);
+
+impl Borrow<[u8]> for MappedBytes {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
 }
 
+// Mapea un archivo completo en memoria de solo lectura.
+pub fn map_file(path: &Path) -> Result<Mmap, Box<dyn Error>> {
+    let file = File::open(path)?;
+    // SAFETY: trip_data.bin solo crece por el final y nunca se trunca en uso; las
+    // reconstrucciones escriben un archivo nuevo y lo renombran encima, así que los
+    // bytes mapeados siguen intactos mientras existan lectores usándolo. La tabla y
+    // los índices secundarios no se modifican en sitio, también se reemplazan con rename.
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+// Reemplaza un archivo de forma atómica escribiendo primero a un temporal.
+pub fn replace_file(path: &Path, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+    let tmp_path = path.with_extension("bin.tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// Bytes de trip_data.bin a los que ya no apunta ninguna entrada: versiones
+// reemplazadas, registros borrados y sus lápidas. La compactación los recupera.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DataStats {
+    pub dead_bytes: u64,
+    pub tombstones: u64,
+    // Largo del archivo de datos cuando se escribió la tabla; lo que sigue se reaplica
+    // al overlay al abrir
+    #[serde(default)]
+    pub indexed_len: u64,
+    // Registros dañados que fsck ya dio por perdidos al reparar. Cuentan como bytes
+    // muertos y desaparecen con la próxima compactación.
+    #[serde(default)]
+    pub quarantined: Vec<u64>,
+    // Última entrada del WAL cuyo efecto ya está guardado en todos los archivos
+    #[serde(default)]
+    pub wal_lsn: u64,
+    // Escrituras de la API que una compactación o un reindexado ya mezclaron con la
+    // tabla. Una reconstrucción desde el dataset no puede separarlas y las pierde.
+    #[serde(default)]
+    pub compacted_writes: u64,
+}
+
+impl DataStats {
+    pub fn load(dir_path: &Path) -> Self {
+        File::open(dir_path.join(STATS_FILE))
+            .ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, dir_path: &Path) -> Result<(), Box<dyn Error>> {
+        replace_file(&dir_path.join(STATS_FILE), &serde_json::to_vec(self)?)
+    }
+}
+
+// Una versión inmutable del índice. Escribir no la modifica: se clona, se aplica el
+// cambio sobre la copia y se publica la copia, así los lectores nunca esperan y una
+// consulta en curso termina sobre la versión con la que empezó. Clonar es barato, las
+// partes grandes están mapeadas y compartidas con Arc; solo se copian el overlay y los
+// deltas, que la compactación vacía.
+#[derive(Clone)]
 pub struct DiskHashTable {
-    table_path: PathBuf,
+    dir_path: PathBuf,
     data_path: PathBuf,
-    //De esta forma no almacenamos la tabla en la memoria permanentemente.
+    // La tabla y los datos se mapean una sola vez, el sistema operativo decide qué
+    // páginas mantener en RAM. Los datos son un log al que solo se le agregan registros.
+    table: Arc<HashTable<TripHashConfig, MappedBytes>>,
+    data_file: Arc<File>,
+    data: Arc<Mmap>,
+    // Registros agregados después del último mapeo. Se mapea de nuevo una vez por lote,
+    // en sync(), y no en cada escritura.
+    tail: Vec<u8>,
+    // Clave -> posición de los registros escritos después de construir la tabla
+    overlay: HashMap<String, u64>,
+    stats: DataStats,
+    locations: LocationIndex,
+    ranges: RangeIndex,
+    bitmaps: BitmapIndex,
+    columns: Arc<ColumnStore>,
 }
 
 impl DiskHashTable {
@@ -61,111 +299,1367 @@ impl DiskHashTable {
         let data_path = dir_path.join("trip_data.bin");
 
         if !table_path.exists() {
-            let builder = HashTableOwned::<TripHashConfig>::with_capacity(4, 90);
-            let data = builder.raw_bytes();
-
-            let mut file = File::create(&table_path)?;
-            file.write_all(data)?;
+            let builder = HashTableOwned::<TripHashConfig>::with_capacity(4, LOAD_FACTOR_PERCENT);
+            replace_file(&table_path, builder.raw_bytes())?;
         }
 
         if !data_path.exists() {
             File::create_new(&data_path)?;
         }
 
+        let table = open_table(&table_path)?;
+        let data_file = OpenOptions::new().append(true).open(&data_path)?;
+        let data = map_file(&data_path)?;
+        let stats = DataStats::load(dir_path);
+
+        // Los registros posteriores a la tabla se recorren en orden, la última versión
+        // de cada clave es la que queda
+        let mut overlay = HashMap::new();
+        let mut position = stats.indexed_len;
+        while position < data.len() as u64 {
+            let (key, _) = decode_record(&data, position)?;
+            overlay.insert(key.to_string(), position);
+            position += record_len(&data, position)?;
+        }
+
+        // Las columnas y los bitmaps numeran las mismas filas
+        let bitmaps = BitmapIndex::open(dir_path)?;
+        let columns = ColumnStore::open(dir_path)?;
+        if columns.rows() != bitmaps.base_len() as u64 {
+            return Err(format!(
+                "El almacén columnar tiene {} filas y los bitmaps {}",
+                columns.rows(),
+                bitmaps.base_len()
+            )
+            .into());
+        }
+
         Ok(Self {
-            table_path,
+            dir_path: dir_path.to_path_buf(),
             data_path,
+            table: Arc::new(table),
+            data_file: Arc::new(data_file),
+            data: Arc::new(data),
+            tail: Vec::new(),
+            overlay,
+            stats,
+            locations: LocationIndex::open(dir_path)?,
+            ranges: RangeIndex::open(dir_path)?,
+            bitmaps,
+            columns: Arc::new(columns),
         })
     }
 
-    pub fn insert(&self, key: String, trip: Trip) -> Result<(), Box<dyn Error>> {
-        let key_hash = calculate_hash(&key);
-        let mut data_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(&self.data_path)?;
-        let position = data_file.metadata()?.len() as u32;
-        let trip_bytes = serde_json::to_vec(&trip)?;
-        let data_size = trip_bytes.len() as u32;
-        data_file.write_all(&data_size.to_le_bytes())?;
-        data_file.write_all(&trip_bytes)?;
-        let mut table_file = OpenOptions::new().read(true).open(&self.table_path)?;
-        let mut table_data = Vec::new();
-        table_file.read_to_end(&mut table_data)?;
-        let mut builder = HashTableOwned::<TripHashConfig>::from_raw_bytes(&table_data)?;
-        builder.insert(&key_hash, &position);
-        let mut table_file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(&self.table_path)?;
-
-        table_file.write_all(builder.raw_bytes())?;
-
+    // Inserta o reemplaza. Para cargas completas usar DiskHashTableBuilder
+    pub fn insert(&mut self, key: String, trip: Trip) -> Result<(), Box<dyn Error>> {
+        self.write_record(&key, Some(&trip))?;
         Ok(())
     }
 
+    // Deja una lápida para la clave, false si no existía
+    pub fn delete(&mut self, key: &str) -> Result<bool, Box<dyn Error>> {
+        self.write_record(key, None)
+    }
+
     pub fn get(&self, key: &str) -> Result<Option<Trip>, Box<dyn Error>> {
-        let key_hash = calculate_hash(key);
-        let mut table_file = File::open(&self.table_path)?;
-        let mut table_data = Vec::new();
-        table_file.read_to_end(&mut table_data)?;
-        let table = HashTable::<TripHashConfig, &[u8]>::from_raw_bytes(&table_data)?;
-        if let Some(position) = table.get(&key_hash) {
-            let mut data_file = File::open(&self.data_path)?;
-            data_file.seek(SeekFrom::Start(position as u64))?;
-            let mut size_bytes = [0u8; 4];
-            data_file.read_exact(&mut size_bytes)?;
-            let data_size = u32::from_le_bytes(size_bytes) as usize;
-            let mut trip_bytes = vec![0u8; data_size];
-            data_file.read_exact(&mut trip_bytes)?;
-            let trip = serde_json::from_slice(&trip_bytes)?;
-            Ok(Some(trip))
+        match self.position_of(key)? {
+            Some(position) => self.read_at(position),
+            None => Ok(None),
+        }
+    }
+
+    // Si la clave tiene entrada (viva o lápida). Falla si el registro al que apunta está
+    // dañado.
+    pub fn contains_key(&self, key: &str) -> Result<bool, Box<dyn Error>> {
+        match self.position_of(key)? {
+            Some(position) => {
+                let (data, offset) = self.log_at(position);
+                decode_record(data, offset)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Posición de la versión más reciente de la clave (viva o lápida). El overlay tapa
+    // a la tabla, que puede seguir apuntando a una versión anterior.
+    pub fn position_of(&self, key: &str) -> Result<Option<u64>, Box<dyn Error>> {
+        if let Some(&position) = self.overlay.get(key) {
+            return Ok(Some(position));
+        }
+        let slot = find_slot(
+            key,
+            |hash| self.table.get(&hash),
+            |pos| Ok(decode_record(&self.data, pos)?.0 == key),
+        )?;
+        match slot {
+            Slot::Taken(_, position) => Ok(Some(position)),
+            Slot::Free(_) => Ok(None),
+        }
+    }
+
+    // Bytes del log donde está una posición y el desplazamiento dentro de ellos; lo
+    // escrito después del último mapeo se lee de `tail`
+    fn log_at(&self, position: u64) -> (&[u8], u64) {
+        let mapped = self.data.len() as u64;
+        if position >= mapped {
+            (&self.tail, position - mapped)
         } else {
-            Ok(None)
+            (&self.data, position)
+        }
+    }
+
+    // Largo del log contando lo que todavía no se mapeó
+    fn log_len(&self) -> u64 {
+        (self.data.len() + self.tail.len()) as u64
+    }
+
+    fn remap(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.tail.is_empty() {
+            self.data = Arc::new(map_file(&self.data_path)?);
+            self.tail.clear();
         }
+        Ok(())
+    }
+
+    // Viaje guardado en una posición de trip_data.bin, None si es una lápida
+    pub fn read_at(&self, position: u64) -> Result<Option<Trip>, Box<dyn Error>> {
+        let (data, offset) = self.log_at(position);
+        let (_, trip_bytes) = decode_record(data, offset)?;
+        if trip_bytes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(decode_trip(trip_bytes)?))
+    }
+
+    // Total de viajes con ese valor de ubicación y la página pedida, leyendo solo los
+    // registros de la página
+    pub fn find_by_location(
+        &self,
+        column: LocationColumn,
+        value: u32,
+        skip: usize,
+        take: usize,
+    ) -> Result<(usize, Vec<Trip>), Box<dyn Error>> {
+        let mut trips = Vec::with_capacity(take);
+        for position in self
+            .locations
+            .positions(column, value)
+            .skip(skip)
+            .take(take)
+        {
+            trips.extend(self.read_at(position)?);
+        }
+        Ok((self.locations.count(column, value), trips))
+    }
+
+    // Lo mismo para un rango de valores o fechas, en orden ascendente
+    pub fn find_by_range(
+        &self,
+        column: RangeColumn,
+        range: &KeyRange,
+        skip: usize,
+        take: usize,
+    ) -> Result<(usize, Vec<Trip>), Box<dyn Error>> {
+        let mut trips = Vec::with_capacity(take);
+        for position in self.ranges.positions(column, range, skip, take) {
+            trips.extend(self.read_at(position)?);
+        }
+        Ok((self.ranges.count(column, range), trips))
+    }
+
+    pub fn bitmaps(&self) -> &BitmapIndex {
+        &self.bitmaps
     }
 
+    pub fn locations(&self) -> &LocationIndex {
+        &self.locations
+    }
+
+    pub fn ranges(&self) -> &RangeIndex {
+        &self.ranges
+    }
+
+    // Página de un conjunto de filas ya resuelto con los bitmaps, en orden de fila
+    pub fn find_by_rows(
+        &self,
+        rows: &RoaringBitmap,
+        skip: usize,
+        take: usize,
+    ) -> Result<Vec<Trip>, Box<dyn Error>> {
+        let mut trips = Vec::with_capacity(take);
+        let Ok(first) = u32::try_from(skip) else {
+            return Ok(trips);
+        };
+        for row in (first..).take(take).map_while(|n| rows.select(n)) {
+            let position = self
+                .bitmaps
+                .position(row)
+                .ok_or("Fila fuera del índice de bitmaps")?;
+            trips.extend(self.read_at(position)?);
+        }
+        Ok(trips)
+    }
+
+    // Recorre los viajes vivos leyendo del almacén columnar solo las columnas de
+    // `fields`, sin abrir los grupos de filas que `keep` descarta. Lo escrito después de
+    // construir se lee completo del log, al final. El callback recibe la posición del
+    // registro para poder leerlo entero después.
+    pub fn scan_columns(
+        &self,
+        fields: &[&str],
+        keep: impl FnMut(&RowGroup) -> bool,
+        mut process: impl FnMut(u64, &Trip) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let skipped = self.columns.scan(fields, keep, |row, trip| {
+            if !self.bitmaps.is_live(row) {
+                return Ok(());
+            }
+            let position = self
+                .bitmaps
+                .position(row)
+                .ok_or("Fila fuera del índice de bitmaps")?;
+            process(position, trip)
+        })?;
+        log::debug!("Escaneo columnar: {} grupos de filas descartados", skipped);
+
+        for position in self.bitmaps.appended_positions() {
+            if let Some(trip) = self.read_at(position)? {
+                process(position, &trip)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Agrega la nueva versión de la clave (o su lápida) al final del log y la apunta
+    // desde el overlay. Los datos se sincronizan antes, así el overlay nunca apunta a
+    // bytes que no llegaron al disco. Devuelve si la clave existía.
+    fn write_record(&mut self, key: &str, trip: Option<&Trip>) -> Result<bool, Box<dyn Error>> {
+        // La versión viva anterior hace falta para sacarla del índice secundario
+        let previous = match self.position_of(key)? {
+            Some(position) => match self.read_at(position)? {
+                Some(old) => {
+                    let (data, offset) = self.log_at(position);
+                    Some((position, old, record_len(data, offset)?))
+                }
+                None => None,
+            },
+            None => None,
+        };
+        // Borrar una clave que no existe no deja lápida
+        if trip.is_none() && previous.is_none() {
+            return Ok(false);
+        }
+
+        let position = self.log_len();
+        let record = encode_record(key, trip)?;
+        (&*self.data_file).write_all(&record)?;
+        self.tail.extend_from_slice(&record);
+        if self.tail.len() >= TAIL_REMAP_BYTES {
+            self.remap()?;
+        }
+        self.overlay.insert(key.to_string(), position);
+
+        self.stats.dead_bytes += previous.as_ref().map_or(0, |(_, _, len)| *len);
+        if trip.is_none() {
+            self.stats.dead_bytes += record.len() as u64;
+            self.stats.tombstones += 1;
+        }
+        let old = previous.as_ref().map(|(position, old, _)| (*position, old));
+        let new = trip.map(|trip| (position, trip));
+        self.locations.record_change(old, new);
+        self.ranges.record_change(old, new);
+        self.bitmaps.record_change(old, new)?;
+
+        Ok(previous.is_some())
+    }
+
+    // Las escrituras solo tocan memoria y el final de trip_data.bin; esto guarda todo
+    // lo escrito desde el último sync y mapea lo agregado al log, así la versión que se
+    // publica lee todo del mapeo. Entre medio el WAL es lo que las hace durables.
+    pub fn sync(&mut self) -> Result<(), Box<dyn Error>> {
+        self.data_file.sync_data()?;
+        self.remap()?;
+        self.locations.save_delta()?;
+        self.ranges.save_delta()?;
+        self.bitmaps.save_delta()?;
+        self.stats.save(&self.dir_path)
+    }
+
+    pub fn wal_lsn(&self) -> u64 {
+        self.stats.wal_lsn
+    }
+
+    pub fn set_wal_lsn(&mut self, lsn: u64) {
+        self.stats.wal_lsn = lsn;
+    }
+
+    // Diferencias entre los índices secundarios y los registros vivos, vacío si cuadran.
+    // La distancia no se compara porque los valores no finitos quedan fuera de su índice.
+    pub fn check_secondary(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut live = 0;
+        for (_, position) in self.table.iter() {
+            let (key, trip_bytes) = decode_record(&self.data, position)?;
+            if !self.overlay.contains_key(key) && !trip_bytes.is_empty() {
+                live += 1;
+            }
+        }
+        for &position in self.overlay.values() {
+            if !decode_record(&self.data, position)?.1.is_empty() {
+                live += 1;
+            }
+        }
+
+        let mut counts = vec![("bitmaps".to_string(), self.bitmaps.live().len() as usize)];
+        for column in LocationColumn::ALL {
+            counts.push((column.file_name().to_string(), self.locations.total(column)));
+        }
+        for column in RangeColumn::ALL {
+            if column != RangeColumn::TripDistance {
+                let count = self.ranges.count(column, &KeyRange::default());
+                counts.push((column.file_name().to_string(), count));
+            }
+        }
+        Ok(counts
+            .into_iter()
+            .filter(|(_, count)| *count != live)
+            .map(|(name, count)| {
+                format!(
+                    "{} tiene {} registros vivos y la tabla {}",
+                    name, count, live
+                )
+            })
+            .collect())
+    }
+
+    // Compactar vale la pena cuando al menos la mitad del archivo es basura, o cuando
+    // el overlay creció tanto que copiarlo en cada lote pesa más que reconstruir
+    pub fn needs_compaction(&self) -> bool {
+        (self.stats.dead_bytes >= COMPACTION_MIN_DEAD_BYTES
+            && self.stats.dead_bytes * 2 >= self.log_len())
+            || self.overlay.len() >= COMPACTION_MAX_OVERLAY_KEYS
+    }
+
+    // Primera fase de la compactación, sin bloquear a nadie: copia los registros vivos
+    // de esta versión al directorio de staging, en el orden en que estaban en el archivo.
+    pub fn copy_live(&self, staging_dir: &Path) -> Result<DiskHashTableBuilder, Box<dyn Error>> {
+        let mut positions = Vec::with_capacity(self.table.len() + self.overlay.len());
+        for (_, position) in self.table.iter() {
+            let (key, _) = decode_record(&self.data, position)?;
+            if !self.overlay.contains_key(key) {
+                positions.push(position);
+            }
+        }
+        positions.extend(self.overlay.values());
+        positions.sort_unstable();
+
+        let mut builder = DiskHashTableBuilder::create(staging_dir, positions.len())?;
+        builder.stats.compacted_writes = self.stats.compacted_writes + self.overlay.len() as u64;
+        for position in positions {
+            let (key, trip_bytes) = decode_record(&self.data, position)?;
+            if !trip_bytes.is_empty() {
+                builder.insert(key, &decode_trip(trip_bytes)?)?;
+            }
+        }
+        Ok(builder)
+    }
+
+    // Segunda fase, con los escritores detenidos: aplica en orden lo que se escribió
+    // después de `snapshot`, cambia el directorio y abre la versión compactada. Devuelve
+    // esa versión y los bytes recuperados.
+    pub fn finish_compaction(
+        &self,
+        mut builder: DiskHashTableBuilder,
+        snapshot: &DiskHashTable,
+    ) -> Result<(DiskHashTable, u64), Box<dyn Error>> {
+        let before = self.data.len() as u64;
+        let mut position = snapshot.data.len() as u64;
+        while position < before {
+            let (key, trip_bytes) = decode_record(&self.data, position)?;
+            if trip_bytes.is_empty() {
+                builder.delete(key)?;
+            } else {
+                builder.insert(key, &decode_trip(trip_bytes)?)?;
+            }
+            builder.stats.compacted_writes += 1;
+            position += record_len(&self.data, position)?;
+        }
+
+        let staging_dir = builder.dir_path.clone();
+        builder.finish()?;
+        // El manifiesto marca el staging como completo, va al final
+        if let Some(manifest) = IndexManifest::load(&self.dir_path)? {
+            manifest.save(&staging_dir)?;
+        }
+        swap_index_dir(&staging_dir, &self.dir_path)?;
+        let compacted = DiskHashTable::new(&self.dir_path)?;
+        let freed = before.saturating_sub(compacted.data.len() as u64);
+
+        Ok((compacted, freed))
+    }
+
+    #[allow(dead_code)]
     pub fn count_entries(&self) -> Result<usize, Box<dyn Error>> {
-        let table_file = File::open(&self.table_path)?;
-        let mut reader = BufReader::new(table_file);
-        let mut table_data = Vec::new();
-        reader.read_to_end(&mut table_data)?;
-        let table = HashTable::<TripHashConfig, &[u8]>::from_raw_bytes(&table_data)?;
-        Ok(table.len())
+        Ok(self.table.len())
     }
 
+    // La construcción ocurre en un directorio de staging junto a hash_dir y solo al
+    // terminar se cambia por el índice activo. Si el proceso muere a medias, la siguiente
+    // llamada continúa desde el último checkpoint del staging.
     pub fn build_hash_table_from_csv<P: AsRef<Path>>(
         csv_path: P,
         hash_dir: P,
     ) -> Result<usize, Box<dyn Error>> {
-        let hash_table = DiskHashTable::new(&hash_dir)?;
-        let data_path = hash_dir.as_ref().join("trip_data.bin");
-        OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(&data_path)?;
-        let builder = HashTableOwned::<TripHashConfig>::with_capacity(16, 90);
-        let table_path = hash_dir.as_ref().join("hash_table.bin");
-        let mut table_file = File::create(&table_path)?;
-        table_file.write_all(builder.raw_bytes())?;
-        let mut count = 0;
-        super::data_lector::stream_process_csv(csv_path, |trip| {
-            let key = trip.index.clone();
-            hash_table.insert(key, trip.clone())?;
-
-            count += 1;
-            if count % 1000 == 0 {
-                println!("Procesados {} registros...", count);
+        let hash_dir = hash_dir.as_ref();
+        let staging_dir = staging_dir(hash_dir);
+
+        // Las particiones ya tienen su conteo de filas, sirve para dimensionar la tabla
+        let dataset = Dataset::open(&csv_path)?;
+        let expected_rows: u64 = dataset.partitions()?.iter().map(|p| p.rows).sum();
+        // La huella se toma antes de leer, si el archivo cambia durante la carga el
+        // próximo arranque lo detecta
+        let sources = dataset
+            .files()
+            .iter()
+            .map(|f| SourceFingerprint::compute(f))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let checkpoint = BuildCheckpoint::load(&staging_dir).unwrap_or_else(|e| {
+            eprintln!("Checkpoint ilegible, se descarta: {}", e);
+            None
+        });
+        // Un checkpoint que no se puede reanudar (faltan sus archivos o están dañados)
+        // no debe impedir arrancar: se descarta y se construye desde cero
+        let resumed = match checkpoint {
+            Some(checkpoint) if checkpoint.can_resume(&sources) => {
+                println!(
+                    "Reanudando construcción desde el checkpoint {}: {} registros, archivo {} fila {}",
+                    checkpoint.sequence,
+                    checkpoint.rows_ingested,
+                    checkpoint.position.file,
+                    checkpoint.position.within.rows
+                );
+                match DiskHashTableBuilder::resume(&staging_dir, &checkpoint) {
+                    Ok(builder) => Some((builder, checkpoint.position)),
+                    Err(e) => {
+                        eprintln!(
+                            "No se pudo reanudar el checkpoint, se empieza de cero: {}",
+                            e
+                        );
+                        None
+                    }
+                }
+            }
+            Some(_) => {
+                println!("El dataset cambió desde el último checkpoint, se empieza de cero");
+                None
+            }
+            None => None,
+        };
+        let (mut builder, start) = match resumed {
+            Some(resumed) => resumed,
+            None => {
+                if staging_dir.exists() {
+                    fs::remove_dir_all(&staging_dir)?;
+                }
+                let builder = DiskHashTableBuilder::create(&staging_dir, expected_rows as usize)?;
+                (builder, DatasetPosition::default())
+            }
+        };
+
+        // Unos diez checkpoints por carga, cada uno copia la tabla completa a disco
+        let interval = (expected_rows / 10).max(CHECKPOINT_MIN_ROWS);
+        let mut since_checkpoint = 0;
+        let wanted = [true; FIELD_NAMES.len()];
+        dataset.stream_from(&start, &wanted, |trip, position| {
+            builder.insert(&trip.index.to_string(), trip)?;
+
+            if builder.rows % 100_000 == 0 {
+                println!("Procesados {} registros...", builder.rows);
+            }
+            since_checkpoint += 1;
+            if since_checkpoint >= interval {
+                builder.checkpoint(position, &sources)?;
+                since_checkpoint = 0;
             }
 
             Ok(())
         })?;
 
+        let count = builder.rows as usize;
+        let carried = carry_writes(hash_dir, &mut builder)?;
+        if carried > 0 {
+            println!("{} escrituras del índice anterior conservadas", carried);
+        }
+        builder.finish()?;
+        IndexManifest::new(sources, count as u64).save(&staging_dir)?;
+        swap_index_dir(&staging_dir, hash_dir)?;
         println!("Total de registros procesados: {}", count);
 
         Ok(count)
     }
 }
+
+// Carga masiva: la tabla vive en memoria y el archivo de datos se escribe con un solo
+// BufWriter, así que cada fila cuesta una inserción en memoria y una escritura
+// amortizada. La tabla se escribe a disco en finish() y en cada checkpoint.
+pub struct DiskHashTableBuilder {
+    dir_path: PathBuf,
+    table_path: PathBuf,
+    data_path: PathBuf,
+    data_tmp_path: PathBuf,
+    table: HashTableOwned<TripHashConfig>,
+    data: BufWriter<File>,
+    position: u64,
+    rows: u64,
+    sequence: u64,
+    stats: DataStats,
+}
+
+impl DiskHashTableBuilder {
+    pub fn create<P: AsRef<Path>>(
+        dir_path: P,
+        expected_rows: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        let dir_path = dir_path.as_ref();
+        create_dir_all(dir_path)?;
+
+        // Se escribe a un temporal que se renombra en finish(), los lectores que tengan
+        // mapeado el índice anterior no se ven afectados
+        let data_path = dir_path.join("trip_data.bin");
+        let data_tmp_path = data_path.with_extension("bin.tmp");
+        let data_file = File::create(&data_tmp_path)?;
+
+        Ok(Self {
+            dir_path: dir_path.to_path_buf(),
+            table_path: dir_path.join("hash_table.bin"),
+            data_path,
+            data_tmp_path,
+            table: HashTableOwned::<TripHashConfig>::with_capacity(
+                expected_rows.max(16),
+                LOAD_FACTOR_PERCENT,
+            ),
+            data: BufWriter::with_capacity(config::get().write_buffer_bytes, data_file),
+            position: 0,
+            rows: 0,
+            sequence: 0,
+            stats: DataStats::default(),
+        })
+    }
+
+    // Vuelve al estado de un checkpoint: la tabla guardada y los datos recortados al
+    // largo que tenían en ese momento.
+    pub fn resume<P: AsRef<Path>>(
+        dir_path: P,
+        checkpoint: &BuildCheckpoint,
+    ) -> Result<Self, Box<dyn Error>> {
+        let dir_path = dir_path.as_ref();
+        let data_path = dir_path.join("trip_data.bin");
+        let data_tmp_path = data_path.with_extension("bin.tmp");
+
+        // Primero los datos: si faltan no hace falta leer la copia de la tabla
+        let mut data_file = OpenOptions::new().write(true).open(&data_tmp_path)?;
+        if data_file.metadata()?.len() < checkpoint.data_len {
+            return Err("El archivo de datos es más corto que el checkpoint".into());
+        }
+        let table_bytes =
+            fs::read(dir_path.join(BuildCheckpoint::table_file(checkpoint.sequence)))?;
+        let table = HashTableOwned::<TripHashConfig>::from_raw_bytes(&table_bytes)?;
+
+        data_file.set_len(checkpoint.data_len)?;
+        data_file.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            dir_path: dir_path.to_path_buf(),
+            table_path: dir_path.join("hash_table.bin"),
+            data_path,
+            data_tmp_path,
+            table,
+            data: BufWriter::with_capacity(config::get().write_buffer_bytes, data_file),
+            position: checkpoint.data_len,
+            rows: checkpoint.rows_ingested,
+            sequence: checkpoint.sequence,
+            stats: checkpoint.stats.clone(),
+        })
+    }
+
+    // Una clave repetida reemplaza a la anterior, como en la inserción individual
+    pub fn insert(&mut self, key: &str, trip: &Trip) -> Result<(), Box<dyn Error>> {
+        self.write_record(key, Some(trip))?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    // Lápida para una clave ya cargada, solo la usa la compactación
+    pub fn delete(&mut self, key: &str) -> Result<(), Box<dyn Error>> {
+        self.write_record(key, None)
+    }
+
+    fn write_record(&mut self, key: &str, trip: Option<&Trip>) -> Result<(), Box<dyn Error>> {
+        let table = &self.table;
+        let data = &mut self.data;
+        let data_tmp_path = &self.data_tmp_path;
+        let slot = find_slot(
+            key,
+            |hash| table.get(&hash),
+            |pos| Ok(read_header(data, data_tmp_path, pos)?.1 == key),
+        )?;
+        let (hash, previous_len) = match slot {
+            Slot::Taken(hash, position) => {
+                let (size, _) = read_header(&mut self.data, &self.data_tmp_path, position)?;
+                // Un tamaño de solo clave es una lápida, ya contada como basura
+                let live = size as usize > CHECKSUM_LEN + 2 + key.len();
+                (hash, live.then_some(4 + size as u64))
+            }
+            Slot::Free(hash) => (hash, None),
+        };
+        if trip.is_none() && previous_len.is_none() {
+            return Ok(());
+        }
+
+        let record = encode_record(key, trip)?;
+        self.data.write_all(&record)?;
+        self.table.insert(&hash, &self.position);
+        self.position += record.len() as u64;
+
+        self.stats.dead_bytes += previous_len.unwrap_or(0);
+        if trip.is_none() {
+            self.stats.dead_bytes += record.len() as u64;
+            self.stats.tombstones += 1;
+        }
+
+        Ok(())
+    }
+
+    // Orden de escritura: datos sincronizados, copia de la tabla y por último el
+    // checkpoint que apunta a ambos. Morir en cualquier punto deja válido el anterior.
+    pub fn checkpoint(
+        &mut self,
+        position: &DatasetPosition,
+        sources: &[SourceFingerprint],
+    ) -> Result<(), Box<dyn Error>> {
+        self.data.flush()?;
+        self.data.get_ref().sync_all()?;
+
+        let sequence = self.sequence + 1;
+        replace_file(
+            &self.dir_path.join(BuildCheckpoint::table_file(sequence)),
+            self.table.raw_bytes(),
+        )?;
+        BuildCheckpoint::new(
+            sequence,
+            self.rows,
+            self.position,
+            self.stats.clone(),
+            *position,
+            sources.to_vec(),
+        )
+        .save(&self.dir_path)?;
+
+        if self.sequence > 0 {
+            fs::remove_file(
+                self.dir_path
+                    .join(BuildCheckpoint::table_file(self.sequence)),
+            )?;
+        }
+        self.sequence = sequence;
+        log::debug!("Checkpoint {} con {} registros", sequence, self.rows);
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<usize, Box<dyn Error>> {
+        self.data.flush()?;
+        self.data.get_ref().sync_all()?;
+        // El checkpoint apunta a trip_data.bin.tmp, tiene que desaparecer antes del
+        // rename. Morir de aquí en adelante deja un staging sin checkpoint, que el
+        // próximo arranque descarta y vuelve a construir.
+        BuildCheckpoint::remove(&self.dir_path)?;
+        fs::rename(&self.data_tmp_path, &self.data_path)?;
+        replace_file(&self.table_path, self.table.raw_bytes())?;
+
+        let data = map_file(&self.data_path)?;
+        let positions = self.table.iter().map(|(_, position)| position).collect();
+        write_secondary_indexes(&self.dir_path, &data, positions)?;
+
+        self.stats.indexed_len = self.position;
+        self.stats.save(&self.dir_path)?;
+
+        Ok(self.table.len())
+    }
+}
+
+// Escrituras de la API en el índice anterior que una reconstrucción tiene que
+// conservar: los registros posteriores a su tabla y lo que quedó pendiente en su WAL.
+// Se aplican sobre lo cargado del dataset en el orden en que se hicieron. Lo que no se
+// puede recuperar se avisa con la cantidad perdida. Devuelve cuántas se aplicaron.
+fn carry_writes(
+    hash_dir: &Path,
+    builder: &mut DiskHashTableBuilder,
+) -> Result<usize, Box<dyn Error>> {
+    if !hash_dir.join("trip_data.bin").exists() {
+        return Ok(0);
+    }
+    let collected = DiskHashTable::new(hash_dir).and_then(|old| {
+        let mut positions: Vec<u64> = old.overlay.values().copied().collect();
+        positions.sort_unstable();
+        let mut writes = Vec::with_capacity(positions.len());
+        for position in positions {
+            let (key, trip_bytes) = decode_record(&old.data, position)?;
+            let trip = (!trip_bytes.is_empty())
+                .then(|| decode_trip(trip_bytes))
+                .transpose()?;
+            writes.push((key.to_string(), trip));
+        }
+        for entry in wal::pending(hash_dir)? {
+            writes.push((entry.key, entry.trip));
+        }
+        Ok((writes, old.stats.compacted_writes))
+    });
+    let (writes, compacted) = match collected {
+        Ok(collected) => collected,
+        Err(e) => {
+            eprintln!(
+                "ATENCIÓN: no se pudo leer el índice anterior, sus escrituras de la API se pierden: {}",
+                e
+            );
+            return Ok(0);
+        }
+    };
+    if compacted > 0 {
+        eprintln!(
+            "ATENCIÓN: {} escrituras de la API ya estaban mezcladas con la tabla del índice anterior y se pierden con la reconstrucción",
+            compacted
+        );
+    }
+
+    for (key, trip) in &writes {
+        match trip {
+            Some(trip) => builder.insert(key, trip)?,
+            None => builder.delete(key)?,
+        }
+    }
+    Ok(writes.len())
+}
+
+// Los índices secundarios se arman con los registros vivos finales, así las claves
+// repetidas y las lápidas ya están resueltas. Se recorren en orden de posición.
+fn write_secondary_indexes(
+    dir_path: &Path,
+    data: &[u8],
+    mut positions: Vec<u64>,
+) -> Result<(), Box<dyn Error>> {
+    positions.sort_unstable();
+    let mut postings = PostingsBuilder::default();
+    let mut ranges = RangeBuilder::default();
+    let mut bitmaps = BitmapBuilder::default();
+    let mut columns = ColumnBuilder::create(dir_path)?;
+    for position in positions {
+        let (_, trip_bytes) = decode_record(data, position)?;
+        if !trip_bytes.is_empty() {
+            let trip = decode_trip(trip_bytes)?;
+            postings.add(position, &trip);
+            ranges.add(position, &trip);
+            bitmaps.add(position, &trip)?;
+            columns.add(trip_bytes, &trip)?;
+        }
+    }
+    postings.write(dir_path)?;
+    ranges.write(dir_path)?;
+    bitmaps.write(dir_path)?;
+    columns.finish()
+}
+
+pub fn staging_dir(hash_dir: &Path) -> PathBuf {
+    sibling_dir(hash_dir, "staging")
+}
+
+// tmp/hash_index -> tmp/hash_index.staging, tmp/hash_index.old
+fn sibling_dir(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    dir.with_file_name(name)
+}
+
+fn sync_dir(dir: &Path) -> Result<(), Box<dyn Error>> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// Cambia el índice activo por el recién construido. Entre los dos rename no existe
+// hash_dir; recover_index_dir completa o deshace el cambio si el proceso muere ahí.
+fn swap_index_dir(staging_dir: &Path, hash_dir: &Path) -> Result<(), Box<dyn Error>> {
+    sync_dir(staging_dir)?;
+    let old_dir = sibling_dir(hash_dir, "old");
+    if old_dir.exists() {
+        fs::remove_dir_all(&old_dir)?;
+    }
+    if hash_dir.exists() {
+        fs::rename(hash_dir, &old_dir)?;
+    }
+    fs::rename(staging_dir, hash_dir)?;
+    if let Some(parent) = hash_dir.parent() {
+        sync_dir(parent)?;
+    }
+    if old_dir.exists() {
+        fs::remove_dir_all(&old_dir)?;
+    }
+    Ok(())
+}
+
+// Se llama al arrancar, antes de revisar el manifiesto
+pub fn recover_index_dir(hash_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let old_dir = sibling_dir(hash_dir, "old");
+    if !hash_dir.exists() {
+        let staging_dir = staging_dir(hash_dir);
+        // Un staging con manifiesto ya terminó, solo faltaba el rename
+        if IndexManifest::load(&staging_dir).is_ok_and(|m| m.is_some()) {
+            println!("Completando el cambio de índice interrumpido");
+            fs::rename(&staging_dir, hash_dir)?;
+        } else if old_dir.exists() {
+            println!("Restaurando el índice anterior tras un cambio interrumpido");
+            fs::rename(&old_dir, hash_dir)?;
+        }
+    }
+    if old_dir.exists() {
+        fs::remove_dir_all(&old_dir)?;
+    }
+    Ok(())
+}
+
+// Solo se usa cuando el hash ya está ocupado (clave repetida o colisión real), por eso
+// puede permitirse vaciar el buffer y leer del archivo temporal. Devuelve el tamaño
+// del registro y su clave.
+fn read_header(
+    data: &mut BufWriter<File>,
+    data_tmp_path: &Path,
+    position: u64,
+) -> Result<(u32, String), Box<dyn Error>> {
+    data.flush()?;
+    let mut file = File::open(data_tmp_path)?;
+    file.seek(SeekFrom::Start(position))?;
+    let mut size = [0u8; 4];
+    file.read_exact(&mut size)?;
+    file.seek(SeekFrom::Current(CHECKSUM_LEN as i64))?;
+    let mut key_len = [0u8; 2];
+    file.read_exact(&mut key_len)?;
+    let mut key = vec![0u8; u16::from_le_bytes(key_len) as usize];
+    file.read_exact(&mut key)?;
+    Ok((u32::from_le_bytes(size), String::from_utf8(key)?))
+}
+
+// Resultado de recorrer trip_data.bin registro por registro, lo usa fsck
+#[derive(Debug, Default)]
+pub struct LogWalk {
+    pub records: u64,
+    pub tombstones: u64,
+    // Inicio de cada registro íntegro, en orden
+    pub valid: Vec<u64>,
+    // Checksum inválido o un trip que no decodifica. El tamaño se pudo leer, así que
+    // el recorrido sigue con el registro siguiente.
+    pub corrupt: Vec<u64>,
+    // Desde aquí el archivo no se puede recorrer: tamaño imposible o registro cortado
+    pub unreadable_from: Option<u64>,
+}
+
+pub fn walk_log(data: &[u8]) -> LogWalk {
+    let mut walk = LogWalk::default();
+    let mut position = 0;
+    while position < data.len() as u64 {
+        let len = match record_len(data, position) {
+            Ok(len)
+                if len >= (4 + CHECKSUM_LEN + 2) as u64 && position + len <= data.len() as u64 =>
+            {
+                len
+            }
+            _ => {
+                walk.unreadable_from = Some(position);
+                break;
+            }
+        };
+        walk.records += 1;
+        match decode_record(data, position) {
+            Ok((_, [])) => {
+                walk.tombstones += 1;
+                walk.valid.push(position);
+            }
+            Ok((_, trip_bytes)) if decode_trip(trip_bytes).is_ok() => walk.valid.push(position),
+            _ => walk.corrupt.push(position),
+        }
+        position += len;
+    }
+    walk
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BadEntry {
+    pub hash: u64,
+    pub position: u64,
+    pub reason: String,
+}
+
+// Revisa que cada entrada de hash_table.bin apunte al inicio de un registro íntegro
+// cuya clave pase por ese hash al sondearla. Devuelve el total de entradas y las malas.
+pub fn check_table(
+    dir_path: &Path,
+    data: &[u8],
+    valid: &[u64],
+) -> Result<(u64, Vec<BadEntry>), Box<dyn Error>> {
+    let table = open_table(&dir_path.join("hash_table.bin"))?;
+    let mut bad = Vec::new();
+    for (hash, position) in table.iter() {
+        let reason = if valid.binary_search(&position).is_err() {
+            "no apunta al inicio de un registro íntegro".to_string()
+        } else {
+            let (key, _) = decode_record(data, position)?;
+            if (0..MAX_PROBES).any(|probe| calculate_hash(key, probe) == hash) {
+                continue;
+            }
+            format!("el registro es de la clave {}, que no usa este hash", key)
+        };
+        bad.push(BadEntry {
+            hash,
+            position,
+            reason,
+        });
+    }
+    Ok((table.len() as u64, bad))
+}
+
+// Reconstruye hash_table.bin y los índices secundarios con los registros íntegros de
+// trip_data.bin, sin reescribir los datos. Queda la última versión íntegra de cada
+// clave; los registros dañados se ignoran. Devuelve la cantidad de claves.
+pub fn reindex_from_log(dir_path: &Path) -> Result<usize, Box<dyn Error>> {
+    let data = map_file(&dir_path.join("trip_data.bin"))?;
+    let walk = walk_log(&data);
+    let mut latest = HashMap::with_capacity(walk.valid.len());
+    for &position in &walk.valid {
+        latest.insert(decode_record(&data, position)?.0, position);
+    }
+
+    let mut table =
+        HashTableOwned::<TripHashConfig>::with_capacity(latest.len().max(16), LOAD_FACTOR_PERCENT);
+    let mut live_bytes = 0;
+    for (key, &position) in &latest {
+        let slot = find_slot(
+            key,
+            |hash| table.get(&hash),
+            |pos| Ok(decode_record(&data, pos)?.0 == *key),
+        )?;
+        if let Slot::Free(hash) = slot {
+            table.insert(&hash, &position);
+        }
+        if !decode_record(&data, position)?.1.is_empty() {
+            live_bytes += record_len(&data, position)?;
+        }
+    }
+
+    // Lo nuevo se arma aparte y entra con swap_index_dir, así morir a medias deja el
+    // índice anterior entero en lugar de tabla, bitmaps y columnas de épocas distintas
+    let reindex_dir = sibling_dir(dir_path, "reindex");
+    if reindex_dir.exists() {
+        fs::remove_dir_all(&reindex_dir)?;
+    }
+    create_dir_all(&reindex_dir)?;
+    replace_file(&reindex_dir.join("hash_table.bin"), table.raw_bytes())?;
+    write_secondary_indexes(&reindex_dir, &data, latest.values().copied().collect())?;
+    let previous = DataStats::load(dir_path);
+    DataStats {
+        dead_bytes: data.len() as u64 - live_bytes,
+        tombstones: walk.tombstones,
+        indexed_len: data.len() as u64,
+        quarantined: walk.corrupt,
+        wal_lsn: previous.wal_lsn,
+        // Lo que era overlay queda dentro de la tabla nueva
+        compacted_writes: previous.compacted_writes
+            + walk
+                .valid
+                .iter()
+                .filter(|&&position| position >= previous.indexed_len)
+                .count() as u64,
+    }
+    .save(&reindex_dir)?;
+
+    // Los datos, el WAL y el manifiesto no cambian, pasan con un enlace
+    for file in ["trip_data.bin", WAL_FILE, MANIFEST_FILE] {
+        let path = dir_path.join(file);
+        if path.exists() {
+            fs::hard_link(&path, reindex_dir.join(file))?;
+        }
+    }
+    swap_index_dir(&reindex_dir, dir_path)?;
+
+    Ok(latest.len())
+}
+
+// Recorta trip_data.bin hasta `len`, descartando una escritura que quedó cortada.
+// Devuelve el largo que tenía.
+pub fn truncate_data(dir_path: &Path, len: u64) -> Result<u64, Box<dyn Error>> {
+    let file = OpenOptions::new()
+        .write(true)
+        .open(dir_path.join("trip_data.bin"))?;
+    let previous = file.metadata()?.len();
+    file.set_len(len)?;
+    file.sync_all()?;
+    Ok(previous)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("disk-hash-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Tres viajes con índices 1, 2 y 3
+    const SAMPLE_CSV: &str = "VendorID,tpep_pickup_datetime,tpep_dropoff_datetime,passenger_count,trip_distance,RatecodeID,store_and_fwd_flag,PULocationID,DOLocationID,payment_type,fare_amount,extra,mta_tax,tip_amount,tolls_amount,improvement_surcharge,total_amount,congestion_surcharge,Index\n\
+    2,2024-01-04 22:19:23,2024-01-04 22:30:40,2,9.95,1,N,44,38,2,21.5,0.5,0.5,2.0,0.0,0.3,24.8,2.5,1\n\
+    1,2024-01-05 19:00:02,2024-01-05 19:45:28,1,0.86,1,N,58,27,1,7.5,0.5,0.5,1.0,0.0,0.3,9.8,2.5,2\n\
+    2,2024-01-06 08:10:00,2024-01-06 08:20:00,1,1.20,1,N,12,13,1,8.0,0.5,0.5,1.0,0.0,0.3,10.3,2.5,3\n";
+
+    fn trip(index: u64, do_location_id: u32) -> Trip {
+        Trip {
+            index,
+            do_location_id,
+            ..Trip::default()
+        }
+    }
+
+    #[test]
+    fn writes_in_one_batch_read_back_before_remapping() {
+        let dir = test_dir("tail");
+        let mut table = DiskHashTable::new(&dir).unwrap();
+        table.insert("1".to_string(), trip(1, 10)).unwrap();
+        table.insert("1".to_string(), trip(1, 20)).unwrap();
+        table.insert("2".to_string(), trip(2, 30)).unwrap();
+        assert!(table.delete("2").unwrap());
+        // Nada de esto se mapeó todavía
+        assert_eq!(table.data.len(), 0);
+        assert_eq!(table.get("1").unwrap().map(|t| t.do_location_id), Some(20));
+        assert!(table.get("2").unwrap().is_none());
+
+        table.sync().unwrap();
+        assert!(table.tail.is_empty());
+        assert_eq!(table.data.len() as u64, table.log_len());
+        assert_eq!(table.get("1").unwrap().map(|t| t.do_location_id), Some(20));
+        assert_eq!(table.locations.count(LocationColumn::Dropoff, 10), 0);
+        assert_eq!(table.locations.count(LocationColumn::Dropoff, 20), 1);
+        assert_eq!(table.locations.count(LocationColumn::Dropoff, 30), 0);
+
+        let reopened = DiskHashTable::new(&dir).unwrap();
+        assert_eq!(
+            reopened.get("1").unwrap().map(|t| t.do_location_id),
+            Some(20)
+        );
+        assert!(reopened.get("2").unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_large_overlay_asks_for_compaction() {
+        let dir = test_dir("overlay");
+        let mut table = DiskHashTable::new(&dir).unwrap();
+        assert!(!table.needs_compaction());
+        table.overlay = (0..COMPACTION_MAX_OVERLAY_KEYS as u64)
+            .map(|key| (key.to_string(), 0))
+            .collect();
+        assert!(table.needs_compaction());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reindex_swaps_in_a_complete_directory() {
+        let dir = test_dir("reindex");
+        let mut table = DiskHashTable::new(&dir).unwrap();
+        table.insert("1".to_string(), trip(1, 10)).unwrap();
+        table.insert("2".to_string(), trip(2, 10)).unwrap();
+        table.sync().unwrap();
+        table.insert("2".to_string(), trip(2, 20)).unwrap();
+        table.sync().unwrap();
+        drop(table);
+
+        assert_eq!(reindex_from_log(&dir).unwrap(), 2);
+        assert!(!sibling_dir(&dir, "reindex").exists());
+        assert!(!sibling_dir(&dir, "old").exists());
+
+        let reopened = DiskHashTable::new(&dir).unwrap();
+        assert_eq!(
+            reopened.get("2").unwrap().map(|t| t.do_location_id),
+            Some(20)
+        );
+        assert_eq!(reopened.locations.count(LocationColumn::Dropoff, 10), 1);
+        assert_eq!(reopened.locations.count(LocationColumn::Dropoff, 20), 1);
+        assert_eq!(reopened.columns.rows(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Tabla armada a mano: la entrada del hash de inicio de "2" y la de "3" apuntan al
+    // registro de "1", como si "1" hubiera caído ahí por una colisión, y "4" tiene toda
+    // la secuencia de sondeo ocupada
+    #[test]
+    fn probing_skips_slots_taken_by_other_keys() {
+        let dir = test_dir("collision");
+        let one = encode_record("1", Some(&trip(1, 10))).unwrap();
+        let two = encode_record("2", Some(&trip(2, 20))).unwrap();
+        let two_position = one.len() as u64;
+        fs::write(dir.join("trip_data.bin"), [one, two].concat()).unwrap();
+
+        let mut table = HashTableOwned::<TripHashConfig>::with_capacity(64, LOAD_FACTOR_PERCENT);
+        table.insert(&calculate_hash("1", 0), &0);
+        table.insert(&calculate_hash("2", 0), &0);
+        table.insert(&calculate_hash("2", 1), &two_position);
+        table.insert(&calculate_hash("3", 0), &0);
+        for probe in 0..MAX_PROBES {
+            table.insert(&calculate_hash("4", probe), &0);
+        }
+        fs::write(dir.join("hash_table.bin"), table.raw_bytes()).unwrap();
+        DataStats {
+            indexed_len: two_position * 2,
+            ..DataStats::default()
+        }
+        .save(&dir)
+        .unwrap();
+
+        let mut table = DiskHashTable::new(&dir).unwrap();
+        assert_eq!(table.get("1").unwrap().map(|t| t.index), Some(1));
+        assert_eq!(table.get("2").unwrap().map(|t| t.index), Some(2));
+        assert_eq!(table.position_of("2").unwrap(), Some(two_position));
+        assert!(table.get("3").unwrap().is_none());
+
+        // Sin lugar libre la inserción falla en vez de perderse
+        assert!(table.get("4").is_err());
+        assert!(table.insert("4".to_string(), trip(4, 40)).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Las escrituras de la API sobreviven a una reconstrucción desde el dataset
+    #[test]
+    fn a_rebuild_keeps_the_writes_made_through_the_api() {
+        let dir = test_dir("carry");
+        let csv_path = dir.join("data.csv");
+        fs::write(&csv_path, SAMPLE_CSV).unwrap();
+        let index_dir = dir.join("index");
+        assert_eq!(
+            DiskHashTable::build_hash_table_from_csv(&csv_path, &index_dir).unwrap(),
+            3
+        );
+
+        let mut table = DiskHashTable::new(&index_dir).unwrap();
+        table.insert("1".to_string(), trip(1, 99)).unwrap();
+        assert!(table.delete("2").unwrap());
+        table.insert("4".to_string(), trip(4, 40)).unwrap();
+        table.sync().unwrap();
+        drop(table);
+        // Confirmada en el WAL pero sin aplicar, como tras una caída
+        wal::append(
+            &index_dir,
+            &[wal::WalEntry {
+                lsn: 1,
+                key: "5".to_string(),
+                trip: Some(trip(5, 50)),
+            }],
+        )
+        .unwrap();
+
+        DiskHashTable::build_hash_table_from_csv(&csv_path, &index_dir).unwrap();
+        let rebuilt = DiskHashTable::new(&index_dir).unwrap();
+        let location = |key: &str| rebuilt.get(key).unwrap().map(|t| t.do_location_id);
+        assert_eq!(location("1"), Some(99));
+        assert_eq!(location("2"), None);
+        assert_eq!(location("3"), Some(13));
+        assert_eq!(location("4"), Some(40));
+        assert_eq!(location("5"), Some(50));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_resumed_builder_drops_what_came_after_the_checkpoint() {
+        let dir = test_dir("resume");
+        let mut builder = DiskHashTableBuilder::create(&dir, 4).unwrap();
+        builder.insert("1", &trip(1, 10)).unwrap();
+        builder
+            .checkpoint(&DatasetPosition::default(), &[])
+            .unwrap();
+        builder.insert("2", &trip(2, 20)).unwrap();
+        builder
+            .checkpoint(&DatasetPosition::default(), &[])
+            .unwrap();
+        // Solo queda la copia de la tabla del último checkpoint
+        assert!(!dir.join(BuildCheckpoint::table_file(1)).exists());
+        // Lo escrito después del checkpoint se pierde con el proceso
+        builder.insert("3", &trip(3, 30)).unwrap();
+        builder.data.flush().unwrap();
+        drop(builder);
+
+        let checkpoint = BuildCheckpoint::load(&dir).unwrap().unwrap();
+        assert_eq!(checkpoint.sequence, 2);
+        assert_eq!(checkpoint.rows_ingested, 2);
+        let mut builder = DiskHashTableBuilder::resume(&dir, &checkpoint).unwrap();
+        builder.insert("4", &trip(4, 40)).unwrap();
+        assert_eq!(builder.finish().unwrap(), 3);
+        assert!(BuildCheckpoint::load(&dir).unwrap().is_none());
+        assert!(!dir.join(BuildCheckpoint::table_file(2)).exists());
+
+        let table = DiskHashTable::new(&dir).unwrap();
+        let location = |key: &str| table.get(key).unwrap().map(|t| t.do_location_id);
+        assert_eq!(location("1"), Some(10));
+        assert_eq!(location("2"), Some(20));
+        assert_eq!(location("3"), None);
+        assert_eq!(location("4"), Some(40));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Un staging a medio construir: el viaje 1 ya cargado con una marca que el CSV no
+    // tiene, así se distingue una reanudación de una carga desde cero
+    fn interrupted_build(csv_path: &Path, index_dir: &Path) {
+        let staging = staging_dir(index_dir);
+        let dataset = Dataset::open(csv_path).unwrap();
+        let sources = [SourceFingerprint::compute(csv_path).unwrap()];
+        let mut builder = DiskHashTableBuilder::create(&staging, 3).unwrap();
+        dataset
+            .stream_from(
+                &DatasetPosition::default(),
+                &[true; FIELD_NAMES.len()],
+                |row, position| {
+                    if row.index == 1 {
+                        builder.insert("1", &trip(1, 77))?;
+                        builder.checkpoint(position, &sources)?;
+                    }
+                    Ok(())
+                },
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn a_build_continues_from_the_checkpoint_in_staging() {
+        let dir = test_dir("continue");
+        let csv_path = dir.join("data.csv");
+        fs::write(&csv_path, SAMPLE_CSV).unwrap();
+        let index_dir = dir.join("index");
+        interrupted_build(&csv_path, &index_dir);
+
+        assert_eq!(
+            DiskHashTable::build_hash_table_from_csv(&csv_path, &index_dir).unwrap(),
+            3
+        );
+        assert!(!staging_dir(&index_dir).exists());
+        let table = DiskHashTable::new(&index_dir).unwrap();
+        let location = |key: &str| table.get(key).unwrap().map(|t| t.do_location_id);
+        assert_eq!(location("1"), Some(77));
+        assert_eq!(location("2"), Some(27));
+        assert_eq!(location("3"), Some(13));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_checkpoint_that_cannot_be_resumed_starts_over() {
+        let dir = test_dir("restart");
+        let csv_path = dir.join("data.csv");
+        fs::write(&csv_path, SAMPLE_CSV).unwrap();
+        let index_dir = dir.join("index");
+        interrupted_build(&csv_path, &index_dir);
+        // Datos más cortos de lo que dice el checkpoint
+        let staging = staging_dir(&index_dir);
+        let checkpoint = BuildCheckpoint::load(&staging).unwrap().unwrap();
+        fs::write(staging.join("trip_data.bin.tmp"), b"").unwrap();
+        assert!(DiskHashTableBuilder::resume(&staging, &checkpoint).is_err());
+
+        assert_eq!(
+            DiskHashTable::build_hash_table_from_csv(&csv_path, &index_dir).unwrap(),
+            3
+        );
+        let table = DiskHashTable::new(&index_dir).unwrap();
+        assert_eq!(table.get("1").unwrap().map(|t| t.do_location_id), Some(38));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_mapping_keeps_its_bytes_after_the_file_is_replaced() {
+        let dir = test_dir("mapping");
+        let path = dir.join("trip_data.bin");
+        fs::write(&path, b"").unwrap();
+        assert!(map_file(&path).unwrap().is_empty());
+
+        replace_file(&path, b"anterior").unwrap();
+        let old = map_file(&path).unwrap();
+        replace_file(&path, b"nuevo").unwrap();
+        assert_eq!(&old[..], b"anterior");
+        assert_eq!(&map_file(&path).unwrap()[..], b"nuevo");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Una consulta en curso sigue leyendo el índice que mapeó aunque una reconstrucción
+    // lo reemplace y borre
+    #[test]
+    fn an_open_table_reads_through_a_rebuild() {
+        let dir = test_dir("mapped-rebuild");
+        let csv_path = dir.join("data.csv");
+        fs::write(&csv_path, SAMPLE_CSV).unwrap();
+        let index_dir = dir.join("index");
+        DiskHashTable::build_hash_table_from_csv(&csv_path, &index_dir).unwrap();
+
+        let table = DiskHashTable::new(&index_dir).unwrap();
+        assert_eq!(
+            table.data.len() as u64,
+            fs::metadata(index_dir.join("trip_data.bin")).unwrap().len()
+        );
+        DiskHashTable::build_hash_table_from_csv(&csv_path, &index_dir).unwrap();
+        assert!(!sibling_dir(&index_dir, "old").exists());
+
+        assert_eq!(table.get("2").unwrap().map(|t| t.do_location_id), Some(27));
+        let (total, trips) = table
+            .find_by_location(LocationColumn::Dropoff, 13, 0, 10)
+            .unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(trips[0].index, 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Lo escrito mientras se copiaba (el 4 y el borrado del 3) entra en la segunda fase
+    #[test]
+    fn compaction_keeps_live_rows_and_drops_the_rest() {
+        let dir = test_dir("compaction");
+        let mut table = DiskHashTable::new(&dir).unwrap();
+        for key in 1..=3 {
+            table.insert(key.to_string(), trip(key, 10)).unwrap();
+        }
+        table.sync().unwrap();
+        table.insert("1".to_string(), trip(1, 11)).unwrap();
+        assert!(table.delete("2").unwrap());
+        table.sync().unwrap();
+        assert!(table.stats.dead_bytes > 0);
+        assert_eq!(table.stats.tombstones, 1);
+
+        let snapshot = table.clone();
+        let builder = snapshot.copy_live(&staging_dir(&dir)).unwrap();
+        table.insert("4".to_string(), trip(4, 40)).unwrap();
+        assert!(table.delete("3").unwrap());
+        table.sync().unwrap();
+        let before = table.log_len();
+        let (compacted, freed) = table.finish_compaction(builder, &snapshot).unwrap();
+        assert!(!staging_dir(&dir).exists());
+        assert_eq!(freed, before - compacted.log_len());
+        assert!(freed > 0);
+
+        for table in [compacted, DiskHashTable::new(&dir).unwrap()] {
+            let location = |key: &str| table.get(key).unwrap().map(|t| t.do_location_id);
+            assert_eq!(location("1"), Some(11));
+            assert_eq!(location("2"), None);
+            assert_eq!(location("3"), None);
+            assert_eq!(location("4"), Some(40));
+            // La lápida del 2 ya no existe, la del 3 llegó después de la copia
+            assert!(!table.contains_key("2").unwrap());
+            assert_eq!(table.stats.tombstones, 1);
+            assert_eq!(table.locations.count(LocationColumn::Dropoff, 10), 0);
+            assert_eq!(table.locations.count(LocationColumn::Dropoff, 11), 1);
+            assert!(table.check_secondary().unwrap().is_empty());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
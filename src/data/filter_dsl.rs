@@ -0,0 +1,413 @@
+use super::filter_params::{equality_filter, is_equality_field, is_range_field, range_filter};
+use super::filters::{SortOrder, TripFilter};
+use super::pagination::Pagination;
+use super::planner::ExplainMode;
+use super::trip_struct::FIELD_NAMES;
+use serde_json::{Map, Value};
+
+// Cuerpo de POST /trip/search ya validado
+pub struct SearchRequest {
+    pub filter: TripFilter,
+    pub pagination: Pagination,
+    pub sort: Option<SortOrder>,
+    // Campos del viaje a devolver, None para todos
+    pub fields: Option<Vec<&'static str>>,
+    pub explain: ExplainMode,
+}
+
+// Los errores dicen en qué nodo están, con la ruta desde la raíz del cuerpo:
+// "filter.and[1].not: ..."
+fn error(path: &str, message: impl std::fmt::Display) -> String {
+    format!("{}: {}", path, message)
+}
+
+fn object<'a>(value: &'a Value, path: &str) -> Result<&'a Map<String, Value>, String> {
+    value
+        .as_object()
+        .ok_or_else(|| error(path, "se esperaba un objeto"))
+}
+
+fn array<'a>(value: &'a Value, path: &str) -> Result<&'a Vec<Value>, String> {
+    value
+        .as_array()
+        .ok_or_else(|| error(path, "se esperaba una lista"))
+}
+
+fn string<'a>(value: &'a Value, path: &str) -> Result<&'a str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| error(path, "se esperaba un texto"))
+}
+
+// Números y textos (fechas, Y/N) se validan como en la query string
+fn scalar(value: &Value, path: &str) -> Result<String, String> {
+    match value {
+        Value::Number(number) => Ok(number.to_string()),
+        Value::String(text) => Ok(text.clone()),
+        _ => Err(error(path, "se esperaba un número o un texto")),
+    }
+}
+
+fn page_number(value: Option<&Value>, path: &str) -> Result<Option<usize>, String> {
+    value
+        .map(|value| {
+            value
+                .as_u64()
+                .map(|n| n as usize)
+                .ok_or_else(|| error(path, "se esperaba un entero positivo"))
+        })
+        .transpose()
+}
+
+fn field_index(value: &Value, path: &str) -> Result<usize, String> {
+    let name = string(value, path)?;
+    FIELD_NAMES
+        .iter()
+        .position(|field| *field == name)
+        .ok_or_else(|| error(path, format!("campo desconocido: {}", name)))
+}
+
+// Un nodo es exactamente una de estas formas:
+//   {"and": [nodos]}, {"or": [nodos]}, {"not": nodo}
+//   {"field": "campo", "eq": valor}
+//   {"field": "campo", "in": [valores]}
+//   {"field": "campo", "min": valor, "max": valor}   (uno o los dos extremos)
+// Los campos y los valores son los mismos que acepta la query string.
+pub fn parse_filter(value: &Value, path: &str) -> Result<TripFilter, String> {
+    let node = object(value, path)?;
+    if node.contains_key("field") {
+        return parse_leaf(node, path);
+    }
+    let mut keys = node.keys();
+    let (Some(key), None) = (keys.next(), keys.next()) else {
+        return Err(error(
+            path,
+            "un nodo lleva una sola de and, or, not o un campo con field",
+        ));
+    };
+    let child_path = format!("{}.{}", path, key);
+    let child = &node[key];
+    match key.as_str() {
+        "and" | "or" => {
+            let children = array(child, &child_path)?
+                .iter()
+                .enumerate()
+                .map(|(i, child)| parse_filter(child, &format!("{}[{}]", child_path, i)))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(if key == "and" {
+                TripFilter::And(children)
+            } else {
+                TripFilter::Or(children)
+            })
+        }
+        "not" => Ok(TripFilter::Not(Box::new(parse_filter(child, &child_path)?))),
+        _ => Err(error(path, format!("operador desconocido: {}", key))),
+    }
+}
+
+fn parse_leaf(node: &Map<String, Value>, path: &str) -> Result<TripFilter, String> {
+    let field_path = format!("{}.field", path);
+    let field = string(&node["field"], &field_path)?;
+    if !is_equality_field(field) && !is_range_field(field) {
+        return Err(error(&field_path, format!("campo desconocido: {}", field)));
+    }
+    for key in node.keys() {
+        if !["field", "eq", "in", "min", "max"].contains(&key.as_str()) {
+            return Err(error(path, format!("clave desconocida: {}", key)));
+        }
+    }
+    let operand = |key: &str| {
+        node.get(key)
+            .map(|value| scalar(value, &format!("{}.{}", path, key)))
+            .transpose()
+    };
+    let at = |key: &'static str| move |e: String| error(&format!("{}.{}", path, key), e);
+
+    match (node.get("eq"), node.get("in")) {
+        (Some(_), Some(_)) => Err(error(path, "eq e in no van juntos")),
+        (Some(_), None) | (None, Some(_))
+            if node.contains_key("min") || node.contains_key("max") =>
+        {
+            Err(error(path, "min y max no van con eq ni con in"))
+        }
+        (Some(_), None) => {
+            let value = operand("eq")?.unwrap_or_default();
+            if is_equality_field(field) {
+                equality_filter(field, &[value.as_str()]).map_err(at("eq"))
+            } else {
+                // En un campo de rango la igualdad es el rango de un solo valor
+                range_filter(field, Some(&value), Some(&value)).map_err(at("eq"))
+            }
+        }
+        (None, Some(values)) => {
+            let in_path = format!("{}.in", path);
+            if !is_equality_field(field) {
+                return Err(error(&in_path, format!("{} no admite in", field)));
+            }
+            let values = array(values, &in_path)?
+                .iter()
+                .enumerate()
+                .map(|(i, value)| scalar(value, &format!("{}[{}]", in_path, i)))
+                .collect::<Result<Vec<_>, _>>()?;
+            if values.is_empty() {
+                return Ok(TripFilter::Or(Vec::new()));
+            }
+            // Cada valor se valida por separado para señalar cuál está mal
+            for (i, value) in values.iter().enumerate() {
+                equality_filter(field, &[value.as_str()])
+                    .map_err(|e| error(&format!("{}[{}]", in_path, i), e))?;
+            }
+            let values: Vec<&str> = values.iter().map(String::as_str).collect();
+            equality_filter(field, &values).map_err(at("in"))
+        }
+        (None, None) => {
+            let (min, max) = (operand("min")?, operand("max")?);
+            if min.is_none() && max.is_none() {
+                return Err(error(path, "falta eq, in, min o max"));
+            }
+            if !is_range_field(field) {
+                return Err(error(path, format!("{} no admite min ni max", field)));
+            }
+            range_filter(field, min.as_deref(), None).map_err(at("min"))?;
+            range_filter(field, None, max.as_deref()).map_err(at("max"))?;
+            range_filter(field, min.as_deref(), max.as_deref()).map_err(|e| error(path, e))
+        }
+    }
+}
+
+// {"filter": nodo, "page": 1, "per_page": 50,
+//  "sort": {"field": "total_amount", "order": "desc"}, "fields": ["index", ...]}
+// "explain": true agrega el plan a la respuesta y "explain": "only" devuelve solo el
+// plan. Todas las claves son opcionales; sin filter se listan todos los viajes.
+pub fn parse_search(body: &Value) -> Result<SearchRequest, String> {
+    let root = object(body, "$")?;
+    for key in root.keys() {
+        if !["filter", "page", "per_page", "sort", "fields", "explain"].contains(&key.as_str()) {
+            return Err(error("$", format!("clave desconocida: {}", key)));
+        }
+    }
+
+    let filter = match root.get("filter") {
+        Some(filter) => parse_filter(filter, "filter")?,
+        None => TripFilter::And(Vec::new()),
+    };
+    let pagination = Pagination::from_query(
+        page_number(root.get("page"), "page")?,
+        page_number(root.get("per_page"), "per_page")?,
+    );
+
+    let sort = match root.get("sort") {
+        Some(sort) => {
+            let node = object(sort, "sort")?;
+            for key in node.keys() {
+                if key != "field" && key != "order" {
+                    return Err(error("sort", format!("clave desconocida: {}", key)));
+                }
+            }
+            let field = field_index(
+                node.get("field")
+                    .ok_or_else(|| error("sort", "falta field"))?,
+                "sort.field",
+            )?;
+            let descending = match node
+                .get("order")
+                .map(|o| string(o, "sort.order"))
+                .transpose()?
+            {
+                None | Some("asc") => false,
+                Some("desc") => true,
+                Some(order) => {
+                    return Err(error(
+                        "sort.order",
+                        format!("orden desconocido: {} (asc o desc)", order),
+                    ));
+                }
+            };
+            Some(SortOrder { field, descending })
+        }
+        None => None,
+    };
+
+    let fields = match root.get("fields") {
+        Some(fields) => Some(
+            array(fields, "fields")?
+                .iter()
+                .enumerate()
+                .map(|(i, field)| {
+                    field_index(field, &format!("fields[{}]", i)).map(|i| FIELD_NAMES[i])
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        None => None,
+    };
+
+    let explain = match root.get("explain") {
+        Some(Value::Bool(true)) => ExplainMode::Alongside,
+        Some(Value::Bool(false)) | None => ExplainMode::Off,
+        Some(Value::String(mode)) => mode.parse().map_err(|e| error("explain", e))?,
+        Some(_) => return Err(error("explain", "se esperaba true, false u \"only\"")),
+    };
+
+    Ok(SearchRequest {
+        filter,
+        pagination,
+        sort,
+        fields,
+        explain,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse_error(body: Value) -> String {
+        match parse_search(&body) {
+            Ok(_) => panic!("se esperaba un error para {}", body),
+            Err(e) => e,
+        }
+    }
+
+    // El error empieza con la ruta del nodo que falla
+    fn assert_fails_at(body: Value, path: &str) {
+        let error = parse_error(body);
+        assert!(
+            error.starts_with(&format!("{}: ", path)),
+            "se esperaba un error en {}, llegó: {}",
+            path,
+            error
+        );
+    }
+
+    #[test]
+    fn a_nested_search_parses() {
+        let request = parse_search(&json!({
+            "filter": {"and": [
+                {"field": "do_location_id", "in": [132, 138]},
+                {"not": {"field": "payment_type", "eq": 3}},
+                {"or": [
+                    {"field": "tip_amount", "min": 5},
+                    {"field": "tpep_pickup_datetime", "min": "2024-01-05T17:00:00"}
+                ]}
+            ]},
+            "sort": {"field": "total_amount", "order": "desc"},
+            "fields": ["index", "total_amount"],
+            "explain": "only"
+        }))
+        .unwrap();
+        assert!(matches!(&request.filter, TripFilter::And(children) if children.len() == 3));
+        assert!(request.sort.is_some_and(|sort| sort.descending));
+        assert_eq!(request.fields, Some(vec!["index", "total_amount"]));
+        assert_eq!(request.explain, ExplainMode::Only);
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_their_path() {
+        assert_eq!(
+            parse_error(json!({"filter": {"and": []}, "limit": 5})),
+            "$: clave desconocida: limit"
+        );
+        assert_eq!(
+            parse_error(json!({"filter": {"and": [
+                {"field": "vendor_id", "eq": 1},
+                {"or": [{"field": "total_amount", "min": 1, "above": 2}]}
+            ]}})),
+            "filter.and[1].or[0]: clave desconocida: above"
+        );
+        assert_eq!(
+            parse_error(json!({"filter": {"and": [
+                {"field": "vendor_id", "eq": 1},
+                {"not": {"field": "tip", "min": 1}}
+            ]}})),
+            "filter.and[1].not.field: campo desconocido: tip"
+        );
+        assert_eq!(
+            parse_error(json!({"filter": {"or": [{"xor": []}]}})),
+            "filter.or[0]: operador desconocido: xor"
+        );
+        assert_eq!(
+            parse_error(json!({"filter": {"and": [], "or": []}})),
+            "filter: un nodo lleva una sola de and, or, not o un campo con field"
+        );
+        assert_eq!(
+            parse_error(json!({"sort": {"field": "index", "direction": "asc"}})),
+            "sort: clave desconocida: direction"
+        );
+        assert_eq!(
+            parse_error(json!({"fields": ["index", "fare"]})),
+            "fields[1]: campo desconocido: fare"
+        );
+    }
+
+    #[test]
+    fn wrong_types_are_reported_with_their_path() {
+        assert_eq!(
+            parse_error(json!({"filter": {"and": {"field": "vendor_id", "eq": 1}}})),
+            "filter.and: se esperaba una lista"
+        );
+        assert_eq!(
+            parse_error(json!({"filter": {"or": [{"not": [1]}]}})),
+            "filter.or[0].not: se esperaba un objeto"
+        );
+        assert_eq!(
+            parse_error(json!({"filter": {"and": [{"or": [
+                {"field": "payment_type", "eq": true}
+            ]}]}})),
+            "filter.and[0].or[0].eq: se esperaba un número o un texto"
+        );
+        assert_eq!(
+            parse_error(json!({"filter": {"and": [
+                {"field": "do_location_id", "in": [132, {"id": 138}]}
+            ]}})),
+            "filter.and[0].in[1]: se esperaba un número o un texto"
+        );
+        assert_eq!(
+            parse_error(json!({"filter": {"not": {"field": 8, "eq": 1}}})),
+            "filter.not.field: se esperaba un texto"
+        );
+        assert_eq!(
+            parse_error(json!({"filter": {"and": [
+                {"field": "total_amount", "in": [1, 2]}
+            ]}})),
+            "filter.and[0].in: total_amount no admite in"
+        );
+        assert_fails_at(json!({"page": "2"}), "page");
+        assert_fails_at(json!({"per_page": -1}), "per_page");
+        assert_fails_at(json!({"explain": 1}), "explain");
+        assert_fails_at(json!({"explain": "always"}), "explain");
+        assert_fails_at(
+            json!({"sort": {"field": "index", "order": "up"}}),
+            "sort.order",
+        );
+    }
+
+    #[test]
+    fn out_of_range_values_are_reported_with_their_path() {
+        // Los IDs de ubicación son u32
+        assert_fails_at(
+            json!({"filter": {"and": [
+                {"field": "vendor_id", "eq": 1},
+                {"or": [
+                    {"field": "passenger_count", "eq": 2},
+                    {"field": "do_location_id", "eq": 5_000_000_000u64}
+                ]}
+            ]}}),
+            "filter.and[1].or[1].eq",
+        );
+        assert_fails_at(
+            json!({"filter": {"or": [{"not": {"field": "pu_location_id", "in": [1, -4]}}]}}),
+            "filter.or[0].not.in[1]",
+        );
+        assert_fails_at(
+            json!({"filter": {"and": [{"or": [
+                {"field": "tpep_pickup_datetime", "min": "2024-13-40T25:00:00"}
+            ]}]}}),
+            "filter.and[0].or[0].min",
+        );
+        assert_fails_at(
+            json!({"filter": {"and": [{"not": {"field": "fare_amount", "max": "mucho"}}]}}),
+            "filter.and[0].not.max",
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use super::dataset::TimeRange;
+use super::filters::{AmountField, KeyField, TripFilter};
+use super::trip_struct::{StoreAndFwdFlag, parse_datetime};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+// Delante de cualquier parámetro niega la condición de ese campo: not_payment_type=3
+const NOT_PREFIX: &str = "not_";
+const FLAG_FIELD: &str = "store_and_fwd_flag";
+const DISTANCE_FIELD: &str = "trip_distance";
+const PASSENGERS_FIELD: &str = "passenger_count";
+const PICKUP_FIELD: &str = "tpep_pickup_datetime";
+const DROPOFF_FIELD: &str = "tpep_dropoff_datetime";
+
+// Extremos de un rango tal como llegaron en la query
+#[derive(Default)]
+struct Bounds<'a> {
+    min: Option<&'a str>,
+    max: Option<&'a str>,
+}
+
+pub fn is_range_field(field: &str) -> bool {
+    [
+        DISTANCE_FIELD,
+        PASSENGERS_FIELD,
+        PICKUP_FIELD,
+        DROPOFF_FIELD,
+    ]
+    .contains(&field)
+        || AmountField::ALL.iter().any(|f| f.field_name() == field)
+}
+
+pub fn is_equality_field(field: &str) -> bool {
+    field == FLAG_FIELD || KeyField::ALL.iter().any(|f| f.field_name() == field)
+}
+
+fn parse_number<T: FromStr>(field: &str, value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("Valor inválido en {}: {}", field, value))
+}
+
+fn parse_bound<T: FromStr>(
+    field: &str,
+    suffix: &str,
+    value: Option<&str>,
+) -> Result<Option<T>, String> {
+    value
+        .map(|v| parse_number(&format!("{}{}", field, suffix), v))
+        .transpose()
+}
+
+// Un valor es una igualdad, varios son pertenencia al conjunto
+pub fn equality_filter(field: &str, values: &[&str]) -> Result<TripFilter, String> {
+    if field == FLAG_FIELD {
+        let mut flags = Vec::new();
+        for flag in values {
+            flags.push(TripFilter::StoreAndFwd(match flag.trim() {
+                "Y" => StoreAndFwdFlag::Y,
+                "N" => StoreAndFwdFlag::N,
+                _ => return Err(format!("Valor inválido en {}: {} (Y o N)", field, flag)),
+            }));
+        }
+        return Ok(if flags.len() == 1 {
+            flags.remove(0)
+        } else {
+            TripFilter::Or(flags)
+        });
+    }
+
+    let Some(key) = KeyField::ALL.into_iter().find(|f| f.field_name() == field) else {
+        return Err(format!("Parámetro desconocido: {}", field));
+    };
+    let values = values
+        .iter()
+        .map(|v| parse_number::<u64>(field, v))
+        .collect::<Result<Vec<u64>, String>>()?;
+    // Todos menos el índice son u32 en el viaje
+    if key != KeyField::Index
+        && let Some(value) = values.iter().find(|v| u32::try_from(**v).is_err())
+    {
+        return Err(format!("Valor fuera de rango en {}: {}", field, value));
+    }
+    Ok(key.filter(values))
+}
+
+// Extremos `campo_min` y `campo_max`, ambos incluidos
+pub fn range_filter(
+    field: &str,
+    min: Option<&str>,
+    max: Option<&str>,
+) -> Result<TripFilter, String> {
+    let time_bound = |suffix: &str, value: Option<&str>| {
+        value
+            .map(|v| {
+                parse_datetime(v.trim())
+                    .ok_or_else(|| format!("Fecha inválida en {}{}: {}", field, suffix, v))
+            })
+            .transpose()
+    };
+    Ok(match field {
+        PICKUP_FIELD | DROPOFF_FIELD => {
+            let range = TimeRange {
+                from: time_bound("_min", min)?,
+                to: time_bound("_max", max)?,
+            };
+            if field == PICKUP_FIELD {
+                TripFilter::PickupTime(range)
+            } else {
+                TripFilter::DropoffTime(range)
+            }
+        }
+        PASSENGERS_FIELD => TripFilter::Passengers {
+            min: parse_bound(field, "_min", min)?,
+            max: parse_bound(field, "_max", max)?,
+        },
+        DISTANCE_FIELD => TripFilter::Distance {
+            min: parse_bound(field, "_min", min)?,
+            max: parse_bound(field, "_max", max)?,
+        },
+        _ => match AmountField::ALL
+            .into_iter()
+            .find(|f| f.field_name() == field)
+        {
+            Some(amount) => TripFilter::Amount {
+                field: amount,
+                min: parse_bound(field, "_min", min)?,
+                max: parse_bound(field, "_max", max)?,
+            },
+            None => return Err(format!("Parámetro desconocido: {}", field)),
+        },
+    })
+}
+
+// Filtro de la query string, None si no trae condiciones. Los campos se llaman como en
+// el JSON del viaje: `campo=v1,v2` pide alguno de los valores, `campo_min`/`campo_max`
+// acotan un rango y `not_` delante niega la condición del campo. Todo se combina con
+// And. Las claves de `reserved` son de la ruta (paginación y sus propios parámetros);
+// cualquier otra que no sea del vocabulario es un error.
+pub fn parse_filter_params(
+    params: &HashMap<String, String>,
+    reserved: &[&str],
+) -> Result<Option<TripFilter>, String> {
+    let mut equalities: BTreeMap<(bool, &str), &str> = BTreeMap::new();
+    let mut ranges: BTreeMap<(bool, &str), Bounds> = BTreeMap::new();
+    for (key, value) in params {
+        if reserved.contains(&key.as_str()) {
+            continue;
+        }
+        let (negated, name) = match key.strip_prefix(NOT_PREFIX) {
+            Some(name) => (true, name),
+            None => (false, key.as_str()),
+        };
+        if let Some(field) = name.strip_suffix("_min")
+            && is_range_field(field)
+        {
+            ranges.entry((negated, field)).or_default().min = Some(value);
+        } else if let Some(field) = name.strip_suffix("_max")
+            && is_range_field(field)
+        {
+            ranges.entry((negated, field)).or_default().max = Some(value);
+        } else if is_equality_field(name) {
+            equalities.insert((negated, name), value);
+        } else {
+            return Err(format!("Parámetro desconocido: {}", key));
+        }
+    }
+
+    let negate = |negated: bool, filter: TripFilter| {
+        if negated {
+            TripFilter::Not(Box::new(filter))
+        } else {
+            filter
+        }
+    };
+    let mut filters = Vec::new();
+    for ((negated, field), value) in equalities {
+        let values: Vec<&str> = value.split(',').collect();
+        filters.push(negate(negated, equality_filter(field, &values)?));
+    }
+    for ((negated, field), bounds) in ranges {
+        filters.push(negate(
+            negated,
+            range_filter(field, bounds.min, bounds.max)?,
+        ));
+    }
+    Ok(match filters.len() {
+        0 => None,
+        1 => filters.pop(),
+        _ => Some(TripFilter::And(filters)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESERVED: [&str; 2] = ["page", "per_page"];
+
+    fn parse(query: &[(&str, &str)]) -> Result<Option<String>, String> {
+        let params = query
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Ok(parse_filter_params(&params, &RESERVED)?.map(|filter| filter.to_string()))
+    }
+
+    #[test]
+    fn a_query_without_conditions_has_no_filter() {
+        assert_eq!(parse(&[]), Ok(None));
+        assert_eq!(parse(&[("page", "2"), ("per_page", "10")]), Ok(None));
+    }
+
+    #[test]
+    fn values_become_equalities_or_sets() {
+        let single = |key, value| parse(&[(key, value)]).unwrap().unwrap();
+        assert_eq!(single("vendor_id", "2"), "vendor_id = 2");
+        assert_eq!(single("vendor_id", "2, 1,2"), "vendor_id in (1, 2)");
+        assert_eq!(single("index", "4294967296"), "index = 4294967296");
+        assert_eq!(
+            single("store_and_fwd_flag", "Y,N"),
+            "(store_and_fwd_flag = Y or store_and_fwd_flag = N)"
+        );
+        assert_eq!(single("passenger_count", "2"), "passenger_count = 2");
+        assert_eq!(single("passenger_count_min", "2"), "passenger_count >= 2");
+        assert_eq!(
+            single("tpep_dropoff_datetime_max", "2024-01-05T10:00:00"),
+            "tpep_dropoff_datetime <= 2024-01-05 10:00:00"
+        );
+    }
+
+    #[test]
+    fn conditions_are_combined_with_and() {
+        let filter = parse(&[
+            ("page", "2"),
+            ("total_amount_max", "20"),
+            ("not_do_location_id", "7"),
+            ("payment_type", "1"),
+            ("total_amount_min", "10"),
+            ("not_tpep_pickup_datetime_min", "2024-01-05 00:00:00"),
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            filter,
+            "(payment_type = 1 and not (do_location_id = 7) and 10 <= total_amount <= 20 \
+             and not (tpep_pickup_datetime >= 2024-01-05 00:00:00))"
+        );
+    }
+
+    #[test]
+    fn bad_parameters_are_rejected() {
+        let error = |key, value| parse(&[(key, value)]).unwrap_err();
+        assert_eq!(error("foo", "1"), "Parámetro desconocido: foo");
+        assert_eq!(error("not_page", "1"), "Parámetro desconocido: not_page");
+        // Los importes solo admiten rangos y las claves solo igualdades
+        assert_eq!(
+            error("fare_amount", "3"),
+            "Parámetro desconocido: fare_amount"
+        );
+        assert_eq!(
+            error("vendor_id_min", "1"),
+            "Parámetro desconocido: vendor_id_min"
+        );
+        assert_eq!(
+            error("vendor_id", "abc"),
+            "Valor inválido en vendor_id: abc"
+        );
+        assert_eq!(
+            error("do_location_id", "4294967296"),
+            "Valor fuera de rango en do_location_id: 4294967296"
+        );
+        assert_eq!(
+            error("store_and_fwd_flag", "X"),
+            "Valor inválido en store_and_fwd_flag: X (Y o N)"
+        );
+        assert_eq!(
+            error("trip_distance_min", "x"),
+            "Valor inválido en trip_distance_min: x"
+        );
+        assert_eq!(
+            error("tpep_pickup_datetime_max", "ayer"),
+            "Fecha inválida en tpep_pickup_datetime_max: ayer"
+        );
+    }
+}
@@ -1,107 +1,384 @@
-use super::disk_hash::DiskHashTable;
+use super::bitmap_index::{BitmapColumn, BitmapIndex, flag_value};
+use super::column_store::{RowGroup, zone_key};
+use super::dataset::{Dataset, TimeRange};
+use super::disk_hash::{DiskHashTable, recover_index_dir, staging_dir};
+use super::fsck::{self, FsckReport};
+use super::index_manifest::{BuildCheckpoint, IndexCheck, IndexManifest};
 use super::pagination::{PagedResult, Pagination};
-use super::trip_struct::Trip;
+use super::planner::{self, AccessPath, Explain, ExplainMode, QueryPlan};
+use super::range_index::{KeyRange, RangeColumn};
+use super::trip_struct::{FIELD_NAMES, StoreAndFwdFlag, Trip};
+use super::wal::{self, WalEntry};
 use crate::Instant;
+use crate::config::{self, RebuildPolicy};
+use arc_swap::ArcSwapOption;
+use roaring::RoaringBitmap;
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::fs::{self, File};
 use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
-use std::sync::LazyLock;
+use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::Once;
-const HASH_DIR: &str = "tmp/hash_index";
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
 static HASH_INIT: Once = Once::new();
-static HASH_TABLE: LazyLock<Mutex<Option<DiskHashTable>>> = LazyLock::new(|| Mutex::new(None));
+// Versión publicada del índice. Los lectores toman un Arc sin bloquear; los escritores
+// publican una versión nueva y la anterior se libera cuando termina su último lector.
+static HASH_TABLE: ArcSwapOption<DiskHashTable> = ArcSwapOption::const_empty();
+// Solo lo toman los escritores (actualizaciones, borrados, compactación y carga)
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+static COMPACTING: AtomicBool = AtomicBool::new(false);
+// Escrituras esperando a que alguien las confirme en el WAL
+static COMMIT_QUEUE: Mutex<Vec<PendingWrite>> = Mutex::new(Vec::new());
 
+// Importes del viaje que se pueden filtrar por rango
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmountField {
+    Fare,
+    Extra,
+    MtaTax,
+    Tip,
+    Tolls,
+    ImprovementSurcharge,
+    Total,
+    CongestionSurcharge,
+}
+
+impl AmountField {
+    pub const ALL: [AmountField; 8] = [
+        AmountField::Fare,
+        AmountField::Extra,
+        AmountField::MtaTax,
+        AmountField::Tip,
+        AmountField::Tolls,
+        AmountField::ImprovementSurcharge,
+        AmountField::Total,
+        AmountField::CongestionSurcharge,
+    ];
+
+    pub fn field_name(self) -> &'static str {
+        match self {
+            AmountField::Fare => "fare_amount",
+            AmountField::Extra => "extra",
+            AmountField::MtaTax => "mta_tax",
+            AmountField::Tip => "tip_amount",
+            AmountField::Tolls => "tolls_amount",
+            AmountField::ImprovementSurcharge => "improvement_surcharge",
+            AmountField::Total => "total_amount",
+            AmountField::CongestionSurcharge => "congestion_surcharge",
+        }
+    }
+
+    fn value(self, trip: &Trip) -> Decimal {
+        match self {
+            AmountField::Fare => trip.fare_amount,
+            AmountField::Extra => trip.extra,
+            AmountField::MtaTax => trip.mta_tax,
+            AmountField::Tip => trip.tip_amount,
+            AmountField::Tolls => trip.tolls_amount,
+            AmountField::ImprovementSurcharge => trip.improvement_surcharge,
+            AmountField::Total => trip.total_amount,
+            AmountField::CongestionSurcharge => trip.congestion_surcharge,
+        }
+    }
+
+    // Solo algunos importes tienen índice de rango
+    pub fn range_column(self) -> Option<RangeColumn> {
+        match self {
+            AmountField::Total => Some(RangeColumn::TotalAmount),
+            AmountField::Fare => Some(RangeColumn::FareAmount),
+            _ => None,
+        }
+    }
+}
+
+// Campos enteros que admiten pertenencia a un conjunto de valores
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyField {
+    Index,
+    Vendor,
+    RateCode,
+    PickupLocation,
+    Destination,
+    PaymentType,
+    PassengerCount,
+}
+
+impl KeyField {
+    pub const ALL: [KeyField; 7] = [
+        KeyField::Index,
+        KeyField::Vendor,
+        KeyField::RateCode,
+        KeyField::PickupLocation,
+        KeyField::Destination,
+        KeyField::PaymentType,
+        KeyField::PassengerCount,
+    ];
+
+    pub fn field_name(self) -> &'static str {
+        match self {
+            KeyField::Index => "index",
+            KeyField::Vendor => "vendor_id",
+            KeyField::RateCode => "ratecode_id",
+            KeyField::PickupLocation => "pu_location_id",
+            KeyField::Destination => "do_location_id",
+            KeyField::PaymentType => "payment_type",
+            KeyField::PassengerCount => "passenger_count",
+        }
+    }
+
+    fn value(self, trip: &Trip) -> u64 {
+        match self {
+            KeyField::Index => trip.index,
+            KeyField::Vendor => trip.vendor_id as u64,
+            KeyField::RateCode => trip.ratecode_id as u64,
+            KeyField::PickupLocation => trip.pu_location_id as u64,
+            KeyField::Destination => trip.do_location_id as u64,
+            KeyField::PaymentType => trip.payment_type as u64,
+            KeyField::PassengerCount => trip.passenger_count as u64,
+        }
+    }
+
+    // Igualdad con un valor o pertenencia a varios. Los valores de los campos que no
+    // son el índice ya vienen validados como u32.
+    pub fn filter(self, mut values: Vec<u64>) -> TripFilter {
+        values.sort_unstable();
+        values.dedup();
+        let [value] = values[..] else {
+            return TripFilter::In(self, values);
+        };
+        let small = value as u32;
+        match self {
+            KeyField::Index => TripFilter::Index(value),
+            KeyField::Vendor => TripFilter::Vendor(small),
+            KeyField::RateCode => TripFilter::RateCode(small),
+            KeyField::PickupLocation => TripFilter::PickupLocation(small),
+            KeyField::Destination => TripFilter::Destination(small),
+            KeyField::PaymentType => TripFilter::PaymentType(small),
+            KeyField::PassengerCount => TripFilter::PassengerCount(small),
+        }
+    }
+
+    fn bitmap_column(self) -> Option<BitmapColumn> {
+        match self {
+            KeyField::Vendor => Some(BitmapColumn::VendorId),
+            KeyField::RateCode => Some(BitmapColumn::RatecodeId),
+            KeyField::PaymentType => Some(BitmapColumn::PaymentType),
+            KeyField::PassengerCount => Some(BitmapColumn::PassengerCount),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum TripFilter {
-    Price { min: Option<f64>, max: Option<f64> },
-    Index(String),
-    Destination(String),
+    Amount {
+        field: AmountField,
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    Distance {
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    Index(u64),
+    Destination(u32),
+    PickupLocation(u32),
+    PickupTime(TimeRange),
+    DropoffTime(TimeRange),
+    Vendor(u32),
+    PaymentType(u32),
+    RateCode(u32),
+    StoreAndFwd(StoreAndFwdFlag),
+    PassengerCount(u32),
+    Passengers {
+        min: Option<u32>,
+        max: Option<u32>,
+    },
+    // El campo vale alguno de los valores
+    In(KeyField, Vec<u64>),
+    Not(Box<TripFilter>),
     And(Vec<TripFilter>),
     Or(Vec<TripFilter>),
 }
 
+// Un valor que no es un número (NaN) no cumple ningún rango acotado
+fn within(value: f64, min: Option<f64>, max: Option<f64>) -> bool {
+    min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+}
+
 impl TripFilter {
-    pub fn matches(&self, trip: &Trip) -> bool {
+    // Campos de Trip que el filtro necesita leer
+    pub fn referenced_fields(&self, fields: &mut Vec<&'static str>) {
         match self {
-            TripFilter::Price { min, max } => {
-                let price = trip.total_amount.parse::<f64>().unwrap_or(0.0);
-                let min_check = min.map_or(true, |min_val| price >= min_val);
-                let max_check = max.map_or(true, |max_val| price <= max_val);
+            TripFilter::Amount { field, .. } => fields.push(field.field_name()),
+            TripFilter::Distance { .. } => fields.push("trip_distance"),
+            TripFilter::Index(_) => fields.push("index"),
+            TripFilter::Destination(_) => fields.push("do_location_id"),
+            TripFilter::PickupLocation(_) => fields.push("pu_location_id"),
+            TripFilter::PickupTime(_) => fields.push("tpep_pickup_datetime"),
+            TripFilter::DropoffTime(_) => fields.push("tpep_dropoff_datetime"),
+            TripFilter::Vendor(_) => fields.push("vendor_id"),
+            TripFilter::PaymentType(_) => fields.push("payment_type"),
+            TripFilter::RateCode(_) => fields.push("ratecode_id"),
+            TripFilter::StoreAndFwd(_) => fields.push("store_and_fwd_flag"),
+            TripFilter::PassengerCount(_) | TripFilter::Passengers { .. } => {
+                fields.push("passenger_count")
+            }
+            TripFilter::In(field, _) => fields.push(field.field_name()),
+            TripFilter::Not(filter) => filter.referenced_fields(fields),
+            TripFilter::And(filters) | TripFilter::Or(filters) => {
+                for filter in filters {
+                    filter.referenced_fields(fields);
+                }
+            }
+        }
+    }
 
-                min_check && max_check
+    // Rango de pickup que cualquier viaje que cumpla el filtro tiene que respetar, se
+    // usa para descartar particiones completas
+    pub fn pickup_range(&self) -> TimeRange {
+        match self {
+            TripFilter::PickupTime(range) => *range,
+            TripFilter::And(filters) => filters.iter().fold(TimeRange::default(), |acc, f| {
+                acc.intersect(&f.pickup_range())
+            }),
+            TripFilter::Or(filters) => filters
+                .iter()
+                .map(|f| f.pickup_range())
+                .reduce(|acc, r| acc.hull(&r))
+                .unwrap_or_default(),
+            _ => TimeRange::default(),
+        }
+    }
+
+    pub fn matches(&self, trip: &Trip) -> bool {
+        match self {
+            TripFilter::Amount { field, min, max } => {
+                within(field.value(trip).to_f64().unwrap_or(f64::NAN), *min, *max)
             }
+            TripFilter::Distance { min, max } => within(trip.trip_distance, *min, *max),
             TripFilter::Index(target_index) => trip.index == *target_index,
             TripFilter::Destination(target_dest) => trip.do_location_id == *target_dest,
+            TripFilter::PickupLocation(target) => trip.pu_location_id == *target,
+            TripFilter::PickupTime(range) => range.contains(trip.tpep_pickup_datetime),
+            TripFilter::DropoffTime(range) => range.contains(trip.tpep_dropoff_datetime),
+            TripFilter::Vendor(vendor) => trip.vendor_id == *vendor,
+            TripFilter::PaymentType(payment) => trip.payment_type == *payment,
+            TripFilter::RateCode(rate) => trip.ratecode_id == *rate,
+            TripFilter::StoreAndFwd(flag) => trip.store_and_fwd_flag == *flag,
+            TripFilter::PassengerCount(count) => trip.passenger_count == *count,
+            TripFilter::Passengers { min, max } => {
+                min.is_none_or(|min| trip.passenger_count >= min)
+                    && max.is_none_or(|max| trip.passenger_count <= max)
+            }
+            TripFilter::In(field, values) => values.contains(&field.value(trip)),
+            TripFilter::Not(filter) => !filter.matches(trip),
             TripFilter::And(filters) => filters.iter().all(|filter| filter.matches(trip)),
             TripFilter::Or(filters) => filters.iter().any(|filter| filter.matches(trip)),
         }
     }
 }
 
-fn get_or_initialize_hash_table<P: AsRef<Path>>(
-    csv_path: P,
-) -> Result<&'static Mutex<Option<DiskHashTable>>, Box<dyn Error>> {
-    HASH_INIT.call_once(|| {
-        println!("Inicializando tabla hash en disco...");
-        let hash_path = PathBuf::from(HASH_DIR);
-        let needs_build = if hash_path.exists() {
-            let table_path = hash_path.join("hash_table.bin");
-            let data_path = hash_path.join("trip_data.bin");
-
-            !(table_path.exists()
-                && data_path.exists()
-                && fs::metadata(&table_path)
-                    .map(|m| m.len() > 0)
-                    .unwrap_or(false))
-        } else {
-            true
-        };
-
-        if !hash_path.exists() {
-            if let Err(e) = fs::create_dir_all(&hash_path) {
-                eprintln!("Error al crear directorio hash: {}", e);
-                return;
-            }
-        }
-        match DiskHashTable::new(&hash_path) {
-            Ok(hash_table) => {
-                let mut table_ref = HASH_TABLE.lock().unwrap();
-                *table_ref = Some(hash_table);
-
-                if needs_build {
-                    println!("Construyendo índice hash desde CSV...");
-
-                    // Convertir ambos paths a String para asegurar que sean del mismo tipo
-                    let csv_path_str = csv_path.as_ref().to_string_lossy().to_string();
-                    let hash_path_str = hash_path.to_string_lossy().to_string();
+// Extremos de un rango en la notación del plan: `10 <= total_amount <= 20`
+fn write_bounds<T: fmt::Display>(
+    f: &mut fmt::Formatter,
+    field: &str,
+    min: Option<T>,
+    max: Option<T>,
+) -> fmt::Result {
+    match (min, max) {
+        (Some(min), Some(max)) => write!(f, "{} <= {} <= {}", min, field, max),
+        (Some(min), None) => write!(f, "{} >= {}", field, min),
+        (None, Some(max)) => write!(f, "{} <= {}", field, max),
+        (None, None) => write!(f, "{} = *", field),
+    }
+}
 
-                    // Pasar ambos como referencias a String
-                    match DiskHashTable::build_hash_table_from_csv(&csv_path_str, &hash_path_str) {
-                        Ok(count) => println!("Índice hash construido con {} registros", count),
-                        Err(e) => eprintln!("Error al construir índice hash: {}", e),
-                    }
-                } else {
-                    println!("Usando índice hash existente");
+// Forma legible del filtro para los logs y para explain. Un And vacío es `true` y un Or
+// vacío es `false`.
+impl fmt::Display for TripFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let join = |f: &mut fmt::Formatter, filters: &[TripFilter], separator: &str| {
+            write!(f, "(")?;
+            for (i, filter) in filters.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " {} ", separator)?;
                 }
+                write!(f, "{}", filter)?;
             }
-            Err(e) => {
-                eprintln!("Error al inicializar tabla hash: {}", e);
+            write!(f, ")")
+        };
+        match self {
+            TripFilter::Amount { field, min, max } => {
+                write_bounds(f, field.field_name(), *min, *max)
+            }
+            TripFilter::Distance { min, max } => write_bounds(f, "trip_distance", *min, *max),
+            TripFilter::Index(index) => write!(f, "index = {}", index),
+            TripFilter::Destination(dest) => write!(f, "do_location_id = {}", dest),
+            TripFilter::PickupLocation(pickup) => write!(f, "pu_location_id = {}", pickup),
+            TripFilter::PickupTime(range) => {
+                write_bounds(f, "tpep_pickup_datetime", range.from, range.to)
+            }
+            TripFilter::DropoffTime(range) => {
+                write_bounds(f, "tpep_dropoff_datetime", range.from, range.to)
+            }
+            TripFilter::Vendor(vendor) => write!(f, "vendor_id = {}", vendor),
+            TripFilter::PaymentType(payment) => write!(f, "payment_type = {}", payment),
+            TripFilter::RateCode(rate) => write!(f, "ratecode_id = {}", rate),
+            TripFilter::StoreAndFwd(flag) => write!(f, "store_and_fwd_flag = {:?}", flag),
+            TripFilter::PassengerCount(count) => write!(f, "passenger_count = {}", count),
+            TripFilter::Passengers { min, max } => write_bounds(f, "passenger_count", *min, *max),
+            TripFilter::In(field, values) => {
+                let values: Vec<String> = values.iter().map(u64::to_string).collect();
+                write!(f, "{} in ({})", field.field_name(), values.join(", "))
             }
+            TripFilter::Not(filter) => match filter.as_ref() {
+                TripFilter::And(filters) | TripFilter::Or(filters) if !filters.is_empty() => {
+                    write!(f, "not {}", filter)
+                }
+                _ => write!(f, "not ({})", filter),
+            },
+            TripFilter::And(filters) if filters.is_empty() => write!(f, "true"),
+            TripFilter::Or(filters) if filters.is_empty() => write!(f, "false"),
+            TripFilter::And(filters) => join(f, filters, "and"),
+            TripFilter::Or(filters) => join(f, filters, "or"),
+        }
+    }
+}
+
+fn get_or_initialize_hash_table<P: AsRef<Path>>(
+    csv_path: P,
+) -> Result<Option<Arc<DiskHashTable>>, Box<dyn Error>> {
+    HASH_INIT.call_once(|| {
+        // main ya lo inicializa al arrancar, esto cubre usos sin pasar por main
+        if HASH_TABLE.load().is_some() {
+            return;
+        }
+        println!("Inicializando tabla hash en disco...");
+        if let Err(e) = initialize_hash_index(&csv_path) {
+            eprintln!("Error al inicializar tabla hash: {}", e);
         }
     });
 
-    Ok(&HASH_TABLE)
+    Ok(HASH_TABLE.load_full())
 }
 
-fn can_use_hash_index(filter: &TripFilter) -> Option<String> {
+// Las funciones de la versión nativa solo atajan el índice exacto, las consultas de la
+// API pasan por el planificador
+fn can_use_hash_index(filter: &TripFilter) -> Option<u64> {
     match filter {
-        TripFilter::Index(idx) => Some(idx.clone()),
+        TripFilter::Index(idx) => Some(*idx),
         TripFilter::And(filters) => {
             for f in filters {
                 if let TripFilter::Index(idx) = f {
-                    return Some(idx.clone());
+                    return Some(*idx);
                 }
             }
             None
@@ -110,6 +387,109 @@ fn can_use_hash_index(filter: &TripFilter) -> Option<String> {
     }
 }
 
+// Hojas que tienen un bitmap por valor
+fn bitmap_key(filter: &TripFilter) -> Option<(BitmapColumn, u32)> {
+    match filter {
+        TripFilter::Vendor(vendor) => Some((BitmapColumn::VendorId, *vendor)),
+        TripFilter::PaymentType(payment) => Some((BitmapColumn::PaymentType, *payment)),
+        TripFilter::RateCode(rate) => Some((BitmapColumn::RatecodeId, *rate)),
+        TripFilter::StoreAndFwd(flag) => Some((BitmapColumn::StoreAndFwdFlag, flag_value(*flag))),
+        TripFilter::PassengerCount(count) => Some((BitmapColumn::PassengerCount, *count)),
+        _ => None,
+    }
+}
+
+// Un árbol de And/Or/Not cuyas hojas tienen bitmap se resuelve entero sin leer
+// registros
+pub fn can_use_bitmap_index(filter: &TripFilter) -> bool {
+    match filter {
+        TripFilter::And(filters) | TripFilter::Or(filters) => {
+            filters.iter().all(can_use_bitmap_index)
+        }
+        TripFilter::Not(filter) => can_use_bitmap_index(filter),
+        TripFilter::Passengers { .. } => true,
+        TripFilter::In(field, _) => field.bitmap_column().is_some(),
+        _ => bitmap_key(filter).is_some(),
+    }
+}
+
+// Filas que cumplen el filtro. Un And vacío cumple con todo y un Or vacío con nada,
+// igual que en matches.
+pub fn bitmap_rows(filter: &TripFilter, bitmaps: &BitmapIndex) -> RoaringBitmap {
+    match filter {
+        TripFilter::And(filters) => filters
+            .iter()
+            .map(|f| bitmap_rows(f, bitmaps))
+            .reduce(|acc, rows| acc & rows)
+            .unwrap_or_else(|| bitmaps.live()),
+        TripFilter::Or(filters) => filters
+            .iter()
+            .map(|f| bitmap_rows(f, bitmaps))
+            .fold(RoaringBitmap::new(), |acc, rows| acc | rows),
+        TripFilter::Not(filter) => bitmaps.live() - bitmap_rows(filter, bitmaps),
+        TripFilter::Passengers { min, max } => bitmaps.rows_between(
+            BitmapColumn::PassengerCount,
+            min.unwrap_or(0),
+            max.unwrap_or(u32::MAX),
+        ),
+        TripFilter::In(field, values) => match field.bitmap_column() {
+            Some(column) => values
+                .iter()
+                .filter_map(|value| u32::try_from(*value).ok())
+                .fold(RoaringBitmap::new(), |acc, value| {
+                    acc | bitmaps.rows(column, value)
+                }),
+            None => RoaringBitmap::new(),
+        },
+        _ => bitmap_key(filter)
+            .map(|(column, value)| bitmaps.rows(column, value))
+            .unwrap_or_default(),
+    }
+}
+
+// Columna y rango de claves que debe cumplir cualquier viaje que pase la hoja, en el
+// espacio de claves de los mapas de zona
+fn zone_range(filter: &TripFilter) -> Option<(&'static str, KeyRange)> {
+    let exact = |field, value: u32| Some((field, KeyRange::exact(value as u64)));
+    match filter {
+        TripFilter::Amount { field, min, max } => {
+            Some((field.field_name(), KeyRange::amounts(*min, *max)))
+        }
+        TripFilter::Distance { min, max } => Some(("trip_distance", KeyRange::amounts(*min, *max))),
+        TripFilter::Index(index) => Some(("index", KeyRange::exact(*index))),
+        TripFilter::Destination(dest) => exact("do_location_id", *dest),
+        TripFilter::PickupLocation(pickup) => exact("pu_location_id", *pickup),
+        TripFilter::PickupTime(range) => Some(("tpep_pickup_datetime", KeyRange::times(range))),
+        TripFilter::DropoffTime(range) => Some(("tpep_dropoff_datetime", KeyRange::times(range))),
+        TripFilter::Vendor(vendor) => exact("vendor_id", *vendor),
+        TripFilter::PaymentType(payment) => exact("payment_type", *payment),
+        TripFilter::RateCode(rate) => exact("ratecode_id", *rate),
+        TripFilter::StoreAndFwd(flag) => exact("store_and_fwd_flag", flag_value(*flag)),
+        TripFilter::PassengerCount(count) => exact("passenger_count", *count),
+        TripFilter::Passengers { min, max } => Some((
+            "passenger_count",
+            KeyRange::keys(min.map(u64::from), max.map(u64::from)),
+        )),
+        // Los valores del conjunto caen entre el menor y el mayor
+        TripFilter::In(field, values) => Some((
+            field.field_name(),
+            KeyRange::keys(values.iter().min().copied(), values.iter().max().copied()),
+        )),
+        TripFilter::Not(_) | TripFilter::And(_) | TripFilter::Or(_) => None,
+    }
+}
+
+// Falso solo si los mínimos y máximos del grupo descartan que alguna fila cumpla
+fn group_may_match(filter: &TripFilter, group: &RowGroup) -> bool {
+    match filter {
+        TripFilter::And(filters) => filters.iter().all(|f| group_may_match(f, group)),
+        TripFilter::Or(filters) => filters.iter().any(|f| group_may_match(f, group)),
+        _ => zone_range(filter).is_none_or(|(field, range)| group.may_contain(field, &range)),
+    }
+}
+
+// Usadas por la version nativa, se conservan para futuros endpoints
+#[allow(dead_code)]
 pub fn filter_to_file<P: AsRef<Path>>(
     csv_path: P,
     output_file: P,
@@ -135,35 +515,34 @@ pub fn filter_to_file<P: AsRef<Path>>(
             "Usando índice hash para búsqueda rápida por índice: {}",
             index
         );
-        let hash_table_ref = get_or_initialize_hash_table(&csv_path)?;
-        if let Some(hash_table) = hash_table_ref.lock().unwrap().as_ref() {
-            if let Ok(Some(trip)) = hash_table.get(&index) {
-                if filter.matches(&trip) {
-                    writeln!(
-                        writer,
-                        "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
-                        trip.vendor_id,
-                        trip.tpep_pickup_datetime,
-                        trip.tpep_dropoff_datetime,
-                        trip.passenger_count,
-                        trip.trip_distance,
-                        trip.ratecode_id,
-                        trip.store_and_fwd_flag,
-                        trip.pu_location_id,
-                        trip.do_location_id,
-                        trip.payment_type,
-                        trip.fare_amount,
-                        trip.extra,
-                        trip.mta_tax,
-                        trip.tip_amount,
-                        trip.tolls_amount,
-                        trip.improvement_surcharge,
-                        trip.total_amount,
-                        trip.congestion_surcharge,
-                        trip.index
-                    )?;
-                    count = 1;
-                }
+        if let Some(hash_table) = get_or_initialize_hash_table(&csv_path)? {
+            if let Ok(Some(trip)) = hash_table.get(&index.to_string())
+                && filter.matches(&trip)
+            {
+                writeln!(
+                    writer,
+                    "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                    trip.vendor_id,
+                    trip.tpep_pickup_datetime,
+                    trip.tpep_dropoff_datetime,
+                    trip.passenger_count,
+                    trip.trip_distance,
+                    trip.ratecode_id,
+                    trip.store_and_fwd_flag,
+                    trip.pu_location_id,
+                    trip.do_location_id,
+                    trip.payment_type,
+                    trip.fare_amount,
+                    trip.extra,
+                    trip.mta_tax,
+                    trip.tip_amount,
+                    trip.tolls_amount,
+                    trip.improvement_surcharge,
+                    trip.total_amount,
+                    trip.congestion_surcharge,
+                    trip.index
+                )?;
+                count = 1;
             }
             writer.flush()?;
             return Ok(count);
@@ -172,43 +551,48 @@ pub fn filter_to_file<P: AsRef<Path>>(
 
     println!("Usando escaneo secuencial de CSV para filtrado");
 
-    super::data_lector::stream_process_csv(csv_path, |trip| {
-        if filter.matches(trip) {
-            writeln!(
-                writer,
-                "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
-                trip.vendor_id,
-                trip.tpep_pickup_datetime,
-                trip.tpep_dropoff_datetime,
-                trip.passenger_count,
-                trip.trip_distance,
-                trip.ratecode_id,
-                trip.store_and_fwd_flag,
-                trip.pu_location_id,
-                trip.do_location_id,
-                trip.payment_type,
-                trip.fare_amount,
-                trip.extra,
-                trip.mta_tax,
-                trip.tip_amount,
-                trip.tolls_amount,
-                trip.improvement_surcharge,
-                trip.total_amount,
-                trip.congestion_surcharge,
-                trip.index
-            )?;
+    super::data_lector::stream_process_pruned(
+        csv_path,
+        &filter.pickup_range(),
+        &FIELD_NAMES,
+        |trip| {
+            if filter.matches(trip) {
+                writeln!(
+                    writer,
+                    "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                    trip.vendor_id,
+                    trip.tpep_pickup_datetime,
+                    trip.tpep_dropoff_datetime,
+                    trip.passenger_count,
+                    trip.trip_distance,
+                    trip.ratecode_id,
+                    trip.store_and_fwd_flag,
+                    trip.pu_location_id,
+                    trip.do_location_id,
+                    trip.payment_type,
+                    trip.fare_amount,
+                    trip.extra,
+                    trip.mta_tax,
+                    trip.tip_amount,
+                    trip.tolls_amount,
+                    trip.improvement_surcharge,
+                    trip.total_amount,
+                    trip.congestion_surcharge,
+                    trip.index
+                )?;
 
-            count += 1;
+                count += 1;
 
-            if let Some(max) = max_results {
-                if count >= max {
+                if let Some(max) = max_results
+                    && count >= max
+                {
                     return Err("Límite de resultados alcanzado".into());
                 }
             }
-        }
 
-        Ok(())
-    })
+            Ok(())
+        },
+    )
     .or_else(|e| {
         if e.to_string() == "Límite de resultados alcanzado" {
             Ok(())
@@ -222,6 +606,7 @@ pub fn filter_to_file<P: AsRef<Path>>(
     Ok(count)
 }
 
+#[allow(dead_code)]
 pub fn get_filter_stats<P: AsRef<Path>>(
     csv_path: P,
     filter: TripFilter,
@@ -229,48 +614,59 @@ pub fn get_filter_stats<P: AsRef<Path>>(
     let mut stats = HashMap::new();
     let mut count = 0;
     let mut total_distance = 0.0;
-    let mut total_amount = 0.0;
-    let mut total_passengers = 0;
+    let mut total_amount = Decimal::ZERO;
+    let mut total_passengers: u64 = 0;
     if let Some(index) = can_use_hash_index(&filter) {
         println!("Usando índice hash para estadísticas por índice: {}", index);
 
-        let hash_table_ref = get_or_initialize_hash_table(&csv_path)?;
-        if let Some(hash_table) = hash_table_ref.lock().unwrap().as_ref() {
-            if let Ok(Some(trip)) = hash_table.get(&index) {
-                if filter.matches(&trip) {
-                    count = 1;
-                    total_distance = trip.trip_distance.parse::<f64>().unwrap_or(0.0);
-                    total_amount = trip.total_amount.parse::<f64>().unwrap_or(0.0);
-                    total_passengers = trip.passenger_count.parse::<i32>().unwrap_or(0);
-
-                    stats.insert("count".to_string(), count as f64);
-                    stats.insert("avg_distance".to_string(), total_distance);
-                    stats.insert("avg_amount".to_string(), total_amount);
-                    stats.insert("avg_passengers".to_string(), total_passengers as f64);
-                    stats.insert("total_amount".to_string(), total_amount);
-
-                    return Ok(stats);
-                }
-            }
+        if let Some(hash_table) = get_or_initialize_hash_table(&csv_path)?
+            && let Ok(Some(trip)) = hash_table.get(&index.to_string())
+            && filter.matches(&trip)
+        {
+            let amount = trip.total_amount.to_f64().unwrap_or(0.0);
+            stats.insert("count".to_string(), 1.0);
+            stats.insert("avg_distance".to_string(), trip.trip_distance);
+            stats.insert("avg_amount".to_string(), amount);
+            stats.insert("avg_passengers".to_string(), trip.passenger_count as f64);
+            stats.insert("total_amount".to_string(), amount);
+
+            return Ok(stats);
         }
     }
 
-    println!("Usando escaneo secuencial de CSV para estadísticas");
-
-    super::data_lector::stream_process_csv(csv_path, |trip| {
+    let mut fields = vec!["trip_distance", "total_amount", "passenger_count"];
+    filter.referenced_fields(&mut fields);
+    let mut accumulate = |trip: &Trip| {
         if filter.matches(trip) {
             count += 1;
-            total_distance += trip.trip_distance.parse::<f64>().unwrap_or(0.0);
-            total_amount += trip.total_amount.parse::<f64>().unwrap_or(0.0);
-            total_passengers += trip.passenger_count.parse::<i32>().unwrap_or(0);
+            total_distance += trip.trip_distance;
+            total_amount += trip.total_amount;
+            total_passengers += trip.passenger_count as u64;
         }
 
         Ok(())
-    })?;
+    };
+    if let Some(hash_table) = get_or_initialize_hash_table(&csv_path)? {
+        println!("Usando almacén columnar para estadísticas");
+        hash_table.scan_columns(
+            &fields,
+            |group| group_may_match(&filter, group),
+            |_, trip| accumulate(trip),
+        )?;
+    } else {
+        println!("Usando escaneo secuencial de CSV para estadísticas");
+        super::data_lector::stream_process_pruned(
+            csv_path,
+            &filter.pickup_range(),
+            &fields,
+            accumulate,
+        )?;
+    }
 
     stats.insert("count".to_string(), count as f64);
 
     if count > 0 {
+        let total_amount = total_amount.to_f64().unwrap_or(0.0);
         stats.insert("avg_distance".to_string(), total_distance / count as f64);
         stats.insert("avg_amount".to_string(), total_amount / count as f64);
         stats.insert(
@@ -283,21 +679,40 @@ pub fn get_filter_stats<P: AsRef<Path>>(
     Ok(stats)
 }
 
+#[allow(dead_code)]
 pub fn get_popular_destinations<P: AsRef<Path>>(
     csv_path: P,
+    filter: Option<TripFilter>,
     limit: usize,
-) -> Result<Vec<(String, usize)>, Box<dyn Error>> {
-    let mut dest_counts: HashMap<String, usize> = HashMap::new();
+) -> Result<Vec<(u32, usize)>, Box<dyn Error>> {
+    let mut dest_counts: HashMap<u32, usize> = HashMap::new();
 
-    super::data_lector::stream_process_csv(csv_path, |trip| {
-        let dest = &trip.do_location_id;
-        *dest_counts.entry(dest.clone()).or_insert(0) += 1;
+    let mut fields = vec!["do_location_id"];
+    let mut range = TimeRange::default();
+    if let Some(filter) = &filter {
+        filter.referenced_fields(&mut fields);
+        range = filter.pickup_range();
+    }
+    let mut count_destination = |trip: &Trip| {
+        if filter.as_ref().is_none_or(|f| f.matches(trip)) {
+            *dest_counts.entry(trip.do_location_id).or_insert(0) += 1;
+        }
 
         Ok(())
-    })?;
+    };
+    if let Some(hash_table) = get_or_initialize_hash_table(&csv_path)? {
+        println!("Usando almacén columnar para destinos populares");
+        hash_table.scan_columns(
+            &fields,
+            |group| filter.as_ref().is_none_or(|f| group_may_match(f, group)),
+            |_, trip| count_destination(trip),
+        )?;
+    } else {
+        super::data_lector::stream_process_pruned(csv_path, &range, &fields, count_destination)?;
+    }
 
-    let mut dest_vec: Vec<(String, usize)> = dest_counts.into_iter().collect();
-    dest_vec.sort_by(|a, b| b.1.cmp(&a.1));
+    let mut dest_vec: Vec<(u32, usize)> = dest_counts.into_iter().collect();
+    dest_vec.sort_by_key(|d| std::cmp::Reverse(d.1));
 
     // Limitar resultados
     let result = dest_vec.into_iter().take(limit).collect();
@@ -305,81 +720,480 @@ pub fn get_popular_destinations<P: AsRef<Path>>(
     Ok(result)
 }
 
+fn hash_dir() -> PathBuf {
+    PathBuf::from(&config::get().index_dir)
+}
+
 pub fn initialize_hash_index<P: AsRef<Path>>(csv_path: P) -> Result<usize, Box<dyn Error>> {
-    println!("Inicializando índice hash manualmente...");
-    let hash_path = PathBuf::from(HASH_DIR);
+    println!("Inicializando índice hash...");
+    let hash_path = hash_dir();
 
-    if hash_path.exists() {
-        println!("Eliminando índice hash existente...");
-        fs::remove_dir_all(&hash_path)?;
+    let dataset = Dataset::open(&csv_path)?;
+    let partitions = dataset.partitions()?;
+    println!("Dataset con {} particiones:", partitions.len());
+    for partition in &partitions {
+        println!(
+            "  {} ({}): {} filas, pickup {:?} a {:?}",
+            partition.path.display(),
+            partition.month.as_deref().unwrap_or("sin mes"),
+            partition.rows,
+            partition.min_pickup,
+            partition.max_pickup
+        );
     }
 
-    fs::create_dir_all(&hash_path)?;
-
-    println!("Construyendo nuevo índice hash...");
+    // Una reconstrucción no frena a los lectores, siguen con la versión publicada
+    let _writer = WRITE_LOCK.lock().unwrap();
+    recover_index_dir(&hash_path)?;
 
-    let csv_path_str = csv_path.as_ref().to_string_lossy().to_string();
-    let hash_path_str = hash_path.to_string_lossy().to_string();
+    // Solo se reconstruye si el dataset o el formato cambiaron desde la última carga, salvo
+    // que la configuración pida otra cosa
+    let check = match (
+        config::get().rebuild_policy,
+        IndexManifest::check(&hash_path, &dataset)?,
+    ) {
+        (RebuildPolicy::Always, _) => IndexCheck::Rebuild("rebuild_policy = always".to_string()),
+        (RebuildPolicy::Never, IndexCheck::Rebuild(reason)) => {
+            return Err(format!(
+                "El índice hash necesita reconstruirse ({}) y rebuild_policy = never",
+                reason
+            )
+            .into());
+        }
+        (_, check) => check,
+    };
+    let count = match check {
+        IndexCheck::Reuse(manifest) => {
+            println!(
+                "Reutilizando índice hash existente: {} registros, construido el {}",
+                manifest.row_count, manifest.built_at
+            );
+            let replayed = wal::replay(&hash_path)?;
+            if replayed > 0 {
+                println!("{} escrituras del WAL reaplicadas", replayed);
+            }
+            manifest.row_count as usize
+        }
+        IndexCheck::Rebuild(reason) => {
+            // El índice actual sigue en su lugar hasta que el nuevo esté completo
+            println!("Reconstruyendo índice hash: {}", reason);
+            let csv_path_str = csv_path.as_ref().to_string_lossy().to_string();
+            let hash_path_str = hash_path.to_string_lossy().to_string();
+            DiskHashTable::build_hash_table_from_csv(&csv_path_str, &hash_path_str)?
+        }
+    };
 
-    let count = DiskHashTable::build_hash_table_from_csv(&csv_path_str, &hash_path_str)?;
     let hash_table = DiskHashTable::new(&hash_path)?;
-    let mut table_ref = HASH_TABLE.lock().unwrap();
-    *table_ref = Some(hash_table);
+    HASH_TABLE.store(Some(Arc::new(hash_table)));
 
     println!("Índice hash inicializado con {} registros", count);
 
     Ok(count)
 }
 
-/**
+// Agrega un viaje nuevo al índice hash, false si su índice ya existe. Igual que las
+// actualizaciones, vive en el índice y no en el dataset fuente.
+pub fn insert_trip<P: AsRef<Path>>(csv_path: P, trip: &Trip) -> Result<bool, Box<dyn Error>> {
+    commit_write(csv_path, TripWrite::Insert(trip.clone()))
+}
+
+// Actualiza el viaje en el índice hash. Los cambios viven en el índice, el dataset
+// fuente no se modifica.
+pub fn update_trip_by_index<P: AsRef<Path>>(
+    csv_path: P,
+    index: u64,
+    trip: &Trip,
+) -> Result<bool, Box<dyn Error>> {
+    commit_write(csv_path, TripWrite::Update(index, trip.clone()))
+}
+
+pub fn delete_trip_by_index<P: AsRef<Path>>(
+    csv_path: P,
+    index: u64,
+) -> Result<bool, Box<dyn Error>> {
+    commit_write(csv_path, TripWrite::Delete(index))
+}
+
+// Escritura pedida por un cliente, todavía con su condición: insertar solo si la clave
+// no existe, actualizar o borrar solo si existe
+enum TripWrite {
+    Insert(Trip),
+    Update(u64, Trip),
+    Delete(u64),
+}
+
+struct PendingWrite {
+    write: TripWrite,
+    done: Sender<Result<bool, String>>,
+}
+
+// Encola la escritura y espera su resultado. Quien consigue el candado de escritura
+// confirma todo lo encolado hasta ese momento con un solo fsync del WAL (group commit);
+// los que llegaron mientras tanto encuentran su resultado listo al conseguir el
+// candado.
+fn commit_write<P: AsRef<Path>>(csv_path: P, write: TripWrite) -> Result<bool, Box<dyn Error>> {
+    get_or_initialize_hash_table(&csv_path)?;
+    let (done, result) = mpsc::channel();
+    COMMIT_QUEUE
+        .lock()
+        .unwrap()
+        .push(PendingWrite { write, done });
+
+    let _writer = WRITE_LOCK.lock().unwrap();
+    if let Ok(result) = result.try_recv() {
+        return result.map_err(Into::into);
+    }
+    let batch = std::mem::take(&mut *COMMIT_QUEUE.lock().unwrap());
+    let results = match commit_batch(&batch) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("Error al confirmar {} escrituras: {}", batch.len(), e);
+            // Lo que haya llegado al WAL se reaplica; el cliente recibe el error igual
+            if let Err(e) = reopen_hash_index() {
+                eprintln!("Error al reabrir el índice hash: {}", e);
+            }
+            vec![Err(e.to_string()); batch.len()]
+        }
+    };
+    for (pending, result) in batch.iter().zip(results) {
+        let _ = pending.done.send(result);
+    }
+    result.recv()?.map_err(Into::into)
+}
+
+// Un lote: se deciden las condiciones contra la versión publicada, se registran en el
+// WAL las escrituras que proceden y recién con el WAL en disco se aplican sobre una
+// copia, se guardan los archivos del índice y se publica la copia.
+fn commit_batch(batch: &[PendingWrite]) -> Result<Vec<Result<bool, String>>, Box<dyn Error>> {
+    let hash_path = hash_dir();
+    let current = HASH_TABLE
+        .load_full()
+        .ok_or("Índice hash no inicializado")?;
+
+    // Claves tocadas antes en el mismo lote: true si quedaron vivas
+    let mut live: HashMap<String, bool> = HashMap::new();
+    let mut entries = Vec::new();
+    let mut results = Vec::with_capacity(batch.len());
+    let mut lsn = current.wal_lsn();
+    for pending in batch {
+        let (key, trip) = match &pending.write {
+            TripWrite::Insert(trip) => (trip.index.to_string(), Some(trip)),
+            TripWrite::Update(index, trip) => (index.to_string(), Some(trip)),
+            TripWrite::Delete(index) => (index.to_string(), None),
+        };
+        let exists = match live.get(&key) {
+            Some(&exists) => exists,
+            None => current.get(&key)?.is_some(),
+        };
+        let applies = match pending.write {
+            TripWrite::Insert(_) => !exists,
+            TripWrite::Update(..) | TripWrite::Delete(_) => exists,
+        };
+        results.push(Ok(applies));
+        if applies {
+            live.insert(key.clone(), trip.is_some());
+            lsn += 1;
+            entries.push(WalEntry {
+                lsn,
+                key,
+                trip: trip.cloned(),
+            });
+        }
+    }
+    if entries.is_empty() {
+        return Ok(results);
+    }
+
+    wal::append(&hash_path, &entries)?;
+    let mut hash_table = DiskHashTable::clone(&current);
+    for entry in entries {
+        match entry.trip {
+            Some(trip) => hash_table.insert(entry.key, trip)?,
+            None => {
+                hash_table.delete(&entry.key)?;
+            }
+        }
+    }
+    hash_table.set_wal_lsn(lsn);
+    hash_table.sync()?;
+    wal::maybe_truncate(&hash_path)?;
+
+    let needs_compaction = hash_table.needs_compaction();
+    HASH_TABLE.store(Some(Arc::new(hash_table)));
+    if needs_compaction {
+        spawn_compaction();
+    }
+    Ok(results)
+}
+
+// Después de un lote fallido la versión publicada puede no coincidir con trip_data.bin.
+// Se vuelve a abrir desde disco reaplicando el WAL.
+fn reopen_hash_index() -> Result<(), Box<dyn Error>> {
+    let hash_path = hash_dir();
+    wal::replay(&hash_path)?;
+    HASH_TABLE.store(Some(Arc::new(DiskHashTable::new(&hash_path)?)));
+    Ok(())
+}
+
+// Filas por lote al reponer desde el CSV, para no cargar todo el WAL de una vez
+const RESTORE_BATCH: usize = 10_000;
+
+// Vuelve a insertar las filas del CSV que fsck no encontró en el índice. Pasan por el
+// WAL y commit_batch como las escrituras de la API; quien llama ya tiene WRITE_LOCK.
+// Devuelve cuántas se insertaron.
+pub fn restore_trips(trips: Vec<Trip>) -> Result<usize, Box<dyn Error>> {
+    // La reparación pudo cambiar los archivos por debajo de la versión publicada
+    HASH_TABLE.store(Some(Arc::new(DiskHashTable::new(hash_dir())?)));
+    let (done, _) = mpsc::channel();
+    let mut restored = 0;
+    for chunk in trips.chunks(RESTORE_BATCH) {
+        let batch: Vec<PendingWrite> = chunk
+            .iter()
+            .map(|trip| PendingWrite {
+                write: TripWrite::Insert(trip.clone()),
+                done: done.clone(),
+            })
+            .collect();
+        for result in commit_batch(&batch)? {
+            if result? {
+                restored += 1;
+            }
+        }
+    }
+    Ok(restored)
+}
+
+// Lanza la compactación en un hilo aparte si no hay otra en curso. Las lecturas nunca
+// esperan; las escrituras solo durante el cambio final de archivos.
+pub fn spawn_compaction() -> bool {
+    if COMPACTING.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+    thread::spawn(|| {
+        if let Err(e) = compact_hash_index() {
+            eprintln!("Error al compactar el índice hash: {}", e);
+        }
+        COMPACTING.store(false, Ordering::Release);
+    });
+    true
+}
+
+fn compact_hash_index() -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    let hash_path = hash_dir();
+    let staging_dir = staging_dir(&hash_path);
+    if BuildCheckpoint::load(&staging_dir)?.is_some() {
+        return Err("hay una construcción del índice sin terminar en staging".into());
+    }
+    if staging_dir.exists() {
+        fs::remove_dir_all(&staging_dir)?;
+    }
+
+    // La versión publicada sirve de foto: el log solo crece y ella no cambia
+    let Some(snapshot) = HASH_TABLE.load_full() else {
+        return Ok(());
+    };
+    println!("Compactando índice hash...");
+    let builder = snapshot.copy_live(&staging_dir)?;
+
+    let _writer = WRITE_LOCK.lock().unwrap();
+    let current = HASH_TABLE
+        .load_full()
+        .ok_or("Índice hash no inicializado")?;
+    let (compacted, freed) = current.finish_compaction(builder, &snapshot)?;
+    HASH_TABLE.store(Some(Arc::new(compacted)));
+    println!(
+        "Índice hash compactado: {} bytes recuperados en {:?}",
+        freed,
+        start.elapsed()
+    );
+    Ok(())
+}
+
+// Verifica el índice en disco y, si se pide, lo repara. Mientras tanto no hay
+// escrituras; los lectores siguen con la versión publicada y pasan a la reparada al
+// terminar.
+pub fn check_hash_index<P: AsRef<Path>>(
+    csv_path: P,
+    repair: bool,
+) -> Result<FsckReport, Box<dyn Error>> {
+    let hash_path = hash_dir();
+    let _writer = WRITE_LOCK.lock().unwrap();
+    if repair && COMPACTING.load(Ordering::Acquire) {
+        return Err("hay una compactación en curso, reintentar al terminar".into());
+    }
+    let report = fsck::run(&hash_path, csv_path.as_ref(), repair)?;
+    if !report.repaired.is_empty() && HASH_TABLE.load().is_some() {
+        HASH_TABLE.store(Some(Arc::new(DiskHashTable::new(&hash_path)?)));
+    }
+    Ok(report)
+}
+
+/*
 * Estas funciones si funcionan para la API, las de arriba funcionaban para la version nativa.
 * Es importante resaltar que claramente habra un menor rendimiento ya que debe precargar los datos
 * en ram para que asi sean entregados a traves de la API. En todo caso mantiene el uso de la
 * paginacion para aligerar el rendimiento.
 * */
 
+// Posiciones de los registros que entrega un camino por índice, en orden del archivo
+fn path_positions(table: &DiskHashTable, path: &AccessPath) -> Result<Vec<u64>, Box<dyn Error>> {
+    let mut positions = match path {
+        AccessPath::FullScan => return Err("El escaneo no tiene lista de posiciones".into()),
+        AccessPath::PrimaryKeys(keys) => {
+            let mut positions = Vec::with_capacity(keys.len());
+            for key in keys {
+                positions.extend(table.position_of(&key.to_string())?);
+            }
+            positions
+        }
+        AccessPath::Location(column, values) => values
+            .iter()
+            .flat_map(|value| table.locations().positions(*column, *value))
+            .collect(),
+        AccessPath::Range(column, range) => {
+            let count = table.ranges().count(*column, range);
+            table.ranges().positions(*column, range, 0, count)
+        }
+        AccessPath::Bitmap(rows) => rows
+            .iter()
+            .map(|row| {
+                table
+                    .bitmaps()
+                    .position(row)
+                    .ok_or("Fila fuera del índice de bitmaps")
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    positions.sort_unstable();
+    positions.dedup();
+    Ok(positions)
+}
+
+// Llama a `process` con cada viaje vivo que cumple el filtro del plan, en orden del
+// archivo. El escaneo lee solo las columnas de `fields`; los caminos por índice leen
+// completos los registros candidatos y les aplican el filtro residual.
+fn for_each_match(
+    table: &DiskHashTable,
+    plan: &QueryPlan,
+    fields: &[&str],
+    mut process: impl FnMut(u64, &Trip),
+) -> Result<(), Box<dyn Error>> {
+    let filter = &plan.filter;
+    if let AccessPath::FullScan = plan.chosen.path {
+        return table.scan_columns(
+            fields,
+            |group| group_may_match(filter, group),
+            |position, trip| {
+                if filter.matches(trip) {
+                    process(position, trip);
+                }
+                Ok(())
+            },
+        );
+    }
+    for position in path_positions(table, &plan.chosen.path)? {
+        if let Some(trip) = table.read_at(position)?
+            && filter.matches(&trip)
+        {
+            process(position, &trip);
+        }
+    }
+    Ok(())
+}
+
+// Viajes de la página y, si se pidió, el plan con el que se obtuvieron. Con
+// ExplainMode::Only no se ejecuta nada y solo va el plan.
+pub type SearchResult = (Option<PagedResult<Trip>>, Option<Explain>);
+
+// Sin índice no hay plan que mostrar
+fn csv_scan_explain(explain: ExplainMode) -> Result<(), Box<dyn Error>> {
+    match explain {
+        ExplainMode::Off => Ok(()),
+        _ => Err("Sin índice no hay plan, la consulta recorre el CSV".into()),
+    }
+}
+
+// Los resultados van siempre en el orden del archivo, sea cual sea el camino elegido
 pub fn filter_with_pagination<P: AsRef<Path>>(
     csv_path: P,
     filter: TripFilter,
     pagination: Pagination,
-) -> Result<PagedResult<Trip>, Box<dyn Error>> {
+    explain: ExplainMode,
+) -> Result<SearchResult, Box<dyn Error>> {
     let start = Instant::now();
-    let mut all_matches = Vec::new();
-    let mut total_count = 0;
-
-    if let Some(index) = can_use_hash_index(&filter) {
-        println!(
-            "Usando índice hash para búsqueda rápida por índice: {}",
-            index
-        );
-        let hash_table_ref = get_or_initialize_hash_table(&csv_path)?;
-
-        if let Some(hash_table) = hash_table_ref.lock().unwrap().as_ref() {
-            if let Ok(Some(trip)) = hash_table.get(&index) {
-                if filter.matches(&trip) {
-                    total_count = 1;
+    let skip = pagination.offset();
+    let take = pagination.per_page;
 
-                    if pagination.page == 1 {
-                        all_matches.push(trip.clone());
+    if let Some(hash_table) = get_or_initialize_hash_table(&csv_path)? {
+        let plan = planner::plan(filter, &hash_table, take, None);
+        let explained = (explain != ExplainMode::Off).then(|| plan.explain());
+        if explain == ExplainMode::Only {
+            return Ok((None, explained));
+        }
+        println!("Plan elegido: {}", plan.describe());
+        let (total_count, all_matches) = match (&plan.chosen.path, plan.chosen.exact) {
+            // Los índices que responden el filtro entero cuentan sin leer registros y
+            // leen solo la página. Una lista de ubicaciones ya está en orden del
+            // archivo; el índice de rango está ordenado por valor, así que sus
+            // posiciones se reordenan antes de paginar.
+            (AccessPath::Location(column, values), true) if values.len() == 1 => {
+                hash_table.find_by_location(*column, values[0], skip, take)?
+            }
+            (AccessPath::Bitmap(rows), true) => (
+                rows.len() as usize,
+                hash_table.find_by_rows(rows, skip, take)?,
+            ),
+            (AccessPath::Location(..) | AccessPath::Range(..), true) => {
+                let positions = path_positions(&hash_table, &plan.chosen.path)?;
+                let mut page = Vec::with_capacity(take);
+                for position in positions.iter().skip(skip).take(take) {
+                    page.extend(hash_table.read_at(*position)?);
+                }
+                (positions.len(), page)
+            }
+            _ => {
+                // Una sola pasada; de la página solo se guardan las posiciones y después
+                // se leen los viajes completos
+                let mut fields = Vec::new();
+                plan.filter.referenced_fields(&mut fields);
+                let mut total_count = 0;
+                let mut page = Vec::with_capacity(take);
+                for_each_match(&hash_table, &plan, &fields, |position, _| {
+                    if total_count >= skip && total_count < skip.saturating_add(take) {
+                        page.push(position);
                     }
+                    total_count += 1;
+                })?;
+                let mut all_matches = Vec::with_capacity(page.len());
+                for position in page {
+                    all_matches.extend(hash_table.read_at(position)?);
                 }
+                (total_count, all_matches)
             }
+        };
 
-            return Ok(PagedResult::new(
+        return Ok((
+            Some(PagedResult::new(
                 all_matches,
                 total_count,
                 &pagination,
                 start.elapsed(),
-            ));
-        }
+            )),
+            explained,
+        ));
     }
+    csv_scan_explain(explain)?;
 
-    println!("Usando escaneo secuencial de CSV para filtrado con paginación");
-
-    let skip = (pagination.page - 1) * pagination.per_page;
-    let take = pagination.per_page;
+    // El conteo solo necesita los campos del filtro
+    let range = filter.pickup_range();
+    let mut fields = Vec::new();
+    filter.referenced_fields(&mut fields);
+    let mut all_matches = Vec::new();
+    let mut total_count = 0;
 
-    super::data_lector::stream_process_csv(csv_path.as_ref(), |trip| {
+    println!("Usando escaneo secuencial de CSV para filtrado con paginación");
+    super::data_lector::stream_process_pruned(csv_path.as_ref(), &range, &fields, |trip| {
         if filter.matches(trip) {
             total_count += 1;
         }
@@ -387,11 +1201,11 @@ pub fn filter_with_pagination<P: AsRef<Path>>(
     })?;
 
     let mut current_index = 0;
-    super::data_lector::stream_process_csv(csv_path.as_ref(), |trip| {
+    super::data_lector::stream_process_pruned(csv_path.as_ref(), &range, &FIELD_NAMES, |trip| {
         if filter.matches(trip) {
-            if current_index >= skip && current_index < skip + take {
+            if current_index >= skip && current_index < skip.saturating_add(take) {
                 all_matches.push(trip.clone());
-            } else if current_index >= skip + take {
+            } else if current_index >= skip.saturating_add(take) {
                 return Err("Página completada".into());
             }
             current_index += 1;
@@ -406,11 +1220,127 @@ pub fn filter_with_pagination<P: AsRef<Path>>(
         }
     })?;
 
-    Ok(PagedResult::new(
-        all_matches,
-        total_count,
-        &pagination,
-        start.elapsed(),
+    Ok((
+        Some(PagedResult::new(
+            all_matches,
+            total_count,
+            &pagination,
+            start.elapsed(),
+        )),
+        None,
+    ))
+}
+
+// Orden de los resultados por un campo de FIELD_NAMES
+#[derive(Debug, Clone, Copy)]
+pub struct SortOrder {
+    pub field: usize,
+    pub descending: bool,
+}
+
+// Como filter_with_pagination pero ordenado. Hay que ver todas las coincidencias antes
+// de saber cuáles van en la página, así que de cada una se guarda solo la clave del
+// campo y la posición, y se leen completos los viajes de la página. Los valores que no
+// son números finitos van al final en los dos sentidos; los empates quedan en el orden
+// del archivo.
+pub fn filter_sorted_with_pagination<P: AsRef<Path>>(
+    csv_path: P,
+    filter: TripFilter,
+    sort: SortOrder,
+    pagination: Pagination,
+    explain: ExplainMode,
+) -> Result<SearchResult, Box<dyn Error>> {
+    let start = Instant::now();
+    let skip = pagination.offset();
+    let take = pagination.per_page;
+    let compare = |a: &Option<u64>, b: &Option<u64>| match (a, b) {
+        (Some(a), Some(b)) if sort.descending => b.cmp(a),
+        (Some(a), Some(b)) => a.cmp(b),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    };
+
+    if let Some(hash_table) = get_or_initialize_hash_table(&csv_path)? {
+        let plan = planner::plan(filter, &hash_table, take, Some(sort));
+        let explained = (explain != ExplainMode::Off).then(|| plan.explain());
+        if explain == ExplainMode::Only {
+            return Ok((None, explained));
+        }
+        println!(
+            "Plan elegido para filtrado ordenado por {}: {}",
+            FIELD_NAMES[sort.field],
+            plan.describe()
+        );
+        // El índice de rango ya está ordenado por (valor, posición), que es justo el
+        // orden ascendente con los empates en el orden del archivo
+        if let (AccessPath::Range(column, range), true) = (&plan.chosen.path, plan.chosen.exact)
+            && planner::follows_order(&plan.chosen.path, Some(sort))
+        {
+            let (total_count, page) = hash_table.find_by_range(*column, range, skip, take)?;
+            return Ok((
+                Some(PagedResult::new(
+                    page,
+                    total_count,
+                    &pagination,
+                    start.elapsed(),
+                )),
+                explained,
+            ));
+        }
+        let mut fields = vec![FIELD_NAMES[sort.field]];
+        plan.filter.referenced_fields(&mut fields);
+        let mut matches = Vec::new();
+        for_each_match(&hash_table, &plan, &fields, |position, trip| {
+            matches.push((zone_key(sort.field, trip), position));
+        })?;
+        matches.sort_by(|a, b| compare(&a.0, &b.0).then(a.1.cmp(&b.1)));
+        let mut page = Vec::with_capacity(take);
+        for (_, position) in matches.iter().skip(skip).take(take) {
+            page.extend(hash_table.read_at(*position)?);
+        }
+
+        return Ok((
+            Some(PagedResult::new(
+                page,
+                matches.len(),
+                &pagination,
+                start.elapsed(),
+            )),
+            explained,
+        ));
+    }
+    csv_scan_explain(explain)?;
+
+    println!("Usando escaneo secuencial de CSV para filtrado ordenado");
+    let mut matches = Vec::new();
+    super::data_lector::stream_process_pruned(
+        csv_path.as_ref(),
+        &filter.pickup_range(),
+        &FIELD_NAMES,
+        |trip| {
+            if filter.matches(trip) {
+                matches.push((zone_key(sort.field, trip), trip.clone()));
+            }
+            Ok(())
+        },
+    )?;
+    // sort_by es estable, los empates quedan en el orden del archivo
+    matches.sort_by(|a, b| compare(&a.0, &b.0));
+    let total_count = matches.len();
+    let page = matches
+        .into_iter()
+        .skip(skip)
+        .take(take)
+        .map(|(_, trip)| trip)
+        .collect();
+
+    Ok((
+        Some(PagedResult::new(
+            page,
+            total_count,
+            &pagination,
+            start.elapsed(),
+        )),
+        None,
     ))
 }
 
@@ -418,9 +1348,12 @@ pub fn get_trip_by_index<P: AsRef<Path>>(
     csv_path: P,
     index: &str,
 ) -> Result<Option<Trip>, Box<dyn Error>> {
-    let hash_table_ref = get_or_initialize_hash_table(&csv_path)?;
-    if let Some(hash_table) = hash_table_ref.lock().unwrap().as_ref() {
-        return hash_table.get(index);
+    // Los índices son enteros, cualquier otra cosa no puede existir
+    let Ok(index) = index.parse::<u64>() else {
+        return Ok(None);
+    };
+    if let Some(hash_table) = get_or_initialize_hash_table(&csv_path)? {
+        return hash_table.get(&index.to_string());
     }
     let mut result = None;
     super::data_lector::stream_process_csv(csv_path, |trip| {
@@ -440,3 +1373,297 @@ pub fn get_trip_by_index<P: AsRef<Path>>(
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::bitmap_index::BitmapBuilder;
+    use crate::data::column_store::{ColumnBuilder, ColumnStore};
+    use crate::data::record_codec::encode_trip;
+    use chrono::{NaiveDate, NaiveDateTime, TimeDelta};
+    use std::cell::RefCell;
+
+    // Directorio vacío y propio de cada prueba
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("filters-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn at(day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+    }
+
+    fn window(from: NaiveDateTime, to: NaiveDateTime) -> TimeRange {
+        TimeRange {
+            from: Some(from),
+            to: Some(to),
+        }
+    }
+
+    fn total(min: Option<f64>, max: Option<f64>) -> TripFilter {
+        TripFilter::Amount {
+            field: AmountField::Total,
+            min,
+            max,
+        }
+    }
+
+    // Un viaje cada 30 segundos desde el 1 de enero, así los grupos de filas cubren
+    // ventanas de tiempo distintas. Los importes altos solo aparecen al final.
+    fn trips() -> Vec<Trip> {
+        (0..70_000u64)
+            .map(|i| {
+                let pickup = at(1, 0) + TimeDelta::seconds(i as i64 * 30);
+                Trip {
+                    index: i + 1,
+                    vendor_id: (i % 2 + 1) as u32,
+                    tpep_pickup_datetime: pickup,
+                    tpep_dropoff_datetime: pickup + TimeDelta::minutes(15),
+                    passenger_count: (i % 6) as u32,
+                    trip_distance: if i % 997 == 0 {
+                        f64::NAN
+                    } else {
+                        i as f64 / 1000.0
+                    },
+                    do_location_id: (i % 265) as u32,
+                    total_amount: if i >= 66_000 {
+                        Decimal::new(10_000 + i as i64, 2)
+                    } else {
+                        Decimal::new((i % 5000) as i64, 2)
+                    },
+                    ..Trip::default()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn zone_pruning_never_drops_a_group_with_matching_rows() {
+        let dir = test_dir("zones");
+        let trips = trips();
+        let mut builder = ColumnBuilder::create(&dir).unwrap();
+        let mut bytes = Vec::new();
+        for trip in &trips {
+            bytes.clear();
+            encode_trip(trip, &mut bytes);
+            builder.add(&bytes, trip).unwrap();
+        }
+        builder.finish().unwrap();
+
+        // Grupos del almacén y primera fila de cada uno
+        let store = ColumnStore::open(&dir).unwrap();
+        let groups = RefCell::new(Vec::new());
+        let mut starts = Vec::new();
+        store
+            .scan(
+                &["index"],
+                |group| {
+                    groups.borrow_mut().push(group.clone());
+                    true
+                },
+                |row, _| {
+                    if starts.len() < groups.borrow().len() {
+                        starts.push(row as usize);
+                    }
+                    Ok(())
+                },
+            )
+            .unwrap();
+        let groups = groups.into_inner();
+        assert_eq!(groups.len(), 2);
+        starts.push(trips.len());
+
+        let late = window(at(24, 10), at(24, 11));
+        let filters = [
+            TripFilter::PickupTime(late),
+            TripFilter::PickupTime(TimeRange {
+                from: Some(at(23, 0)),
+                to: None,
+            }),
+            TripFilter::DropoffTime(window(at(23, 14), at(23, 20))),
+            total(Some(100.0), None),
+            total(Some(12.34), Some(12.34)),
+            TripFilter::Distance {
+                min: None,
+                max: Some(0.5),
+            },
+            TripFilter::Distance {
+                min: Some(69.9),
+                max: None,
+            },
+            TripFilter::Or(vec![
+                TripFilter::PickupTime(window(at(2, 8), at(2, 9))),
+                total(Some(100.0), None),
+            ]),
+            TripFilter::And(vec![
+                TripFilter::Destination(7),
+                TripFilter::PickupTime(late),
+            ]),
+            TripFilter::In(KeyField::Destination, vec![3, 264]),
+            TripFilter::Passengers {
+                min: Some(5),
+                max: None,
+            },
+            TripFilter::Not(Box::new(total(Some(100.0), None))),
+            TripFilter::Index(65_600),
+        ];
+        for filter in &filters {
+            for (group, bounds) in groups.iter().zip(starts.windows(2)) {
+                let matching = trips[bounds[0]..bounds[1]]
+                    .iter()
+                    .any(|trip| filter.matches(trip));
+                assert!(
+                    !matching || group_may_match(filter, group),
+                    "el grupo que empieza en la fila {} se descartó con {}",
+                    bounds[0],
+                    filter
+                );
+            }
+        }
+
+        // Y los mapas de zona sí descartan grupos cuando corresponde
+        assert!(!group_may_match(&TripFilter::PickupTime(late), &groups[0]));
+        assert!(!group_may_match(&total(Some(100.0), None), &groups[0]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Una página enorme llega sin tope desde la query string y desde el DSL
+    // Cada combinación tiene que dar las mismas filas que evaluar el filtro viaje por
+    // viaje, también con filas retiradas y agregadas después de construir
+    #[test]
+    fn bitmap_trees_resolve_like_matches() {
+        let dir = test_dir("bitmaps");
+        let mut rows: Vec<Option<Trip>> = trips()
+            .into_iter()
+            .take(24)
+            .enumerate()
+            .map(|(i, trip)| {
+                Some(Trip {
+                    payment_type: (i % 3) as u32,
+                    store_and_fwd_flag: if i % 4 == 0 {
+                        StoreAndFwdFlag::Y
+                    } else {
+                        StoreAndFwdFlag::N
+                    },
+                    ..trip
+                })
+            })
+            .collect();
+        let mut builder = BitmapBuilder::default();
+        for (row, trip) in rows.iter().enumerate() {
+            builder
+                .add(row as u64 * 100, trip.as_ref().unwrap())
+                .unwrap();
+        }
+        builder.write(&dir).unwrap();
+
+        let mut bitmaps = BitmapIndex::open(&dir).unwrap();
+        for row in [1, 6] {
+            let old = rows[row].take().unwrap();
+            bitmaps
+                .record_change(Some((row as u64 * 100, &old)), None)
+                .unwrap();
+        }
+        let updated = Trip {
+            vendor_id: 2,
+            passenger_count: 3,
+            ..rows[2].clone().unwrap()
+        };
+        bitmaps
+            .record_change(
+                Some((200, rows[2].as_ref().unwrap())),
+                Some((5000, &updated)),
+            )
+            .unwrap();
+        rows[2] = None;
+        rows.push(Some(updated));
+
+        let vendor = |v| TripFilter::Vendor(v);
+        let filters = [
+            TripFilter::And(vec![vendor(2), TripFilter::PaymentType(1)]),
+            TripFilter::Or(vec![vendor(1), TripFilter::StoreAndFwd(StoreAndFwdFlag::Y)]),
+            TripFilter::Not(Box::new(vendor(1))),
+            TripFilter::And(vec![
+                TripFilter::Not(Box::new(TripFilter::PaymentType(0))),
+                TripFilter::Or(vec![
+                    TripFilter::Passengers {
+                        min: Some(2),
+                        max: Some(3),
+                    },
+                    TripFilter::In(KeyField::PassengerCount, vec![5, u64::MAX]),
+                ]),
+            ]),
+            TripFilter::And(vec![]),
+            TripFilter::Or(vec![]),
+        ];
+        for filter in filters {
+            assert!(can_use_bitmap_index(&filter));
+            let expected: Vec<u32> = rows
+                .iter()
+                .enumerate()
+                .filter(|(_, trip)| trip.as_ref().is_some_and(|t| filter.matches(t)))
+                .map(|(row, _)| row as u32)
+                .collect();
+            let resolved: Vec<u32> = bitmap_rows(&filter, &bitmaps).iter().collect();
+            assert_eq!(resolved, expected, "{}", filter);
+        }
+
+        // Una hoja sin bitmap obliga a leer los registros
+        assert!(!can_use_bitmap_index(&TripFilter::And(vec![
+            vendor(1),
+            TripFilter::Destination(7)
+        ])));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_huge_page_returns_an_empty_page_instead_of_overflowing() {
+        let dir = test_dir("page");
+        let mut table = DiskHashTable::new(&dir).unwrap();
+        for trip in trips().into_iter().take(50) {
+            table.insert(trip.index.to_string(), trip).unwrap();
+        }
+        table.sync().unwrap();
+        HASH_TABLE.store(Some(Arc::new(table)));
+
+        let pagination = || Pagination {
+            page: usize::MAX,
+            per_page: 10,
+        };
+        let filters = [
+            TripFilter::Destination(7),
+            TripFilter::PickupTime(window(at(1, 0), at(1, 1))),
+            TripFilter::Passengers {
+                min: Some(1),
+                max: None,
+            },
+        ];
+        for filter in filters {
+            let (page, _) =
+                filter_with_pagination(&dir, filter.clone(), pagination(), ExplainMode::Off)
+                    .unwrap();
+            let page = page.unwrap();
+            assert!(page.items.is_empty());
+            assert!(page.total > 0, "{}", filter);
+
+            let sort = SortOrder {
+                field: FIELD_NAMES
+                    .iter()
+                    .position(|f| *f == "total_amount")
+                    .unwrap(),
+                descending: false,
+            };
+            let (page, _) =
+                filter_sorted_with_pagination(&dir, filter, sort, pagination(), ExplainMode::Off)
+                    .unwrap();
+            assert!(page.unwrap().items.is_empty());
+        }
+        HASH_TABLE.store(None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use super::data_lector::stream_process_pruned;
+use super::dataset::{Dataset, TimeRange};
+use super::disk_hash::{
+    BadEntry, DataStats, DiskHashTable, check_table, map_file, reindex_from_log, truncate_data,
+    walk_log,
+};
+use super::filters::restore_trips;
+use super::index_manifest::{IndexCheck, IndexManifest};
+use super::trip_struct::FIELD_NAMES;
+use super::wal;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::Path;
+use std::time::Instant;
+
+// Cuántos ejemplos de cada problema se listan en el reporte
+const SAMPLE_LEN: usize = 20;
+
+// Lo que encontró una verificación del índice. Las listas completas se guardan para la
+// reparación pero el reporte solo muestra las primeras.
+#[derive(Debug, Default, Serialize)]
+pub struct FsckReport {
+    pub clean: bool,
+    pub records: u64,
+    pub tombstones: u64,
+    // Registros dañados que una reparación anterior todavía no dio por perdidos
+    pub corrupt_records: usize,
+    pub corrupt_sample: Vec<u64>,
+    pub quarantined_records: usize,
+    // Byte desde el que trip_data.bin no se puede recorrer (escritura cortada)
+    pub unreadable_from: Option<u64>,
+    // Escrituras confirmadas en el WAL que todavía no llegaron a los archivos del índice
+    pub wal_pending: usize,
+    pub table_entries: u64,
+    pub table_error: Option<String>,
+    pub bad_entries: usize,
+    pub bad_entry_sample: Vec<BadEntry>,
+    pub secondary_errors: Vec<String>,
+    pub csv_rows: u64,
+    pub missing_rows: usize,
+    pub damaged_rows: usize,
+    // Índices del CSV sin entrada en la tabla o con el registro dañado
+    pub unindexed_sample: Vec<u64>,
+    pub manifest: Option<String>,
+    pub repaired: Vec<String>,
+    pub time_ms: u128,
+    #[serde(skip)]
+    unindexed: Vec<u64>,
+}
+
+impl FsckReport {
+    fn is_clean(&self) -> bool {
+        self.corrupt_records == 0
+            && self.wal_pending == 0
+            && self.unreadable_from.is_none()
+            && self.table_error.is_none()
+            && self.bad_entries == 0
+            && self.secondary_errors.is_empty()
+            && self.unindexed.is_empty()
+            && self.manifest.is_none()
+    }
+
+    // Daños que se arreglan reconstruyendo los índices desde trip_data.bin
+    fn needs_reindex(&self) -> bool {
+        self.corrupt_records > 0
+            || self.unreadable_from.is_some()
+            || self.table_error.is_some()
+            || self.bad_entries > 0
+            || !self.secondary_errors.is_empty()
+    }
+}
+
+// Verifica el índice de index_dir contra el dataset y, si se pide, repara lo dañado
+pub fn run(index_dir: &Path, csv_path: &Path, repair: bool) -> Result<FsckReport, Box<dyn Error>> {
+    let start = Instant::now();
+    let mut report = verify(index_dir, csv_path)?;
+    if repair && !report.clean {
+        let repaired = repair_index(index_dir, csv_path, &report)?;
+        report = verify(index_dir, csv_path)?;
+        report.repaired = repaired;
+    }
+    report.time_ms = start.elapsed().as_millis();
+    Ok(report)
+}
+
+fn verify(index_dir: &Path, csv_path: &Path) -> Result<FsckReport, Box<dyn Error>> {
+    let data_path = index_dir.join("trip_data.bin");
+    if !data_path.exists() {
+        return Err(format!("No hay índice en {}", index_dir.display()).into());
+    }
+    println!("Verificando índice hash en {}...", index_dir.display());
+    let mut report = FsckReport::default();
+
+    let data = map_file(&data_path)?;
+    let walk = walk_log(&data);
+    report.records = walk.records;
+    report.tombstones = walk.tombstones;
+    let quarantined = DataStats::load(index_dir).quarantined;
+    let (known, corrupt): (Vec<u64>, Vec<u64>) = walk
+        .corrupt
+        .iter()
+        .partition(|position| quarantined.binary_search(position).is_ok());
+    report.corrupt_records = corrupt.len();
+    report.corrupt_sample = corrupt.into_iter().take(SAMPLE_LEN).collect();
+    report.quarantined_records = known.len();
+    report.unreadable_from = walk.unreadable_from;
+    report.wal_pending = wal::pending(index_dir)?.len();
+
+    match check_table(index_dir, &data, &walk.valid) {
+        Ok((entries, bad)) => {
+            report.table_entries = entries;
+            report.bad_entries = bad.len();
+            report.bad_entry_sample = bad.into_iter().take(SAMPLE_LEN).collect();
+        }
+        Err(e) => report.table_error = Some(e.to_string()),
+    }
+
+    // Los índices secundarios y el CSV se revisan sobre el índice abierto como lo usa
+    // el servidor. Sin tabla no se abre: DiskHashTable::new crearía una vacía y la
+    // verificación no escribe nada.
+    let opened = if index_dir.join("hash_table.bin").exists() {
+        DiskHashTable::new(index_dir)
+    } else {
+        Err("falta hash_table.bin".into())
+    };
+    match opened {
+        Ok(hash_table) => {
+            if report.table_error.is_none() && report.bad_entries == 0 {
+                match hash_table.check_secondary() {
+                    Ok(errors) => report.secondary_errors = errors,
+                    Err(e) => report.secondary_errors.push(e.to_string()),
+                }
+            }
+            let mut missing = Vec::new();
+            let mut damaged = Vec::new();
+            stream_process_pruned(csv_path, &TimeRange::default(), &["index"], |trip| {
+                report.csv_rows += 1;
+                match hash_table.contains_key(&trip.index.to_string()) {
+                    Ok(true) => {}
+                    Ok(false) => missing.push(trip.index),
+                    Err(_) => damaged.push(trip.index),
+                }
+                Ok(())
+            })?;
+            report.missing_rows = missing.len();
+            report.damaged_rows = damaged.len();
+            report.unindexed = missing.into_iter().chain(damaged).collect();
+            report.unindexed_sample = report.unindexed.iter().take(SAMPLE_LEN).copied().collect();
+        }
+        Err(e) => report
+            .secondary_errors
+            .push(format!("No se pudo abrir el índice: {}", e)),
+    }
+
+    if let IndexCheck::Rebuild(reason) =
+        IndexManifest::inspect(index_dir, &Dataset::open(csv_path)?)?
+    {
+        report.manifest = Some(reason);
+    }
+
+    report.clean = report.is_clean();
+    Ok(report)
+}
+
+// Arregla solo lo dañado: recorta una escritura cortada, rehace la tabla y los índices
+// secundarios desde los registros íntegros y vuelve a cargar del CSV las filas que se
+// perdieron. Devuelve las acciones hechas.
+fn repair_index(
+    index_dir: &Path,
+    csv_path: &Path,
+    report: &FsckReport,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut repaired = Vec::new();
+
+    // Reaplicar el WAL ya recorta y reindexa; lo que quede se revisa de nuevo
+    let after_wal;
+    let report = if report.wal_pending > 0 {
+        let replayed = wal::replay(index_dir)?;
+        repaired.push(format!("{} escrituras del WAL reaplicadas", replayed));
+        after_wal = verify(index_dir, csv_path)?;
+        &after_wal
+    } else {
+        report
+    };
+
+    if let Some(from) = report.unreadable_from {
+        let len = truncate_data(index_dir, from)?;
+        repaired.push(format!(
+            "trip_data.bin recortado de {} a {} bytes",
+            len, from
+        ));
+    }
+
+    if report.needs_reindex() {
+        println!("Reconstruyendo tabla e índices secundarios desde trip_data.bin...");
+        let keys = reindex_from_log(index_dir)?;
+        repaired.push(format!(
+            "Tabla e índices secundarios reconstruidos desde trip_data.bin con {} claves",
+            keys
+        ));
+    }
+
+    // Lo que no quedó íntegro en trip_data.bin se recupera del CSV, por el WAL como
+    // cualquier otra escritura
+    let after = verify(index_dir, csv_path)?;
+    if !after.unindexed.is_empty() {
+        let wanted: HashSet<u64> = after.unindexed.iter().copied().collect();
+        let mut trips = Vec::with_capacity(wanted.len());
+        stream_process_pruned(csv_path, &TimeRange::default(), &FIELD_NAMES, |trip| {
+            if wanted.contains(&trip.index) {
+                trips.push(trip.clone());
+            }
+            Ok(())
+        })?;
+        let restored = restore_trips(trips)?;
+        repaired.push(format!("{} filas del CSV vueltas a cargar", restored));
+    }
+
+    Ok(repaired)
+}
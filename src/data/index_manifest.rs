@@ -0,0 +1,404 @@
+use super::bitmap_index::{BITMAPS_FILE, ROWS_FILE};
+use super::column_store::COLUMNS_META;
+use super::dataset::{Dataset, DatasetPosition, file_fingerprint};
+use super::disk_hash::{DataStats, HASH_FUNCTION_ID, INDEX_FORMAT_VERSION, replace_file};
+use super::posting_index::LocationColumn;
+use super::range_index::RangeColumn;
+use super::record_codec::RECORD_VERSION;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use xxhash_rust::xxh3::Xxh3;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+// Huella de un archivo fuente. El hash del contenido solo se recalcula cuando cambia
+// la fecha de modificación, así un reinicio normal no relee el dataset.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceFingerprint {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: u64,
+    pub content_hash: String,
+}
+
+impl SourceFingerprint {
+    pub fn compute(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let (size, modified) = file_fingerprint(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            size,
+            modified,
+            content_hash: content_hash(path)?,
+        })
+    }
+
+    // Mismo archivo con el mismo contenido, aunque lo hayan tocado
+    pub fn same_content(&self, other: &SourceFingerprint) -> bool {
+        self.path == other.path
+            && self.size == other.size
+            && self.content_hash == other.content_hash
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexManifest {
+    pub format_version: u32,
+    pub record_version: u8,
+    pub hash_function: String,
+    pub row_count: u64,
+    pub built_at: DateTime<Utc>,
+    pub sources: Vec<SourceFingerprint>,
+}
+
+// Resultado de comparar el índice en disco con el dataset actual
+pub enum IndexCheck {
+    Reuse(IndexManifest),
+    Rebuild(String),
+}
+
+impl IndexManifest {
+    pub fn new(sources: Vec<SourceFingerprint>, row_count: u64) -> Self {
+        Self {
+            format_version: INDEX_FORMAT_VERSION,
+            record_version: RECORD_VERSION,
+            hash_function: HASH_FUNCTION_ID.to_string(),
+            row_count,
+            built_at: Utc::now(),
+            sources,
+        }
+    }
+
+    pub fn load(index_dir: &Path) -> Result<Option<Self>, Box<dyn Error>> {
+        let path = index_dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let manifest = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        Ok(Some(manifest))
+    }
+
+    // Se escribe al final de la construcción: un índice sin manifiesto nunca se reutiliza
+    pub fn save(&self, index_dir: &Path) -> Result<(), Box<dyn Error>> {
+        replace_file(
+            &index_dir.join(MANIFEST_FILE),
+            &serde_json::to_vec_pretty(self)?,
+        )
+    }
+
+    // Decide si el índice de index_dir sigue siendo válido para el dataset. Si solo
+    // cambiaron fechas de modificación guarda las nuevas en el manifiesto.
+    pub fn check(index_dir: &Path, dataset: &Dataset) -> Result<IndexCheck, Box<dyn Error>> {
+        let (check, touched) = Self::evaluate(index_dir, dataset)?;
+        if let (IndexCheck::Reuse(manifest), true) = (&check, touched) {
+            manifest.save(index_dir)?;
+        }
+        Ok(check)
+    }
+
+    // Como check pero sin escribir nada, para fsck
+    pub fn inspect(index_dir: &Path, dataset: &Dataset) -> Result<IndexCheck, Box<dyn Error>> {
+        Ok(Self::evaluate(index_dir, dataset)?.0)
+    }
+
+    // La decisión y si el manifiesto devuelto tiene fechas distintas de las guardadas
+    fn evaluate(index_dir: &Path, dataset: &Dataset) -> Result<(IndexCheck, bool), Box<dyn Error>> {
+        let rebuild = |reason: &str| Ok((IndexCheck::Rebuild(reason.to_string()), false));
+
+        let mut manifest = match IndexManifest::load(index_dir) {
+            Ok(Some(manifest)) => manifest,
+            Ok(None) => return rebuild("no hay manifiesto"),
+            Err(e) => return rebuild(&format!("manifiesto ilegible ({})", e)),
+        };
+
+        if manifest.format_version != INDEX_FORMAT_VERSION
+            || manifest.record_version != RECORD_VERSION
+        {
+            return rebuild(&format!(
+                "formato {}/{} distinto del actual {}/{}",
+                manifest.format_version,
+                manifest.record_version,
+                INDEX_FORMAT_VERSION,
+                RECORD_VERSION
+            ));
+        }
+        if manifest.hash_function != HASH_FUNCTION_ID {
+            return rebuild(&format!(
+                "función hash {} distinta de {}",
+                manifest.hash_function, HASH_FUNCTION_ID
+            ));
+        }
+        let postings = LocationColumn::ALL.map(|column| column.file_name());
+        let ranges = RangeColumn::ALL.map(|column| column.file_name());
+        for file in [
+            "hash_table.bin",
+            "trip_data.bin",
+            ROWS_FILE,
+            BITMAPS_FILE,
+            COLUMNS_META,
+        ]
+        .iter()
+        .chain(&postings)
+        .chain(&ranges)
+        {
+            if !index_dir.join(file).exists() {
+                return rebuild(&format!("falta {}", file));
+            }
+        }
+
+        let files = dataset.files();
+        let same_files = files.len() == manifest.sources.len()
+            && files
+                .iter()
+                .zip(&manifest.sources)
+                .all(|(f, s)| *f == s.path);
+        if !same_files {
+            return rebuild("cambió la lista de archivos del dataset");
+        }
+
+        let mut touched = false;
+        for source in &mut manifest.sources {
+            let (size, modified) = file_fingerprint(&source.path)?;
+            if size != source.size {
+                return rebuild(&format!("cambió el tamaño de {}", source.path.display()));
+            }
+            if modified != source.modified {
+                // Misma longitud pero otra fecha: solo el contenido decide
+                if content_hash(&source.path)? != source.content_hash {
+                    return rebuild(&format!("cambió el contenido de {}", source.path.display()));
+                }
+                source.modified = modified;
+                touched = true;
+            }
+        }
+        Ok((IndexCheck::Reuse(manifest), touched))
+    }
+}
+
+// Estado de una construcción a medias dentro del directorio de staging. Solo se
+// escribe después de sincronizar los datos y la copia de la tabla a la que apunta,
+// así que siempre describe un estado consistente.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildCheckpoint {
+    pub format_version: u32,
+    pub record_version: u8,
+    pub hash_function: String,
+    pub sequence: u64,
+    pub rows_ingested: u64,
+    // Bytes válidos de trip_data.bin.tmp, lo que haya después se descarta
+    pub data_len: u64,
+    pub stats: DataStats,
+    pub position: DatasetPosition,
+    pub sources: Vec<SourceFingerprint>,
+    pub written_at: DateTime<Utc>,
+}
+
+impl BuildCheckpoint {
+    pub fn new(
+        sequence: u64,
+        rows_ingested: u64,
+        data_len: u64,
+        stats: DataStats,
+        position: DatasetPosition,
+        sources: Vec<SourceFingerprint>,
+    ) -> Self {
+        Self {
+            format_version: INDEX_FORMAT_VERSION,
+            record_version: RECORD_VERSION,
+            hash_function: HASH_FUNCTION_ID.to_string(),
+            sequence,
+            rows_ingested,
+            data_len,
+            stats,
+            position,
+            sources,
+            written_at: Utc::now(),
+        }
+    }
+
+    // Copia de la tabla en memoria que corresponde a este checkpoint
+    pub fn table_file(sequence: u64) -> String {
+        format!("hash_table.{}.ckpt", sequence)
+    }
+
+    pub fn load(staging_dir: &Path) -> Result<Option<Self>, Box<dyn Error>> {
+        let path = staging_dir.join(CHECKPOINT_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let checkpoint = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        Ok(Some(checkpoint))
+    }
+
+    pub fn save(&self, staging_dir: &Path) -> Result<(), Box<dyn Error>> {
+        replace_file(
+            &staging_dir.join(CHECKPOINT_FILE),
+            &serde_json::to_vec_pretty(self)?,
+        )
+    }
+
+    pub fn remove(staging_dir: &Path) -> Result<(), Box<dyn Error>> {
+        let path = staging_dir.join(CHECKPOINT_FILE);
+        if let Some(checkpoint) = Self::load(staging_dir)? {
+            fs::remove_file(&path)?;
+            fs::remove_file(staging_dir.join(Self::table_file(checkpoint.sequence)))?;
+        }
+        Ok(())
+    }
+
+    // Solo se reanuda con el mismo formato y exactamente los mismos archivos fuente
+    pub fn can_resume(&self, sources: &[SourceFingerprint]) -> bool {
+        self.format_version == INDEX_FORMAT_VERSION
+            && self.record_version == RECORD_VERSION
+            && self.hash_function == HASH_FUNCTION_ID
+            && self.sources.len() == sources.len()
+            && self
+                .sources
+                .iter()
+                .zip(sources)
+                .all(|(a, b)| a.same_content(b))
+    }
+}
+
+fn content_hash(path: &Path) -> Result<String, Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let mut hasher = Xxh3::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("xxh3:{:016x}", hasher.digest()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::disk_hash::DiskHashTable;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // Directorio vacío y propio de cada prueba
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("manifest-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const CSV: &str = "VendorID,tpep_pickup_datetime,tpep_dropoff_datetime,passenger_count,trip_distance,RatecodeID,store_and_fwd_flag,PULocationID,DOLocationID,payment_type,fare_amount,extra,mta_tax,tip_amount,tolls_amount,improvement_surcharge,total_amount,congestion_surcharge,Index\n\
+    2,2024-01-04 22:19:23,2024-01-04 22:30:40,2,9.95,1,N,44,38,2,21.5,0.5,0.5,2.0,0.0,0.3,24.8,2.5,1\n\
+    1,2024-01-05 19:00:02,2024-01-05 19:45:28,1,0.86,1,N,58,27,1,7.5,0.5,0.5,1.0,0.0,0.3,9.8,2.5,2\n";
+
+    // Índice recién construido sobre un dataset de un archivo
+    fn built_index(name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let dir = test_dir(name);
+        let csv_path = dir.join("data.csv");
+        fs::write(&csv_path, CSV).unwrap();
+        let index_dir = dir.join("index");
+        DiskHashTable::build_hash_table_from_csv(&csv_path, &index_dir).unwrap();
+        (dir, csv_path, index_dir)
+    }
+
+    fn rebuild_reason(index_dir: &Path, dataset: &Dataset) -> Option<String> {
+        match IndexManifest::check(index_dir, dataset).unwrap() {
+            IndexCheck::Reuse(_) => None,
+            IndexCheck::Rebuild(reason) => Some(reason),
+        }
+    }
+
+    fn set_modified(path: &Path, secs: u64) {
+        let file = File::options().write(true).open(path).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn an_unchanged_dataset_reuses_the_index() {
+        let (dir, csv_path, index_dir) = built_index("reuse");
+        let dataset = Dataset::open(&csv_path).unwrap();
+        match IndexManifest::check(&index_dir, &dataset).unwrap() {
+            IndexCheck::Reuse(manifest) => assert_eq!(manifest.row_count, 2),
+            IndexCheck::Rebuild(reason) => panic!("se pidió reconstruir: {}", reason),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_touched_file_with_the_same_content_is_reused() {
+        let (dir, csv_path, index_dir) = built_index("touched");
+        let dataset = Dataset::open(&csv_path).unwrap();
+        set_modified(&csv_path, 1_000_000);
+
+        // inspect no escribe, check guarda la fecha nueva
+        assert!(matches!(
+            IndexManifest::inspect(&index_dir, &dataset).unwrap(),
+            IndexCheck::Reuse(_)
+        ));
+        let saved = |dir: &Path| IndexManifest::load(dir).unwrap().unwrap().sources[0].modified;
+        assert_ne!(saved(&index_dir), 1_000_000);
+        assert_eq!(rebuild_reason(&index_dir, &dataset), None);
+        assert_eq!(saved(&index_dir), 1_000_000);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_changed_dataset_asks_for_a_rebuild() {
+        let (dir, csv_path, index_dir) = built_index("changed");
+
+        // Mismo tamaño, otro contenido
+        fs::write(&csv_path, CSV.replace(",44,", ",45,")).unwrap();
+        set_modified(&csv_path, 1_000_000);
+        let dataset = Dataset::open(&csv_path).unwrap();
+        let reason = rebuild_reason(&index_dir, &dataset).unwrap();
+        assert!(reason.starts_with("cambió el contenido"), "{}", reason);
+
+        fs::write(&csv_path, format!("{}1,2024-01-06 08:10:00,2024-01-06 08:20:00,1,1.2,1,N,12,13,1,8.0,0.5,0.5,1.0,0.0,0.3,10.3,2.5,3\n", CSV)).unwrap();
+        let reason = rebuild_reason(&index_dir, &dataset).unwrap();
+        assert!(reason.starts_with("cambió el tamaño"), "{}", reason);
+
+        fs::write(dir.join("more.csv"), CSV).unwrap();
+        let both = Dataset::open(dir.join("*.csv")).unwrap();
+        assert_eq!(
+            rebuild_reason(&index_dir, &both).as_deref(),
+            Some("cambió la lista de archivos del dataset")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn an_incomplete_or_outdated_index_is_rebuilt() {
+        let (dir, csv_path, index_dir) = built_index("outdated");
+        let dataset = Dataset::open(&csv_path).unwrap();
+
+        let mut manifest = IndexManifest::load(&index_dir).unwrap().unwrap();
+        manifest.format_version = INDEX_FORMAT_VERSION - 1;
+        manifest.save(&index_dir).unwrap();
+        let reason = rebuild_reason(&index_dir, &dataset).unwrap();
+        assert!(reason.starts_with("formato"), "{}", reason);
+
+        manifest.format_version = INDEX_FORMAT_VERSION;
+        manifest.save(&index_dir).unwrap();
+        fs::remove_file(index_dir.join(BITMAPS_FILE)).unwrap();
+        assert_eq!(
+            rebuild_reason(&index_dir, &dataset),
+            Some(format!("falta {}", BITMAPS_FILE))
+        );
+
+        fs::write(index_dir.join(MANIFEST_FILE), "{").unwrap();
+        let reason = rebuild_reason(&index_dir, &dataset).unwrap();
+        assert!(reason.starts_with("manifiesto ilegible"), "{}", reason);
+        fs::remove_file(index_dir.join(MANIFEST_FILE)).unwrap();
+        assert_eq!(
+            rebuild_reason(&index_dir, &dataset).as_deref(),
+            Some("no hay manifiesto")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
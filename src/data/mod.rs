@@ -1,66 +1,209 @@
+pub mod bitmap_index;
+pub mod column_store;
+pub mod compression;
+pub mod csv_schema;
 pub mod data_lector;
+pub mod dataset;
 pub mod disk_hash;
+pub mod filter_dsl;
+pub mod filter_params;
 pub mod filters;
+pub mod fsck;
+pub mod index_manifest;
 pub mod pagination;
+pub mod parquet_lector;
+pub mod planner;
+pub mod posting_index;
+pub mod range_index;
+pub mod record_codec;
 pub mod trip_struct;
+pub mod wal;
 
-const CSV_PATH: &str = "src/data/data.csv";
+// El dataset puede ser un archivo, un directorio de particiones o un glob
+pub fn dataset_path() -> String {
+    config::get().dataset_path.clone()
+}
 
+use crate::config;
+use dataset::TimeRange;
 use filters::{
-    TripFilter, filter_to_file, filter_with_pagination, get_filter_stats, get_popular_destinations,
-    get_trip_by_index,
+    AmountField, SortOrder, TripFilter, check_hash_index, delete_trip_by_index,
+    filter_sorted_with_pagination, filter_with_pagination, get_trip_by_index, insert_trip,
+    update_trip_by_index,
 };
+use fsck::FsckReport;
 use pagination::{PagedResult, Pagination};
+use planner::{Explain, ExplainMode};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
-use std::time::Instant;
-use trip_struct::Trip;
+use trip_struct::{FIELD_NAMES, Trip};
 
+#[allow(dead_code)]
 pub struct FilterResult {
     count: usize,
     time: std::time::Duration,
     output_file: String,
 }
 
+// Resultado de una consulta. Con explain lleva el plan al lado de los viajes, o en
+// lugar de ellos si solo se pidió el plan.
+#[derive(Debug, Serialize)]
+pub struct SearchOutput {
+    #[serde(flatten)]
+    result: Option<PagedResult<Trip>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plan: Option<Explain>,
+}
+
+#[allow(dead_code)]
 pub struct StatsResult {
     stats: HashMap<String, f64>,
     time: std::time::Duration,
 }
 
+#[allow(dead_code)]
 pub struct PopularDestinationsResult {
-    destinations: Vec<(String, usize)>,
+    destinations: Vec<(u32, usize)>,
     time: std::time::Duration,
 }
 
 pub fn get_trips_by_index(index: &str) -> Result<Option<Trip>, Box<dyn Error>> {
-    get_trip_by_index(CSV_PATH, index)
+    get_trip_by_index(dataset_path(), index)
+}
+
+// Devuelve None si ya existe un viaje con ese índice
+pub fn create_trip(trip: Trip) -> Result<Option<Trip>, Box<dyn Error>> {
+    if insert_trip(dataset_path(), &trip)? {
+        Ok(Some(trip))
+    } else {
+        Ok(None)
+    }
+}
+
+// El índice del viaje lo fija la ruta, no el cuerpo
+pub fn update_trip(index: u64, mut trip: Trip) -> Result<Option<Trip>, Box<dyn Error>> {
+    trip.index = index;
+    if update_trip_by_index(dataset_path(), index, &trip)? {
+        Ok(Some(trip))
+    } else {
+        Ok(None)
+    }
+}
+
+pub fn delete_trip(index: u64) -> Result<bool, Box<dyn Error>> {
+    delete_trip_by_index(dataset_path(), index)
+}
+
+pub fn check_index(repair: bool) -> Result<FsckReport, Box<dyn Error>> {
+    check_hash_index(dataset_path(), repair)
+}
+
+// Las condiciones extra de la query se suman con And al filtro propio de la ruta
+fn with_extra(filter: TripFilter, extra: Option<TripFilter>) -> TripFilter {
+    match extra {
+        Some(extra) => TripFilter::And(vec![filter, extra]),
+        None => filter,
+    }
+}
+
+// Orden por un campo de FIELD_NAMES
+fn sorted_by(field: &str, descending: bool) -> Option<SortOrder> {
+    let field = FIELD_NAMES.iter().position(|name| *name == field)?;
+    Some(SortOrder { field, descending })
+}
+
+// El filtro se planea una sola vez: el plan de explain es el mismo que se ejecuta. Sin
+// orden los resultados van en el orden del archivo.
+fn run_search(
+    filter: TripFilter,
+    sort: Option<SortOrder>,
+    pagination: Pagination,
+    explain: ExplainMode,
+) -> Result<SearchOutput, Box<dyn Error>> {
+    let (result, plan) = match sort {
+        Some(sort) => {
+            filter_sorted_with_pagination(dataset_path(), filter, sort, pagination, explain)?
+        }
+        None => filter_with_pagination(dataset_path(), filter, pagination, explain)?,
+    };
+    Ok(SearchOutput { result, plan })
 }
 
 pub fn get_trips_by_price_range(
     min_price: f64,
     max_price: f64,
+    extra: Option<TripFilter>,
+    sort_descending: Option<bool>,
     pagination: Pagination,
-) -> Result<PagedResult<Trip>, Box<dyn Error>> {
-    let filter = TripFilter::Price {
+    explain: ExplainMode,
+) -> Result<SearchOutput, Box<dyn Error>> {
+    let filter = TripFilter::Amount {
+        field: AmountField::Total,
         min: Some(min_price),
         max: Some(max_price),
     };
 
-    filter_with_pagination(CSV_PATH, filter, pagination)
+    // En el orden del archivo salvo que se pida ordenar por importe
+    let sort = sort_descending.and_then(|descending| sorted_by("total_amount", descending));
+    run_search(with_extra(filter, extra), sort, pagination, explain)
 }
 
 pub fn get_trips_by_destination(
-    destination: &str,
+    destination: u32,
+    extra: Option<TripFilter>,
     pagination: Pagination,
-) -> Result<PagedResult<Trip>, Box<dyn Error>> {
-    let filter = TripFilter::Destination(destination.to_string());
+    explain: ExplainMode,
+) -> Result<SearchOutput, Box<dyn Error>> {
+    let filter = TripFilter::Destination(destination);
 
-    filter_with_pagination(CSV_PATH, filter, pagination)
+    run_search(with_extra(filter, extra), None, pagination, explain)
+}
+
+// Fecha del viaje sobre la que se filtra una ventana de tiempo
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeField {
+    #[default]
+    Pickup,
+    Dropoff,
+}
+
+pub fn get_trips_by_time_range(
+    field: TimeField,
+    range: TimeRange,
+    extra: Option<TripFilter>,
+    pagination: Pagination,
+    explain: ExplainMode,
+) -> Result<SearchOutput, Box<dyn Error>> {
+    let (filter, sort) = match field {
+        TimeField::Pickup => (
+            TripFilter::PickupTime(range),
+            sorted_by("tpep_pickup_datetime", false),
+        ),
+        TimeField::Dropoff => (
+            TripFilter::DropoffTime(range),
+            sorted_by("tpep_dropoff_datetime", false),
+        ),
+    };
+
+    // Ordenados por la fecha de la ventana
+    run_search(with_extra(filter, extra), sort, pagination, explain)
 }
 
 pub fn get_trips_with_complex_filter(
     filter: TripFilter,
     pagination: Pagination,
-) -> Result<PagedResult<Trip>, Box<dyn Error>> {
-    filter_with_pagination(CSV_PATH, filter, pagination)
+    explain: ExplainMode,
+) -> Result<SearchOutput, Box<dyn Error>> {
+    run_search(filter, None, pagination, explain)
+}
+
+pub fn search_trips(
+    filter: TripFilter,
+    sort: Option<SortOrder>,
+    pagination: Pagination,
+    explain: ExplainMode,
+) -> Result<SearchOutput, Box<dyn Error>> {
+    run_search(filter, sort, pagination, explain)
 }
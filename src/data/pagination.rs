@@ -1,3 +1,4 @@
+use crate::config;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Deserialize)]
@@ -10,11 +11,30 @@ impl Default for Pagination {
     fn default() -> Self {
         Self {
             page: 1,
-            per_page: 50,
+            per_page: config::get().default_per_page,
         }
     }
 }
 
+impl Pagination {
+    // Lo que pide el cliente, con el valor por defecto y el tope de la configuración
+    pub fn from_query(page: Option<usize>, per_page: Option<usize>) -> Self {
+        let config = config::get();
+        Self {
+            page: page.unwrap_or(1).max(1),
+            per_page: per_page
+                .unwrap_or(config.default_per_page)
+                .clamp(1, config.max_per_page),
+        }
+    }
+
+    // Filas que se saltan antes de la página. Una página enorme satura en lugar de
+    // desbordar y simplemente devuelve una página vacía.
+    pub fn offset(&self) -> usize {
+        self.page.saturating_sub(1).saturating_mul(self.per_page)
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct PagedResult<T> {
     pub items: Vec<T>,
@@ -33,7 +53,7 @@ impl<T> PagedResult<T> {
         time: std::time::Duration,
     ) -> Self {
         let pages = if pagination.per_page > 0 {
-            (total + pagination.per_page - 1) / pagination.per_page
+            total.div_ceil(pagination.per_page)
         } else {
             0
         };
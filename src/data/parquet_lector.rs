@@ -0,0 +1,298 @@
+use super::csv_schema::{ColumnMapping, CsvOptions};
+use super::data_lector::FilePosition;
+use super::trip_struct::{FIELD_NAMES, INDEX_FIELD, Trip};
+use chrono::DateTime;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::Field;
+use parquet::schema::types::Type;
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+// Lee un Parquet grupo de filas por grupo de filas, solo con las columnas pedidas.
+// Cada valor se pasa a texto y se valida igual que una celda de CSV, así ambos
+// formatos producen exactamente los mismos Trip. Las primeras `skip_rows` filas se
+// saltan, los grupos completos sin decodificarlos.
+pub fn stream_process_parquet<P, F>(
+    filename: P,
+    options: &CsvOptions,
+    wanted: &[bool; FIELD_NAMES.len()],
+    row_offset: u64,
+    skip_rows: u64,
+    mut process_trip: F,
+) -> Result<u64, Box<dyn Error>>
+where
+    P: AsRef<Path>,
+    F: FnMut(&Trip, FilePosition) -> Result<(), Box<dyn Error>>,
+{
+    let file = File::open(filename)?;
+    let reader = SerializedFileReader::new(file)?;
+    let root = reader
+        .metadata()
+        .file_metadata()
+        .schema_descr()
+        .root_schema_ptr();
+    let columns = root.get_fields();
+
+    // Del dialecto solo aplican los alias de columnas
+    let mapping = ColumnMapping::from_names(columns.iter().map(|c| c.name()), options)?;
+    let has_index = mapping.contains(INDEX_FIELD);
+
+    // Columnas del archivo que realmente hacen falta, en el orden del esquema
+    let mut selected: Vec<usize> = (0..FIELD_NAMES.len())
+        .filter(|&field| wanted[field])
+        .filter_map(|field| mapping.position(field))
+        .collect();
+    selected.sort_unstable();
+    let projection = Type::group_type_builder(root.name())
+        .with_fields(selected.iter().map(|&c| Arc::clone(&columns[c])).collect())
+        .build()?;
+
+    let mut values = vec![String::new(); columns.len()];
+    let mut row_number = row_offset;
+    for group in 0..reader.num_row_groups() {
+        let group_rows = reader.metadata().row_group(group).num_rows() as u64;
+        let skipped = row_number - row_offset;
+        if skipped + group_rows <= skip_rows {
+            row_number += group_rows;
+            continue;
+        }
+
+        let row_group = reader.get_row_group(group)?;
+        let rows = row_group.get_row_iter(Some(projection.clone()))?;
+        let skip_in_group = skip_rows.saturating_sub(skipped);
+        row_number += skip_in_group;
+        for row in rows.skip(skip_in_group as usize) {
+            let row = row?;
+            row_number += 1;
+
+            for (&column, (_, field)) in selected.iter().zip(row.get_column_iter()) {
+                values[column] = field_to_string(field);
+            }
+            let mut fields = mapping.fields_with(|p| values.get(p).map(|v| v.as_str()));
+
+            let row_index;
+            if !has_index {
+                row_index = row_number.to_string();
+                fields[INDEX_FIELD] = &row_index;
+            }
+
+            match Trip::from_fields_projected(&fields, wanted) {
+                Ok(trip) => {
+                    let position = FilePosition {
+                        rows: row_number - row_offset,
+                        byte: None,
+                    };
+                    process_trip(&trip, position)?
+                }
+                Err(e) => eprintln!(
+                    "Registro inválido en la fila {}: {}",
+                    row_number - row_offset,
+                    e
+                ),
+            }
+        }
+    }
+
+    Ok(row_number - row_offset)
+}
+
+fn field_to_string(field: &Field) -> String {
+    match field {
+        Field::Null => String::new(),
+        Field::Str(value) => value.clone(),
+        Field::Bytes(value) => String::from_utf8_lossy(value.data()).into_owned(),
+        // Display de parquet usa notación científica, Rust escribe el valor corto exacto
+        Field::Double(value) => value.to_string(),
+        Field::Float(value) => value.to_string(),
+        Field::TimestampMicros(value) => DateTime::from_timestamp_micros(*value)
+            .map(|d| d.naive_utc().format("%Y-%m-%d %H:%M:%S%.f").to_string())
+            .unwrap_or_default(),
+        Field::TimestampMillis(value) => DateTime::from_timestamp_millis(*value)
+            .map(|d| d.naive_utc().format("%Y-%m-%d %H:%M:%S%.f").to_string())
+            .unwrap_or_default(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::trip_struct::projection_mask;
+    use parquet::column::writer::ColumnWriter;
+    use parquet::data_type::ByteArray;
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+    use rust_decimal::Decimal;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // Directorio vacío y propio de cada prueba
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("parquet-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Esquema de los exports de NYC TLC: nombres propios, conteos en double, fechas en
+    // microsegundos y sin columna de índice
+    const SCHEMA: &str = "
+        message schema {
+            required int64 VendorID;
+            required int64 tpep_pickup_datetime (TIMESTAMP(MICROS,false));
+            required int64 tpep_dropoff_datetime (TIMESTAMP(MICROS,false));
+            required double passenger_count;
+            required double trip_distance;
+            required double RatecodeID;
+            required binary store_and_fwd_flag (STRING);
+            required int64 PULocationID;
+            required int64 DOLocationID;
+            required int64 payment_type;
+            required double fare_amount;
+            required double extra;
+            required double mta_tax;
+            required double tip_amount;
+            required double tolls_amount;
+            required double improvement_surcharge;
+            required double total_amount;
+            optional double congestion_surcharge;
+        }
+    ";
+
+    // Tres grupos de filas de tres filas. La fila i tiene destino 100 + i y recargo
+    // solo en las pares.
+    fn write_file(path: &Path) {
+        let schema = Arc::new(parse_message_type(SCHEMA).unwrap());
+        let props = Arc::new(WriterProperties::builder().build());
+        let mut writer =
+            SerializedFileWriter::new(File::create(path).unwrap(), schema, props).unwrap();
+        let start = 1_704_067_200_000_000i64; // 2024-01-01 00:00:00
+        for group in 0..3i64 {
+            let rows: Vec<i64> = (group * 3..group * 3 + 3).collect();
+            let mut row_group = writer.next_row_group().unwrap();
+            let mut column = 0;
+            while let Some(mut writer) = row_group.next_column().unwrap() {
+                match writer.untyped() {
+                    ColumnWriter::Int64ColumnWriter(w) => {
+                        let values: Vec<i64> = rows
+                            .iter()
+                            .map(|&i| match column {
+                                0 => 2,
+                                1 => start + i * 60_000_000,
+                                2 => start + i * 60_000_000 + 900_000_000,
+                                8 => 100 + i,
+                                _ => 1,
+                            })
+                            .collect();
+                        w.write_batch(&values, None, None).unwrap();
+                    }
+                    ColumnWriter::DoubleColumnWriter(w) if column == 17 => {
+                        let present: Vec<f64> =
+                            rows.iter().filter(|&&i| i % 2 == 0).map(|_| 2.5).collect();
+                        let levels: Vec<i16> = rows.iter().map(|&i| (i % 2 == 0) as i16).collect();
+                        w.write_batch(&present, Some(&levels), None).unwrap();
+                    }
+                    // Los conteos vienen como double con valor entero
+                    ColumnWriter::DoubleColumnWriter(w) if column == 3 || column == 5 => {
+                        let values: Vec<f64> = rows.iter().map(|&i| 1.0 + i as f64).collect();
+                        w.write_batch(&values, None, None).unwrap();
+                    }
+                    ColumnWriter::DoubleColumnWriter(w) => {
+                        let values: Vec<f64> = rows.iter().map(|&i| 1.0 + i as f64 / 4.0).collect();
+                        w.write_batch(&values, None, None).unwrap();
+                    }
+                    ColumnWriter::ByteArrayColumnWriter(w) => {
+                        let values = vec![ByteArray::from("N"); rows.len()];
+                        w.write_batch(&values, None, None).unwrap();
+                    }
+                    _ => unreachable!(),
+                }
+                writer.close().unwrap();
+                column += 1;
+            }
+            row_group.close().unwrap();
+        }
+        writer.close().unwrap();
+    }
+
+    fn read(
+        path: &Path,
+        wanted: &[bool; FIELD_NAMES.len()],
+        skip_rows: u64,
+    ) -> (Vec<(Trip, FilePosition)>, u64) {
+        let mut trips = Vec::new();
+        let total = stream_process_parquet(
+            path,
+            &CsvOptions::default(),
+            wanted,
+            10,
+            skip_rows,
+            |trip, position| {
+                trips.push((trip.clone(), position));
+                Ok(())
+            },
+        )
+        .unwrap();
+        (trips, total)
+    }
+
+    #[test]
+    fn a_tlc_export_reads_like_a_csv() {
+        let dir = test_dir("read");
+        let path = dir.join("yellow_tripdata_2024-01.parquet");
+        write_file(&path);
+
+        let (trips, total) = read(&path, &[true; FIELD_NAMES.len()], 0);
+        assert_eq!(total, 9);
+        assert_eq!(trips.len(), 9);
+        let (trip, position) = &trips[4];
+        assert_eq!(trip.vendor_id, 2);
+        assert_eq!(trip.tpep_pickup_datetime.to_string(), "2024-01-01 00:04:00");
+        assert_eq!(trip.passenger_count, 5);
+        assert_eq!(trip.trip_distance, 2.0);
+        assert_eq!(trip.do_location_id, 104);
+        assert_eq!(trip.total_amount, Decimal::new(2, 0));
+        assert_eq!(trip.congestion_surcharge, Decimal::new(25, 1));
+        // Sin columna de índice se numera con la fila, a continuación de row_offset
+        assert_eq!(trip.index, 15);
+        assert_eq!(position.rows, 5);
+        assert_eq!(position.byte, None);
+        // Un nulo en una columna opcional vale cero
+        assert_eq!(trips[3].0.congestion_surcharge, Decimal::ZERO);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skipped_rows_and_unwanted_columns_are_not_read() {
+        let dir = test_dir("skip");
+        let path = dir.join("trips.parquet");
+        write_file(&path);
+
+        // El primer grupo se salta entero y del segundo solo la primera fila
+        let wanted = projection_mask(&["do_location_id", "index"]);
+        let (trips, total) = read(&path, &wanted, 4);
+        assert_eq!(total, 9);
+        let rows: Vec<(u64, u32, u64)> = trips
+            .iter()
+            .map(|(trip, position)| (trip.index, trip.do_location_id, position.rows))
+            .collect();
+        assert_eq!(
+            rows,
+            (4..9)
+                .map(|i| (11 + i, 100 + i as u32, i + 1))
+                .collect::<Vec<_>>()
+        );
+        // Lo que no está en la proyección queda en su valor por defecto
+        assert_eq!(trips[0].0.vendor_id, 0);
+        assert_eq!(trips[0].0.total_amount, Decimal::ZERO);
+
+        // Saltar todo no lee nada pero cuenta las filas
+        let (trips, total) = read(&path, &wanted, 9);
+        assert!(trips.is_empty());
+        assert_eq!(total, 9);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
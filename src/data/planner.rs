@@ -0,0 +1,576 @@
+use super::disk_hash::DiskHashTable;
+use super::filters::{KeyField, SortOrder, TripFilter, bitmap_rows, can_use_bitmap_index};
+use super::posting_index::LocationColumn;
+use super::range_index::{KeyRange, RangeColumn};
+use super::trip_struct::FIELD_NAMES;
+use roaring::RoaringBitmap;
+use serde::Serialize;
+use std::str::FromStr;
+
+// Costos relativos de cada operación; la unidad es revisar una fila en el almacén
+// columnar. Solo importa la proporción entre ellos.
+const SCAN_ROW_COST: f64 = 1.0;
+// Leer y decodificar un registro completo en cualquier posición de trip_data.bin
+const RECORD_READ_COST: f64 = 8.0;
+// Sondeo de una clave en la tabla hash
+const KEY_LOOKUP_COST: f64 = 2.0;
+// Recorrer una posición de una lista de ubicaciones o de un índice de rango
+const INDEX_ENTRY_COST: f64 = 0.1;
+// Combinar bitmaps comprimidos, por fila del resultado
+const BITMAP_ROW_COST: f64 = 0.01;
+
+// Qué devuelve una consulta con explain: solo los viajes, los viajes y el plan, o solo
+// el plan sin ejecutarla
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExplainMode {
+    #[default]
+    Off,
+    Alongside,
+    Only,
+}
+
+impl FromStr for ExplainMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "false" => Ok(ExplainMode::Off),
+            "true" => Ok(ExplainMode::Alongside),
+            "only" => Ok(ExplainMode::Only),
+            _ => Err(format!(
+                "Valor inválido en explain: {} (true, false u only)",
+                value
+            )),
+        }
+    }
+}
+
+// Hojas que son igualdad o pertenencia sobre un campo entero
+fn key_values(filter: &TripFilter) -> Option<(KeyField, &[u64])> {
+    match filter {
+        TripFilter::In(field, values) => Some((*field, values)),
+        _ => None,
+    }
+}
+
+fn key_leaf(filter: &TripFilter) -> Option<(KeyField, u64)> {
+    let (field, value) = match filter {
+        TripFilter::Index(index) => return Some((KeyField::Index, *index)),
+        TripFilter::Vendor(value) => (KeyField::Vendor, value),
+        TripFilter::RateCode(value) => (KeyField::RateCode, value),
+        TripFilter::PickupLocation(value) => (KeyField::PickupLocation, value),
+        TripFilter::Destination(value) => (KeyField::Destination, value),
+        TripFilter::PaymentType(value) => (KeyField::PaymentType, value),
+        TripFilter::PassengerCount(value) => (KeyField::PassengerCount, value),
+        _ => return None,
+    };
+    Some((field, *value as u64))
+}
+
+// Un And o un Or de un solo hijo es el hijo
+fn collapse(mut filters: Vec<TripFilter>, build: fn(Vec<TripFilter>) -> TripFilter) -> TripFilter {
+    if filters.len() == 1 {
+        filters.remove(0)
+    } else {
+        build(filters)
+    }
+}
+
+// Reescribe el filtro en una forma equivalente más fácil de planear:
+//   - los And dentro de And y los Or dentro de Or se aplanan, y un And o un Or de un
+//     solo hijo se reemplaza por el hijo;
+//   - un Or vacío (falso) dentro de un And lo vuelve falso, un And vacío (verdadero)
+//     dentro de un Or lo vuelve verdadero, y se descartan los neutros;
+//   - la doble negación se cancela;
+//   - en un Or las igualdades sobre el mismo campo se juntan en un In, así un Or de
+//     índices se resuelve con una sola búsqueda de varias claves.
+pub fn normalize(filter: TripFilter) -> TripFilter {
+    match filter {
+        TripFilter::And(filters) => {
+            let mut flat = Vec::with_capacity(filters.len());
+            for filter in filters.into_iter().map(normalize) {
+                match filter {
+                    TripFilter::Or(inner) if inner.is_empty() => return TripFilter::Or(inner),
+                    TripFilter::And(inner) => flat.extend(inner),
+                    filter => flat.push(filter),
+                }
+            }
+            collapse(flat, TripFilter::And)
+        }
+        TripFilter::Or(filters) => {
+            let mut flat = Vec::with_capacity(filters.len());
+            let mut keys: Vec<(KeyField, Vec<u64>)> = Vec::new();
+            for filter in filters.into_iter().map(normalize) {
+                let leaf = key_leaf(&filter)
+                    .map(|(field, value)| (field, vec![value]))
+                    .or_else(|| key_values(&filter).map(|(f, values)| (f, values.to_vec())));
+                match (filter, leaf) {
+                    (_, Some((field, values))) => {
+                        match keys.iter_mut().find(|(f, _)| *f == field) {
+                            Some((_, merged)) => merged.extend(values),
+                            None => keys.push((field, values)),
+                        }
+                    }
+                    (TripFilter::And(inner), _) if inner.is_empty() => {
+                        return TripFilter::And(inner);
+                    }
+                    (TripFilter::Or(inner), _) => flat.extend(inner),
+                    (filter, _) => flat.push(filter),
+                }
+            }
+            let mut merged: Vec<TripFilter> = keys
+                .into_iter()
+                .map(|(field, values)| field.filter(values))
+                .collect();
+            merged.extend(flat);
+            collapse(merged, TripFilter::Or)
+        }
+        TripFilter::Not(filter) => match normalize(*filter) {
+            TripFilter::Not(inner) => *inner,
+            TripFilter::And(inner) if inner.is_empty() => TripFilter::Or(inner),
+            TripFilter::Or(inner) if inner.is_empty() => TripFilter::And(inner),
+            filter => TripFilter::Not(Box::new(filter)),
+        },
+        // Un conjunto vacío no lo cumple nadie
+        TripFilter::In(_, values) if values.is_empty() => TripFilter::Or(Vec::new()),
+        TripFilter::In(field, values) => field.filter(values),
+        filter => filter,
+    }
+}
+
+// Forma de llegar a los registros candidatos
+pub enum AccessPath {
+    // Recorrer todas las filas vivas en el almacén columnar
+    FullScan,
+    // Buscar cada clave en la tabla hash
+    PrimaryKeys(Vec<u64>),
+    // Unión de las listas de posiciones de cada valor
+    Location(LocationColumn, Vec<u32>),
+    Range(RangeColumn, KeyRange),
+    // Filas ya resueltas con los bitmaps
+    Bitmap(RoaringBitmap),
+}
+
+impl AccessPath {
+    fn name(&self) -> &'static str {
+        match self {
+            AccessPath::FullScan => "full_scan",
+            AccessPath::PrimaryKeys(_) => "primary_key",
+            AccessPath::Location(..) => "location_index",
+            AccessPath::Range(..) => "range_index",
+            AccessPath::Bitmap(_) => "bitmap_index",
+        }
+    }
+
+    fn column(&self) -> Option<&'static str> {
+        match self {
+            AccessPath::PrimaryKeys(_) => Some("index"),
+            AccessPath::Location(column, _) => Some(column.field_name()),
+            AccessPath::Range(column, _) => Some(column.field_name()),
+            AccessPath::FullScan | AccessPath::Bitmap(_) => None,
+        }
+    }
+}
+
+pub struct Candidate {
+    pub path: AccessPath,
+    // Condición que resuelve el índice; el escaneo no tiene, evalúa el filtro entero
+    condition: Option<String>,
+    // El índice responde el filtro completo y no hace falta evaluarlo en cada registro
+    pub exact: bool,
+    // Registros que entrega el camino antes del filtro residual. Es exacto salvo en
+    // las claves primarias, donde es la cantidad de claves pedidas.
+    pub rows: usize,
+    pub cost: f64,
+}
+
+impl Candidate {
+    fn step(&self, filter: &TripFilter) -> PlanStep {
+        PlanStep {
+            access: self.path.name(),
+            column: self.path.column(),
+            condition: self.condition.clone(),
+            residual: (!self.exact).then(|| filter.to_string()),
+            estimated_rows: self.rows,
+            // Con dos decimales basta para comparar caminos
+            estimated_cost: (self.cost * 100.0).round() / 100.0,
+        }
+    }
+}
+
+// Un paso del plan tal como lo devuelve explain
+#[derive(Debug, Serialize)]
+pub struct PlanStep {
+    access: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    condition: Option<String>,
+    // Lo que se evalúa sobre cada registro candidato
+    #[serde(skip_serializing_if = "Option::is_none")]
+    residual: Option<String>,
+    estimated_rows: usize,
+    estimated_cost: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Explain {
+    filter: String,
+    plan: PlanStep,
+    // Los demás caminos considerados, del más barato al más caro
+    alternatives: Vec<PlanStep>,
+}
+
+pub struct QueryPlan {
+    // Filtro ya normalizado, es el que se ejecuta
+    pub filter: TripFilter,
+    pub chosen: Candidate,
+    alternatives: Vec<Candidate>,
+}
+
+impl QueryPlan {
+    pub fn explain(&self) -> Explain {
+        Explain {
+            filter: self.filter.to_string(),
+            plan: self.chosen.step(&self.filter),
+            alternatives: self
+                .alternatives
+                .iter()
+                .map(|candidate| candidate.step(&self.filter))
+                .collect(),
+        }
+    }
+
+    // Resumen para el log
+    pub fn describe(&self) -> String {
+        let chosen = &self.chosen;
+        let mut description = match &chosen.path {
+            AccessPath::FullScan => "escaneo del almacén columnar".to_string(),
+            AccessPath::PrimaryKeys(keys) => format!("búsqueda de {} claves", keys.len()),
+            AccessPath::Location(column, _) => {
+                format!("índice secundario de {}", column.field_name())
+            }
+            AccessPath::Range(column, _) => format!("índice de rango de {}", column.field_name()),
+            AccessPath::Bitmap(_) => "índices de bitmap".to_string(),
+        };
+        if let Some(condition) = &chosen.condition {
+            description += &format!(" para {}", condition);
+        }
+        if !chosen.exact && !matches!(chosen.path, AccessPath::FullScan) {
+            description += " con filtro residual";
+        }
+        format!(
+            "{} (filas estimadas: {}, costo: {:.1})",
+            description, chosen.rows, chosen.cost
+        )
+    }
+}
+
+// Rangos que tienen índice ordenado. Sin ningún extremo el rango no descarta nada.
+fn range_path(filter: &TripFilter) -> Option<(RangeColumn, KeyRange)> {
+    match filter {
+        TripFilter::Amount { field, min, max } if min.is_some() || max.is_some() => {
+            Some((field.range_column()?, KeyRange::amounts(*min, *max)))
+        }
+        TripFilter::Distance { min, max } if min.is_some() || max.is_some() => {
+            Some((RangeColumn::TripDistance, KeyRange::amounts(*min, *max)))
+        }
+        TripFilter::PickupTime(range) if !range.is_unbounded() => {
+            Some((RangeColumn::PickupTime, KeyRange::times(range)))
+        }
+        TripFilter::DropoffTime(range) if !range.is_unbounded() => {
+            Some((RangeColumn::DropoffTime, KeyRange::times(range)))
+        }
+        _ => None,
+    }
+}
+
+fn location_column(field: KeyField) -> Option<LocationColumn> {
+    match field {
+        KeyField::PickupLocation => Some(LocationColumn::Pickup),
+        KeyField::Destination => Some(LocationColumn::Dropoff),
+        _ => None,
+    }
+}
+
+// Índice que responde el filtro completo y cuántos registros entrega
+fn index_path(filter: &TripFilter, table: &DiskHashTable) -> Option<(AccessPath, usize)> {
+    let keys = key_leaf(filter)
+        .map(|(field, value)| (field, vec![value]))
+        .or_else(|| key_values(filter).map(|(field, values)| (field, values.to_vec())));
+    if let Some((field, values)) = keys {
+        if field == KeyField::Index {
+            let rows = values.len();
+            return Some((AccessPath::PrimaryKeys(values), rows));
+        }
+        if let Some(column) = location_column(field) {
+            let values: Vec<u32> = values.iter().map(|v| *v as u32).collect();
+            let rows = values
+                .iter()
+                .map(|value| table.locations().count(column, *value))
+                .sum();
+            return Some((AccessPath::Location(column, values), rows));
+        }
+    }
+    if let Some((column, range)) = range_path(filter) {
+        let rows = table.ranges().count(column, &range);
+        return Some((AccessPath::Range(column, range), rows));
+    }
+    if can_use_bitmap_index(filter) {
+        let rows = bitmap_rows(filter, table.bitmaps());
+        let len = rows.len() as usize;
+        return Some((AccessPath::Bitmap(rows), len));
+    }
+    None
+}
+
+// Si el camino entrega los registros ya en el orden de los resultados. Sin orden pedido
+// van en el del archivo y todos lo cumplen, porque ordenar las posiciones no lee
+// registros. Con orden solo el índice de rango del mismo campo, y en sentido ascendente.
+pub fn follows_order(path: &AccessPath, order: Option<SortOrder>) -> bool {
+    match (path, order) {
+        (_, None) => true,
+        (AccessPath::Range(column, _), Some(order)) => {
+            !order.descending && FIELD_NAMES[order.field] == column.field_name()
+        }
+        _ => false,
+    }
+}
+
+// Costo estimado de un camino. Un camino exacto que ya sigue el orden pedido solo lee
+// los registros de la página; con filtro residual, o si hay que ordenar, lee todos los
+// candidatos. El escaneo revisa todas las filas y lee completos solo los de la página.
+fn cost(path: &AccessPath, rows: usize, exact: bool, take: usize, order: Option<SortOrder>) -> f64 {
+    let reads = if exact && follows_order(path, order) {
+        rows.min(take)
+    } else {
+        rows
+    } as f64;
+    match path {
+        AccessPath::FullScan => rows as f64 * SCAN_ROW_COST + take as f64 * RECORD_READ_COST,
+        AccessPath::PrimaryKeys(keys) => keys.len() as f64 * (KEY_LOOKUP_COST + RECORD_READ_COST),
+        AccessPath::Location(..) | AccessPath::Range(..) => {
+            rows as f64 * INDEX_ENTRY_COST + reads * RECORD_READ_COST
+        }
+        AccessPath::Bitmap(_) => rows as f64 * BITMAP_ROW_COST + reads * RECORD_READ_COST,
+    }
+}
+
+// Elige cómo ejecutar el filtro. Los candidatos son el escaneo completo, el índice que
+// responda el filtro entero y, en un And, el índice de cada hijo (o la intersección de
+// los bitmaps de varios) con el resto como filtro residual. Gana el de menor costo
+// estimado; en un empate el que aparece primero.
+pub fn plan(
+    filter: TripFilter,
+    table: &DiskHashTable,
+    take: usize,
+    order: Option<SortOrder>,
+) -> QueryPlan {
+    let filter = normalize(filter);
+    let mut candidates = Vec::new();
+    let mut add = |path: AccessPath, condition: Option<String>, exact: bool, rows: usize| {
+        candidates.push(Candidate {
+            cost: cost(&path, rows, exact, take, order),
+            path,
+            condition,
+            exact,
+            rows,
+        });
+    };
+
+    if let Some((path, rows)) = index_path(&filter, table) {
+        add(path, Some(filter.to_string()), true, rows);
+    } else if let TripFilter::And(children) = &filter {
+        let mut bitmap_children = Vec::new();
+        for child in children {
+            if let Some((path, rows)) = index_path(child, table) {
+                add(path, Some(child.to_string()), false, rows);
+            }
+            if can_use_bitmap_index(child) {
+                bitmap_children.push(child);
+            }
+        }
+        if bitmap_children.len() > 1 {
+            let rows = bitmap_children
+                .iter()
+                .map(|child| bitmap_rows(child, table.bitmaps()))
+                .reduce(|acc, rows| acc & rows)
+                .unwrap_or_default();
+            let condition = bitmap_children
+                .iter()
+                .map(|child| child.to_string())
+                .collect::<Vec<_>>()
+                .join(" and ");
+            let len = rows.len() as usize;
+            add(AccessPath::Bitmap(rows), Some(condition), false, len);
+        }
+    }
+    let live = table.bitmaps().live().len() as usize;
+    add(AccessPath::FullScan, None, false, live);
+
+    let best = candidates
+        .iter()
+        .enumerate()
+        .min_by(|a, b| a.1.cost.total_cmp(&b.1.cost))
+        .map(|(i, _)| i)
+        .unwrap_or_default();
+    let chosen = candidates.remove(best);
+    candidates.sort_by(|a, b| a.cost.total_cmp(&b.cost));
+
+    QueryPlan {
+        filter,
+        chosen,
+        alternatives: candidates,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::filters::AmountField;
+    use crate::data::trip_struct::Trip;
+    use rust_decimal::Decimal;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // Directorio vacío y propio de cada prueba
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("planner-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // 200 viajes: el índice i cuesta i, el destino 77 solo en los múltiplos de 50
+    fn table(name: &str) -> (PathBuf, DiskHashTable) {
+        let dir = test_dir(name);
+        let mut table = DiskHashTable::new(&dir).unwrap();
+        for index in 1..=200u64 {
+            let trip = Trip {
+                index,
+                vendor_id: (index % 2 + 1) as u32,
+                do_location_id: if index % 50 == 0 { 77 } else { 1 },
+                total_amount: Decimal::from(index),
+                ..Trip::default()
+            };
+            table.insert(index.to_string(), trip).unwrap();
+        }
+        table.sync().unwrap();
+        (dir, table)
+    }
+
+    fn total_between(min: f64, max: f64) -> TripFilter {
+        TripFilter::Amount {
+            field: AmountField::Total,
+            min: Some(min),
+            max: Some(max),
+        }
+    }
+
+    fn sort_by(field: &str, descending: bool) -> Option<SortOrder> {
+        Some(SortOrder {
+            field: FIELD_NAMES.iter().position(|name| *name == field).unwrap(),
+            descending,
+        })
+    }
+
+    #[test]
+    fn normalize_merges_an_or_of_equalities_into_in() {
+        let filter = TripFilter::Or(vec![
+            TripFilter::Destination(7),
+            TripFilter::Vendor(1),
+            TripFilter::Or(vec![
+                TripFilter::Destination(3),
+                TripFilter::In(KeyField::Destination, vec![7, 9]),
+            ]),
+        ]);
+        let expected = TripFilter::Or(vec![
+            TripFilter::In(KeyField::Destination, vec![3, 7, 9]),
+            TripFilter::Vendor(1),
+        ]);
+        assert_eq!(normalize(filter).to_string(), expected.to_string());
+
+        let same_key = TripFilter::Or(vec![TripFilter::Index(4), TripFilter::Index(4)]);
+        assert_eq!(
+            normalize(same_key).to_string(),
+            TripFilter::Index(4).to_string()
+        );
+    }
+
+    #[test]
+    fn normalize_flattens_and_simplifies_constants() {
+        let filter = TripFilter::And(vec![
+            TripFilter::And(vec![TripFilter::Vendor(1)]),
+            TripFilter::Not(Box::new(TripFilter::Not(Box::new(
+                TripFilter::Destination(5),
+            )))),
+        ]);
+        let expected = TripFilter::And(vec![TripFilter::Vendor(1), TripFilter::Destination(5)]);
+        assert_eq!(normalize(filter).to_string(), expected.to_string());
+
+        // Un In vacío es falso y vuelve falso el And que lo contiene
+        let empty = TripFilter::And(vec![
+            TripFilter::Vendor(1),
+            TripFilter::In(KeyField::Destination, Vec::new()),
+        ]);
+        assert_eq!(
+            normalize(empty).to_string(),
+            TripFilter::Or(Vec::new()).to_string()
+        );
+    }
+
+    #[test]
+    fn picks_the_cheapest_index_for_the_filter() {
+        let (dir, table) = table("cheapest");
+
+        let by_location = plan(TripFilter::Destination(77), &table, 10, None);
+        assert!(matches!(by_location.chosen.path, AccessPath::Location(..)));
+        assert!(by_location.chosen.exact);
+        assert_eq!(by_location.chosen.rows, 4);
+
+        let keys = TripFilter::Or(vec![TripFilter::Index(3), TripFilter::Index(5)]);
+        let by_keys = plan(keys, &table, 10, None);
+        assert!(matches!(&by_keys.chosen.path, AccessPath::PrimaryKeys(keys) if keys == &[3, 5]));
+
+        // En un And gana el hijo más selectivo y el resto queda como filtro residual
+        let both = TripFilter::And(vec![TripFilter::Destination(77), total_between(1.0, 200.0)]);
+        let residual = plan(both, &table, 10, None);
+        assert!(matches!(residual.chosen.path, AccessPath::Location(..)));
+        assert!(!residual.chosen.exact);
+        assert!(
+            residual
+                .alternatives
+                .windows(2)
+                .all(|pair| pair[0].cost <= pair[1].cost)
+        );
+        assert!(
+            residual
+                .alternatives
+                .iter()
+                .all(|c| c.cost >= residual.chosen.cost)
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_requested_order_decides_between_range_index_and_scan() {
+        let (dir, table) = table("order");
+        let wide = || total_between(1.0, 200.0);
+
+        // Sin orden o en el orden del índice alcanza con leer la página
+        let plan_without_order = plan(wide(), &table, 10, None);
+        assert!(matches!(
+            plan_without_order.chosen.path,
+            AccessPath::Range(..)
+        ));
+        let plan_by_amount = plan(wide(), &table, 10, sort_by("total_amount", false));
+        assert!(matches!(plan_by_amount.chosen.path, AccessPath::Range(..)));
+
+        // Con otro orden el índice tiene que leer todos los registros y conviene escanear
+        let plan_by_fare = plan(wide(), &table, 10, sort_by("fare_amount", false));
+        assert!(matches!(plan_by_fare.chosen.path, AccessPath::FullScan));
+        let plan_descending = plan(wide(), &table, 10, sort_by("total_amount", true));
+        assert!(matches!(plan_descending.chosen.path, AccessPath::FullScan));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
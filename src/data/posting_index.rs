@@ -0,0 +1,368 @@
+use super::disk_hash::{map_file, replace_file};
+use super::trip_struct::Trip;
+use crate::config;
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const POSTINGS_MAGIC: &[u8; 4] = b"PST1";
+const DELTA_FILE: &str = "postings_delta.json";
+// Entrada del directorio: valor u32, inicio u64 y largo u64 en el arreglo de posiciones
+const DIRECTORY_ENTRY_LEN: usize = 4 + 8 + 8;
+
+// Columnas con índice secundario. Cada una mapea el valor de la columna a la lista
+// ordenada de posiciones en trip_data.bin de los registros que lo tienen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocationColumn {
+    Pickup,
+    Dropoff,
+}
+
+impl LocationColumn {
+    pub const ALL: [LocationColumn; 2] = [LocationColumn::Pickup, LocationColumn::Dropoff];
+
+    pub fn file_name(self) -> &'static str {
+        match self {
+            LocationColumn::Pickup => "pu_location.postings",
+            LocationColumn::Dropoff => "do_location.postings",
+        }
+    }
+
+    pub fn field_name(self) -> &'static str {
+        match self {
+            LocationColumn::Pickup => "pu_location_id",
+            LocationColumn::Dropoff => "do_location_id",
+        }
+    }
+
+    fn value(self, trip: &Trip) -> u32 {
+        match self {
+            LocationColumn::Pickup => trip.pu_location_id,
+            LocationColumn::Dropoff => trip.do_location_id,
+        }
+    }
+}
+
+// Se llena al terminar una construcción recorriendo los registros vivos en orden de
+// posición, así cada lista queda ordenada sin tener que ordenarla.
+#[derive(Default)]
+pub struct PostingsBuilder {
+    lists: [BTreeMap<u32, Vec<u64>>; 2],
+}
+
+impl PostingsBuilder {
+    pub fn add(&mut self, position: u64, trip: &Trip) {
+        for (column, lists) in LocationColumn::ALL.iter().zip(&mut self.lists) {
+            lists.entry(column.value(trip)).or_default().push(position);
+        }
+    }
+
+    // Formato: [magic][u32 cantidad de valores][directorio][posiciones u64], todo en
+    // little endian. Cualquier delta anterior queda obsoleto con las listas nuevas.
+    pub fn write(&self, dir_path: &Path) -> Result<(), Box<dyn Error>> {
+        for (column, lists) in LocationColumn::ALL.iter().zip(&self.lists) {
+            let path = dir_path.join(column.file_name());
+            let tmp_path = path.with_extension("postings.tmp");
+            let mut out = BufWriter::with_capacity(
+                config::get().write_buffer_bytes,
+                File::create(&tmp_path)?,
+            );
+
+            out.write_all(POSTINGS_MAGIC)?;
+            out.write_all(&(lists.len() as u32).to_le_bytes())?;
+            let mut start = 0u64;
+            for (value, positions) in lists {
+                out.write_all(&value.to_le_bytes())?;
+                out.write_all(&start.to_le_bytes())?;
+                out.write_all(&(positions.len() as u64).to_le_bytes())?;
+                start += positions.len() as u64;
+            }
+            for position in lists.values().flatten() {
+                out.write_all(&position.to_le_bytes())?;
+            }
+
+            out.flush()?;
+            out.get_ref().sync_all()?;
+            fs::rename(&tmp_path, &path)?;
+        }
+
+        let delta_path = dir_path.join(DELTA_FILE);
+        if delta_path.exists() {
+            fs::remove_file(delta_path)?;
+        }
+        Ok(())
+    }
+}
+
+// Listas de una columna tal como quedaron al construir el índice
+struct PostingFile {
+    map: Option<Mmap>,
+    directory: HashMap<u32, (usize, usize)>,
+    base: usize,
+}
+
+impl PostingFile {
+    // Un índice recién creado todavía no tiene listas, se trata como vacío
+    fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Self {
+                map: None,
+                directory: HashMap::new(),
+                base: 0,
+            });
+        }
+
+        let map = map_file(path)?;
+        if map.get(..4) != Some(POSTINGS_MAGIC.as_slice()) {
+            return Err(format!("{} no es un archivo de listas", path.display()).into());
+        }
+        let count = u32::from_le_bytes(map.get(4..8).ok_or("Listas truncadas")?.try_into()?);
+        let base = 8 + count as usize * DIRECTORY_ENTRY_LEN;
+        let directory_bytes = map.get(8..base).ok_or("Directorio de listas truncado")?;
+        if !(map.len() - base).is_multiple_of(8) {
+            return Err(format!("Largo inesperado en {}", path.display()).into());
+        }
+        let stored = (map.len() - base) / 8;
+
+        // Cada lista tiene que caer dentro del arreglo de posiciones, así positions()
+        // puede cortar el mapeo sin revisar nada
+        let mut directory = HashMap::with_capacity(count as usize);
+        let mut total = 0usize;
+        for entry in directory_bytes.chunks_exact(DIRECTORY_ENTRY_LEN) {
+            let value = u32::from_le_bytes(entry[..4].try_into()?);
+            let start = u64::from_le_bytes(entry[4..12].try_into()?);
+            let len = u64::from_le_bytes(entry[12..].try_into()?);
+            let fits = start
+                .checked_add(len)
+                .is_some_and(|end| end <= stored as u64);
+            if !fits {
+                return Err(format!(
+                    "La lista del valor {} se sale de {} ({} + {} de {} posiciones)",
+                    value,
+                    path.display(),
+                    start,
+                    len,
+                    stored
+                )
+                .into());
+            }
+            total += len as usize;
+            directory.insert(value, (start as usize, len as usize));
+        }
+        if total != stored {
+            return Err(format!("Largo inesperado en {}", path.display()).into());
+        }
+
+        Ok(Self {
+            map: Some(map),
+            directory,
+            base,
+        })
+    }
+
+    fn len(&self, value: u32) -> usize {
+        self.directory.get(&value).map_or(0, |(_, len)| *len)
+    }
+
+    fn positions(&self, value: u32) -> impl Iterator<Item = u64> + '_ {
+        let bytes = match (&self.map, self.directory.get(&value)) {
+            (Some(map), Some(&(start, len))) => {
+                &map[self.base + start * 8..self.base + (start + len) * 8]
+            }
+            _ => &[][..],
+        };
+        bytes
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+    }
+}
+
+// Cambios desde la construcción: posiciones que dejaron de ser vivas y registros
+// nuevos. Las posiciones agregadas siempre son mayores que las de la lista base
+// porque el log solo crece, así que se recorren después de ella.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ColumnDelta {
+    added: BTreeMap<u32, Vec<u64>>,
+    removed: BTreeMap<u32, BTreeSet<u64>>,
+}
+
+impl ColumnDelta {
+    fn remove(&mut self, value: u32, position: u64) {
+        if let Some(added) = self.added.get_mut(&value)
+            && let Some(i) = added.iter().position(|p| *p == position)
+        {
+            added.remove(i);
+            if added.is_empty() {
+                self.added.remove(&value);
+            }
+            return;
+        }
+        self.removed.entry(value).or_default().insert(position);
+    }
+
+    fn add(&mut self, value: u32, position: u64) {
+        self.added.entry(value).or_default().push(position);
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct PostingDelta {
+    pickup: ColumnDelta,
+    dropoff: ColumnDelta,
+}
+
+impl PostingDelta {
+    fn column(&self, column: LocationColumn) -> &ColumnDelta {
+        match column {
+            LocationColumn::Pickup => &self.pickup,
+            LocationColumn::Dropoff => &self.dropoff,
+        }
+    }
+
+    fn column_mut(&mut self, column: LocationColumn) -> &mut ColumnDelta {
+        match column {
+            LocationColumn::Pickup => &mut self.pickup,
+            LocationColumn::Dropoff => &mut self.dropoff,
+        }
+    }
+}
+
+// Índice secundario de ubicaciones: las listas inmutables de la última construcción
+// más el delta de las actualizaciones y borrados posteriores, que se persiste en cada
+// cambio y desaparece con la siguiente compactación. Las copias comparten las listas.
+#[derive(Clone)]
+pub struct LocationIndex {
+    dir_path: PathBuf,
+    files: Arc<[PostingFile; 2]>,
+    delta: PostingDelta,
+}
+
+impl LocationIndex {
+    pub fn open(dir_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let delta = match File::open(dir_path.join(DELTA_FILE)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(_) => PostingDelta::default(),
+        };
+        Ok(Self {
+            dir_path: dir_path.to_path_buf(),
+            files: Arc::new([
+                PostingFile::open(&dir_path.join(LocationColumn::Pickup.file_name()))?,
+                PostingFile::open(&dir_path.join(LocationColumn::Dropoff.file_name()))?,
+            ]),
+            delta,
+        })
+    }
+
+    fn file(&self, column: LocationColumn) -> &PostingFile {
+        match column {
+            LocationColumn::Pickup => &self.files[0],
+            LocationColumn::Dropoff => &self.files[1],
+        }
+    }
+
+    // Cantidad exacta de registros vivos con ese valor, sin leer ninguno
+    pub fn count(&self, column: LocationColumn, value: u32) -> usize {
+        let delta = self.delta.column(column);
+        self.file(column).len(value) - delta.removed.get(&value).map_or(0, |r| r.len())
+            + delta.added.get(&value).map_or(0, |a| a.len())
+    }
+
+    // Registros vivos en la columna, sumando todos los valores
+    pub fn total(&self, column: LocationColumn) -> usize {
+        let delta = self.delta.column(column);
+        let base: usize = self
+            .file(column)
+            .directory
+            .values()
+            .map(|(_, len)| len)
+            .sum();
+        base - delta.removed.values().map(|r| r.len()).sum::<usize>()
+            + delta.added.values().map(|a| a.len()).sum::<usize>()
+    }
+
+    // Posiciones vivas con ese valor, en orden de posición
+    pub fn positions(&self, column: LocationColumn, value: u32) -> impl Iterator<Item = u64> + '_ {
+        let delta = self.delta.column(column);
+        let removed = delta.removed.get(&value);
+        let added = delta.added.get(&value).map_or(&[][..], |a| a.as_slice());
+        self.file(column)
+            .positions(value)
+            .filter(move |p| removed.is_none_or(|r| !r.contains(p)))
+            .chain(added.iter().copied())
+    }
+
+    // Registra que la versión viva de una clave pasó de `old` a `new`; cualquiera de las
+    // dos puede faltar (inserción nueva o borrado).
+    pub fn record_change(&mut self, old: Option<(u64, &Trip)>, new: Option<(u64, &Trip)>) {
+        for column in LocationColumn::ALL {
+            let delta = self.delta.column_mut(column);
+            if let Some((position, trip)) = old {
+                delta.remove(column.value(trip), position);
+            }
+            if let Some((position, trip)) = new {
+                delta.add(column.value(trip), position);
+            }
+        }
+    }
+
+    // Los cambios se acumulan en memoria y se guardan una vez por lote de escrituras
+    pub fn save_delta(&self) -> Result<(), Box<dyn Error>> {
+        replace_file(
+            &self.dir_path.join(DELTA_FILE),
+            &serde_json::to_vec(&self.delta)?,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Directorio vacío y propio de cada prueba
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("postings-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn trip(destination: u32) -> Trip {
+        Trip {
+            do_location_id: destination,
+            ..Trip::default()
+        }
+    }
+
+    #[test]
+    fn open_rejects_a_directory_entry_past_the_positions() {
+        let dir = test_dir("bounds");
+        let mut builder = PostingsBuilder::default();
+        builder.add(0, &trip(5));
+        builder.add(40, &trip(9));
+        builder.add(80, &trip(5));
+        builder.write(&dir).unwrap();
+
+        let path = dir.join(LocationColumn::Dropoff.file_name());
+        let file = PostingFile::open(&path).unwrap();
+        assert_eq!(file.positions(5).collect::<Vec<_>>(), [0, 80]);
+        assert_eq!(file.positions(9).collect::<Vec<_>>(), [40]);
+
+        // El largo de la primera lista (valor 5) pasa a apuntar fuera del archivo
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[8 + 12..8 + 20].copy_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        assert!(PostingFile::open(&path).is_err());
+
+        // Un inicio fuera del arreglo aunque el total de posiciones cuadre
+        bytes[8 + 12..8 + 20].copy_from_slice(&2u64.to_le_bytes());
+        bytes[8 + 4..8 + 12].copy_from_slice(&2u64.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        assert!(PostingFile::open(&path).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,502 @@
+use super::dataset::TimeRange;
+use super::disk_hash::{map_file, replace_file};
+use super::trip_struct::Trip;
+use crate::config;
+use chrono::NaiveDateTime;
+use memmap2::Mmap;
+use rust_decimal::prelude::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const RANGE_MAGIC: &[u8; 4] = b"RNG1";
+const RANGE_HEADER_LEN: usize = 4 + 8;
+// Entrada: clave u64 y posición u64 en trip_data.bin
+const RANGE_ENTRY_LEN: usize = 8 + 8;
+const DELTA_FILE: &str = "ranges_delta.json";
+const SIGN_BIT: u64 = 1 << 63;
+
+// Columnas con índice ordenado. Cada valor se traduce a una clave u64 que se ordena
+// igual que el valor original, así todas comparten el mismo formato de archivo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeColumn {
+    TotalAmount,
+    FareAmount,
+    TripDistance,
+    PickupTime,
+    DropoffTime,
+}
+
+impl RangeColumn {
+    pub const ALL: [RangeColumn; 5] = [
+        RangeColumn::TotalAmount,
+        RangeColumn::FareAmount,
+        RangeColumn::TripDistance,
+        RangeColumn::PickupTime,
+        RangeColumn::DropoffTime,
+    ];
+
+    pub fn file_name(self) -> &'static str {
+        match self {
+            RangeColumn::TotalAmount => "total_amount.range",
+            RangeColumn::FareAmount => "fare_amount.range",
+            RangeColumn::TripDistance => "trip_distance.range",
+            RangeColumn::PickupTime => "pickup_time.range",
+            RangeColumn::DropoffTime => "dropoff_time.range",
+        }
+    }
+
+    pub fn field_name(self) -> &'static str {
+        match self {
+            RangeColumn::TotalAmount => "total_amount",
+            RangeColumn::FareAmount => "fare_amount",
+            RangeColumn::TripDistance => "trip_distance",
+            RangeColumn::PickupTime => "tpep_pickup_datetime",
+            RangeColumn::DropoffTime => "tpep_dropoff_datetime",
+        }
+    }
+
+    // Los importes usan la misma conversión a f64 que los filtros. Un valor que no es
+    // un número finito no cumple ningún rango acotado, así que no entra al índice.
+    fn key(self, trip: &Trip) -> Option<u64> {
+        match self {
+            RangeColumn::TotalAmount => amount_key(trip.total_amount.to_f64()?),
+            RangeColumn::FareAmount => amount_key(trip.fare_amount.to_f64()?),
+            RangeColumn::TripDistance => amount_key(trip.trip_distance),
+            RangeColumn::PickupTime => Some(time_key(trip.tpep_pickup_datetime)),
+            RangeColumn::DropoffTime => Some(time_key(trip.tpep_dropoff_datetime)),
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+// Bits del f64 reordenados para que el orden de u64 coincida con el numérico. -0.0 se
+// normaliza a 0.0 porque para las comparaciones de los filtros son iguales.
+pub fn amount_key(value: f64) -> Option<u64> {
+    if !value.is_finite() {
+        return None;
+    }
+    let bits = (value + 0.0).to_bits();
+    Some(if bits & SIGN_BIT != 0 {
+        !bits
+    } else {
+        bits | SIGN_BIT
+    })
+}
+
+// Microsegundos desde 1970 con el bit de signo invertido. Con microsegundos cabe todo
+// el rango de NaiveDateTime; los datos de TLC traen segundos enteros.
+pub fn time_key(time: NaiveDateTime) -> u64 {
+    (time.and_utc().timestamp_micros() as u64) ^ SIGN_BIT
+}
+
+// Rango cerrado de claves; un extremo ausente no acota
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeyRange {
+    min: Option<u64>,
+    max: Option<u64>,
+    empty: bool,
+}
+
+impl KeyRange {
+    // Un extremo NaN no deja pasar ningún importe, igual que al comparar; uno infinito
+    // no acota o lo descarta todo según el lado
+    pub fn amounts(min: Option<f64>, max: Option<f64>) -> Self {
+        let empty = min.is_some_and(|min| min.is_nan() || min == f64::INFINITY)
+            || max.is_some_and(|max| max.is_nan() || max == f64::NEG_INFINITY);
+        Self {
+            min: min.and_then(amount_key),
+            max: max.and_then(amount_key),
+            empty,
+        }
+    }
+
+    pub fn times(range: &TimeRange) -> Self {
+        Self {
+            min: range.from.map(time_key),
+            max: range.to.map(time_key),
+            empty: false,
+        }
+    }
+
+    // Claves enteras (conteos, IDs) con los extremos tal cual
+    pub fn keys(min: Option<u64>, max: Option<u64>) -> Self {
+        Self {
+            min,
+            max,
+            empty: false,
+        }
+    }
+
+    pub fn exact(key: u64) -> Self {
+        Self {
+            min: Some(key),
+            max: Some(key),
+            empty: false,
+        }
+    }
+
+    // Si algún valor entre `min` y `max` puede caer en el rango
+    pub fn overlaps(&self, min: u64, max: u64) -> bool {
+        !self.empty
+            && self.min.is_none_or(|from| max >= from)
+            && self.max.is_none_or(|to| min <= to)
+    }
+
+    // Sin extremos el rango deja pasar cualquier valor, incluso los que no tienen clave
+    pub fn is_unbounded(&self) -> bool {
+        !self.empty && self.min.is_none() && self.max.is_none()
+    }
+
+    fn contains(&self, key: u64) -> bool {
+        !self.empty
+            && self.min.is_none_or(|min| key >= min)
+            && self.max.is_none_or(|max| key <= max)
+    }
+}
+
+#[derive(Default)]
+pub struct RangeBuilder {
+    entries: [Vec<(u64, u64)>; RangeColumn::ALL.len()],
+    skipped: usize,
+}
+
+impl RangeBuilder {
+    pub fn add(&mut self, position: u64, trip: &Trip) {
+        for column in RangeColumn::ALL {
+            match column.key(trip) {
+                Some(key) => self.entries[column.index()].push((key, position)),
+                None => self.skipped += 1,
+            }
+        }
+    }
+
+    // Formato: [magic][u64 cantidad][(clave u64, posición u64) ordenados], en little
+    // endian. Los valores repetidos quedan juntos y ordenados por posición.
+    pub fn write(mut self, dir_path: &Path) -> Result<(), Box<dyn Error>> {
+        if self.skipped > 0 {
+            println!(
+                "{} valores no numéricos quedaron fuera de los índices de rango",
+                self.skipped
+            );
+        }
+        for column in RangeColumn::ALL {
+            let entries = &mut self.entries[column.index()];
+            entries.sort_unstable();
+
+            let path = dir_path.join(column.file_name());
+            let tmp_path = path.with_extension("range.tmp");
+            let mut out = BufWriter::with_capacity(
+                config::get().write_buffer_bytes,
+                File::create(&tmp_path)?,
+            );
+            out.write_all(RANGE_MAGIC)?;
+            out.write_all(&(entries.len() as u64).to_le_bytes())?;
+            for (key, position) in entries.iter() {
+                out.write_all(&key.to_le_bytes())?;
+                out.write_all(&position.to_le_bytes())?;
+            }
+            out.flush()?;
+            out.get_ref().sync_all()?;
+            fs::rename(&tmp_path, &path)?;
+        }
+
+        let delta_path = dir_path.join(DELTA_FILE);
+        if delta_path.exists() {
+            fs::remove_file(delta_path)?;
+        }
+        Ok(())
+    }
+}
+
+// Arreglo ordenado de una columna tal como quedó al construir el índice
+struct RangeFile {
+    map: Option<Mmap>,
+    len: usize,
+}
+
+impl RangeFile {
+    fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Self { map: None, len: 0 });
+        }
+
+        let map = map_file(path)?;
+        if map.get(..4) != Some(RANGE_MAGIC.as_slice()) {
+            return Err(format!("{} no es un índice de rango", path.display()).into());
+        }
+        let len = u64::from_le_bytes(
+            map.get(4..RANGE_HEADER_LEN)
+                .ok_or("Índice de rango truncado")?
+                .try_into()?,
+        );
+        // La cantidad de la cabecera tiene que cubrir exactamente el resto del archivo,
+        // sin desbordar con un valor corrupto
+        let expected = usize::try_from(len)
+            .ok()
+            .and_then(|len| len.checked_mul(RANGE_ENTRY_LEN))
+            .and_then(|bytes| bytes.checked_add(RANGE_HEADER_LEN));
+        if expected != Some(map.len()) {
+            return Err(format!(
+                "Largo inesperado en {}: la cabecera indica {} entradas",
+                path.display(),
+                len
+            )
+            .into());
+        }
+        let len = len as usize;
+        Ok(Self {
+            map: Some(map),
+            len,
+        })
+    }
+
+    // None fuera del arreglo; con el largo revisado en open no pasa para i < len
+    fn entry(&self, i: usize) -> Option<(u64, u64)> {
+        let start = i
+            .checked_mul(RANGE_ENTRY_LEN)?
+            .checked_add(RANGE_HEADER_LEN)?;
+        let bytes = self.map.as_ref()?.get(start..start + RANGE_ENTRY_LEN)?;
+        let (key, position) = bytes.split_first_chunk::<8>()?;
+        Some((
+            u64::from_le_bytes(*key),
+            u64::from_le_bytes(*position.first_chunk::<8>()?),
+        ))
+    }
+
+    // Primer índice cuya clave no cumple `below`, por búsqueda binaria
+    fn partition_point(&self, below: impl Fn(u64) -> bool) -> usize {
+        let (mut lo, mut hi) = (0, self.len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.entry(mid).is_some_and(|(key, _)| below(key)) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    // Sub-arreglo [desde, hasta) con las claves dentro del rango
+    fn seek(&self, range: &KeyRange) -> (usize, usize) {
+        if range.empty {
+            return (0, 0);
+        }
+        let from = range.min.map_or(0, |min| self.partition_point(|k| k < min));
+        let to = range
+            .max
+            .map_or(self.len, |max| self.partition_point(|k| k <= max));
+        (from, to.max(from))
+    }
+}
+
+// Cambios desde la construcción, con su clave para poder ubicarlos en el orden
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ColumnDelta {
+    added: Vec<(u64, u64)>,
+    removed: Vec<(u64, u64)>,
+}
+
+impl ColumnDelta {
+    fn remove(&mut self, entry: (u64, u64)) {
+        match self.added.iter().position(|e| e.1 == entry.1) {
+            Some(i) => {
+                self.added.remove(i);
+            }
+            None => self.removed.push(entry),
+        }
+    }
+
+    fn add(&mut self, entry: (u64, u64)) {
+        let i = self.added.partition_point(|e| *e < entry);
+        self.added.insert(i, entry);
+    }
+}
+
+// Índice ordenado por columna: un arreglo inmutable por columna más el delta de
+// actualizaciones y borrados, que la siguiente compactación incorpora. Los arreglos se
+// comparten entre copias, clonar el índice solo copia el delta.
+#[derive(Clone)]
+pub struct RangeIndex {
+    dir_path: PathBuf,
+    files: Arc<Vec<RangeFile>>,
+    delta: Vec<ColumnDelta>,
+}
+
+impl RangeIndex {
+    pub fn open(dir_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut delta: Vec<ColumnDelta> = match File::open(dir_path.join(DELTA_FILE)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(_) => Vec::new(),
+        };
+        delta.resize_with(RangeColumn::ALL.len(), Default::default);
+
+        let files = RangeColumn::ALL
+            .iter()
+            .map(|column| RangeFile::open(&dir_path.join(column.file_name())))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            dir_path: dir_path.to_path_buf(),
+            files: Arc::new(files),
+            delta,
+        })
+    }
+
+    // Cantidad exacta de registros vivos en el rango: la resta de dos búsquedas más
+    // lo que cambió después de construir
+    pub fn count(&self, column: RangeColumn, range: &KeyRange) -> usize {
+        let (from, to) = self.files[column.index()].seek(range);
+        let delta = &self.delta[column.index()];
+        let in_range = |entries: &[(u64, u64)]| {
+            entries
+                .iter()
+                .filter(|(key, _)| range.contains(*key))
+                .count()
+        };
+        to - from - in_range(&delta.removed) + in_range(&delta.added)
+    }
+
+    // Posiciones vivas en el rango, ordenadas por valor. Sin cambios pendientes en el
+    // rango la página es directamente un sub-arreglo del archivo.
+    pub fn positions(
+        &self,
+        column: RangeColumn,
+        range: &KeyRange,
+        skip: usize,
+        take: usize,
+    ) -> Vec<u64> {
+        let file = &self.files[column.index()];
+        let delta = &self.delta[column.index()];
+        let (from, to) = file.seek(range);
+
+        let removed: HashSet<u64> = delta
+            .removed
+            .iter()
+            .filter(|(key, _)| range.contains(*key))
+            .map(|(_, position)| *position)
+            .collect();
+        let added = delta.added.iter().filter(|(key, _)| range.contains(*key));
+
+        if removed.is_empty() && added.clone().next().is_none() {
+            let start = from.saturating_add(skip).min(to);
+            let end = start.saturating_add(take).min(to);
+            return (start..end)
+                .filter_map(|i| file.entry(i))
+                .map(|(_, position)| position)
+                .collect();
+        }
+
+        let base = (from..to)
+            .filter_map(|i| file.entry(i))
+            .filter(|(_, position)| !removed.contains(position));
+        MergeByKey {
+            left: base.peekable(),
+            right: added.copied().peekable(),
+        }
+        .skip(skip)
+        .take(take)
+        .map(|(_, position)| position)
+        .collect()
+    }
+
+    pub fn record_change(&mut self, old: Option<(u64, &Trip)>, new: Option<(u64, &Trip)>) {
+        for column in RangeColumn::ALL {
+            let delta = &mut self.delta[column.index()];
+            if let Some((position, trip)) = old
+                && let Some(key) = column.key(trip)
+            {
+                delta.remove((key, position));
+            }
+            if let Some((position, trip)) = new
+                && let Some(key) = column.key(trip)
+            {
+                delta.add((key, position));
+            }
+        }
+    }
+
+    // Los cambios se acumulan en memoria y se guardan una vez por lote de escrituras
+    pub fn save_delta(&self) -> Result<(), Box<dyn Error>> {
+        replace_file(
+            &self.dir_path.join(DELTA_FILE),
+            &serde_json::to_vec(&self.delta)?,
+        )
+    }
+}
+
+// Mezcla dos secuencias ya ordenadas por (clave, posición)
+struct MergeByKey<L: Iterator<Item = (u64, u64)>, R: Iterator<Item = (u64, u64)>> {
+    left: Peekable<L>,
+    right: Peekable<R>,
+}
+
+impl<L, R> Iterator for MergeByKey<L, R>
+where
+    L: Iterator<Item = (u64, u64)>,
+    R: Iterator<Item = (u64, u64)>,
+{
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match (self.left.peek(), self.right.peek()) {
+            (Some(l), Some(r)) if r < l => self.right.next(),
+            (Some(_), _) => self.left.next(),
+            (None, _) => self.right.next(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Directorio vacío y propio de cada prueba
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ranges-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn open_rejects_a_count_that_does_not_match_the_file() {
+        let dir = test_dir("bounds");
+        let mut builder = RangeBuilder::default();
+        for (position, distance) in [(0, 3.0), (40, 1.0), (80, 2.0)] {
+            let trip = Trip {
+                trip_distance: distance,
+                ..Trip::default()
+            };
+            builder.add(position, &trip);
+        }
+        builder.write(&dir).unwrap();
+
+        let path = dir.join(RangeColumn::TripDistance.file_name());
+        let file = RangeFile::open(&path).unwrap();
+        let (from, to) = file.seek(&KeyRange::amounts(Some(1.5), None));
+        let positions: Vec<u64> = (from..to)
+            .filter_map(|i| file.entry(i))
+            .map(|e| e.1)
+            .collect();
+        assert_eq!(positions, [80, 0]);
+        assert_eq!(file.entry(3), None);
+
+        // Una cantidad que no cuadra, y otra que desbordaría al multiplicarla
+        let mut bytes = fs::read(&path).unwrap();
+        for count in [4u64, u64::MAX / 8] {
+            bytes[4..RANGE_HEADER_LEN].copy_from_slice(&count.to_le_bytes());
+            fs::write(&path, &bytes).unwrap();
+            assert!(RangeFile::open(&path).is_err());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
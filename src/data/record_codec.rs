@@ -0,0 +1,213 @@
+use super::trip_struct::{FIELD_NAMES, StoreAndFwdFlag, Trip};
+use chrono::{DateTime, NaiveDateTime};
+use rust_decimal::Decimal;
+use std::error::Error;
+
+// Versión del formato binario de un Trip. Cambiarla si se altera el orden o el ancho
+// de algún campo; los registros con otra versión se rechazan al decodificar.
+pub const RECORD_VERSION: u8 = 1;
+
+// Tamaño fijo de un Trip codificado: versión, 6 enteros u32, 2 fechas (i64 segundos +
+// u32 nanosegundos), f64, bandera, 8 Decimal de 16 bytes y el índice u64.
+pub const ENCODED_TRIP_LEN: usize = 1 + 6 * 4 + 2 * 12 + 8 + 1 + 8 * 16 + 8;
+
+// Ancho de cada campo codificado, en el orden de FIELD_NAMES. El almacén columnar
+// guarda cada columna con estos mismos bytes.
+pub const FIELD_WIDTHS: [usize; FIELD_NAMES.len()] = [
+    4, 12, 12, 4, 8, 4, 1, 4, 4, 4, 16, 16, 16, 16, 16, 16, 16, 16, 8,
+];
+
+// Desplazamiento de un campo dentro del Trip codificado, contando el byte de versión
+pub fn field_offset(field: usize) -> usize {
+    1 + FIELD_WIDTHS[..field].iter().sum::<usize>()
+}
+
+// Escribe el Trip en little endian, en el orden de FIELD_NAMES. Los Decimal se guardan
+// con su representación nativa de 16 bytes para no perder escala ni precisión.
+pub fn encode_trip(trip: &Trip, out: &mut Vec<u8>) {
+    out.reserve(ENCODED_TRIP_LEN);
+    out.push(RECORD_VERSION);
+    out.extend_from_slice(&trip.vendor_id.to_le_bytes());
+    put_datetime(out, trip.tpep_pickup_datetime);
+    put_datetime(out, trip.tpep_dropoff_datetime);
+    out.extend_from_slice(&trip.passenger_count.to_le_bytes());
+    out.extend_from_slice(&trip.trip_distance.to_le_bytes());
+    out.extend_from_slice(&trip.ratecode_id.to_le_bytes());
+    out.push(match trip.store_and_fwd_flag {
+        StoreAndFwdFlag::Y => b'Y',
+        StoreAndFwdFlag::N => b'N',
+    });
+    out.extend_from_slice(&trip.pu_location_id.to_le_bytes());
+    out.extend_from_slice(&trip.do_location_id.to_le_bytes());
+    out.extend_from_slice(&trip.payment_type.to_le_bytes());
+    for amount in [
+        trip.fare_amount,
+        trip.extra,
+        trip.mta_tax,
+        trip.tip_amount,
+        trip.tolls_amount,
+        trip.improvement_surcharge,
+        trip.total_amount,
+        trip.congestion_surcharge,
+    ] {
+        out.extend_from_slice(&amount.serialize());
+    }
+    out.extend_from_slice(&trip.index.to_le_bytes());
+}
+
+pub fn decode_trip(bytes: &[u8]) -> Result<Trip, Box<dyn Error>> {
+    let mut reader = RecordReader { bytes };
+    let version = reader.take::<1>()?[0];
+    if version != RECORD_VERSION {
+        return Err(format!("Versión de registro desconocida: {}", version).into());
+    }
+
+    let trip = Trip {
+        vendor_id: reader.u32()?,
+        tpep_pickup_datetime: reader.datetime()?,
+        tpep_dropoff_datetime: reader.datetime()?,
+        passenger_count: reader.u32()?,
+        trip_distance: f64::from_le_bytes(reader.take()?),
+        ratecode_id: reader.u32()?,
+        store_and_fwd_flag: match reader.take::<1>()?[0] {
+            b'Y' => StoreAndFwdFlag::Y,
+            b'N' => StoreAndFwdFlag::N,
+            other => return Err(format!("Bandera inválida en el registro: {}", other).into()),
+        },
+        pu_location_id: reader.u32()?,
+        do_location_id: reader.u32()?,
+        payment_type: reader.u32()?,
+        fare_amount: reader.decimal()?,
+        extra: reader.decimal()?,
+        mta_tax: reader.decimal()?,
+        tip_amount: reader.decimal()?,
+        tolls_amount: reader.decimal()?,
+        improvement_surcharge: reader.decimal()?,
+        total_amount: reader.decimal()?,
+        congestion_surcharge: reader.decimal()?,
+        index: u64::from_le_bytes(reader.take()?),
+    };
+
+    if !reader.bytes.is_empty() {
+        return Err("Bytes sobrantes al final del registro".into());
+    }
+    Ok(trip)
+}
+
+fn put_datetime(out: &mut Vec<u8>, datetime: NaiveDateTime) {
+    let utc = datetime.and_utc();
+    out.extend_from_slice(&utc.timestamp().to_le_bytes());
+    out.extend_from_slice(&utc.timestamp_subsec_nanos().to_le_bytes());
+}
+
+struct RecordReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl RecordReader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], Box<dyn Error>> {
+        let (head, rest) = self
+            .bytes
+            .split_first_chunk::<N>()
+            .ok_or("Registro truncado")?;
+        self.bytes = rest;
+        Ok(*head)
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn datetime(&mut self) -> Result<NaiveDateTime, Box<dyn Error>> {
+        let seconds = i64::from_le_bytes(self.take()?);
+        let nanos = u32::from_le_bytes(self.take()?);
+        DateTime::from_timestamp(seconds, nanos)
+            .map(|d| d.naive_utc())
+            .ok_or_else(|| "Fecha fuera de rango en el registro".into())
+    }
+
+    fn decimal(&mut self) -> Result<Decimal, Box<dyn Error>> {
+        Ok(Decimal::deserialize(self.take()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use std::str::FromStr;
+
+    fn sample() -> Trip {
+        let day = NaiveDate::from_ymd_opt(2024, 1, 5).unwrap();
+        Trip {
+            vendor_id: 2,
+            tpep_pickup_datetime: day.and_hms_nano_opt(17, 3, 9, 123_456_789).unwrap(),
+            tpep_dropoff_datetime: day.and_hms_opt(17, 41, 0).unwrap(),
+            passenger_count: 3,
+            trip_distance: 12.75,
+            ratecode_id: 99,
+            store_and_fwd_flag: StoreAndFwdFlag::Y,
+            pu_location_id: 132,
+            do_location_id: 265,
+            payment_type: 4,
+            fare_amount: Decimal::from_str("52.10").unwrap(),
+            extra: Decimal::from_str("-0.5").unwrap(),
+            mta_tax: Decimal::from_str("0.5").unwrap(),
+            tip_amount: Decimal::from_str("10.561510000000002").unwrap(),
+            tolls_amount: Decimal::ZERO,
+            improvement_surcharge: Decimal::from_str("0.3").unwrap(),
+            total_amount: Decimal::from_str("69.96151").unwrap(),
+            congestion_surcharge: Decimal::from_str("2.50").unwrap(),
+            index: u64::MAX,
+        }
+    }
+
+    // Trip no implementa PartialEq; el JSON compara todos los campos, incluida la
+    // escala de los Decimal
+    fn same(a: &Trip, b: &Trip) -> bool {
+        serde_json::to_value(a).unwrap() == serde_json::to_value(b).unwrap()
+    }
+
+    #[test]
+    fn decode_returns_the_encoded_trip() {
+        let trip = sample();
+        let mut bytes = Vec::new();
+        encode_trip(&trip, &mut bytes);
+        assert_eq!(bytes.len(), ENCODED_TRIP_LEN);
+        assert_eq!(bytes[0], RECORD_VERSION);
+        assert!(same(&decode_trip(&bytes).unwrap(), &trip));
+
+        // Los desplazamientos de campo son los que usa el almacén columnar
+        assert_eq!(1 + FIELD_WIDTHS.iter().sum::<usize>(), ENCODED_TRIP_LEN);
+        let destination = field_offset(8);
+        assert_eq!(bytes[destination..destination + 4], 265u32.to_le_bytes());
+        let index = field_offset(FIELD_NAMES.len() - 1);
+        assert_eq!(bytes[index..], u64::MAX.to_le_bytes());
+
+        let default = Trip::default();
+        let mut bytes = Vec::new();
+        encode_trip(&default, &mut bytes);
+        assert!(same(&decode_trip(&bytes).unwrap(), &default));
+    }
+
+    #[test]
+    fn decode_rejects_damaged_records() {
+        let mut bytes = Vec::new();
+        encode_trip(&sample(), &mut bytes);
+
+        let mut other_version = bytes.clone();
+        other_version[0] = RECORD_VERSION + 1;
+        assert!(decode_trip(&other_version).is_err());
+
+        assert!(decode_trip(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode_trip(&[]).is_err());
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(decode_trip(&trailing).is_err());
+
+        let mut bad_flag = bytes.clone();
+        bad_flag[field_offset(6)] = b'X';
+        assert!(decode_trip(&bad_flag).is_err());
+    }
+}
@@ -1,24 +1,389 @@
+use chrono::NaiveDateTime;
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// Orden canónico de las columnas, el mismo que escribe datagen.py.
+pub const FIELD_NAMES: [&str; 19] = [
+    "vendor_id",
+    "tpep_pickup_datetime",
+    "tpep_dropoff_datetime",
+    "passenger_count",
+    "trip_distance",
+    "ratecode_id",
+    "store_and_fwd_flag",
+    "pu_location_id",
+    "do_location_id",
+    "payment_type",
+    "fare_amount",
+    "extra",
+    "mta_tax",
+    "tip_amount",
+    "tolls_amount",
+    "improvement_surcharge",
+    "total_amount",
+    "congestion_surcharge",
+    "index",
+];
+
+// Columnas que los exports de NYC TLC pueden no traer: congestion_surcharge no existe
+// antes de 2019 y index se numera con la fila cuando falta.
+pub const OPTIONAL_FIELDS: [&str; 2] = ["congestion_surcharge", "index"];
+pub const INDEX_FIELD: usize = 18;
+
+// datagen.py escribe ISO 8601, los exports de NYC TLC usan espacio como separador.
+const DATETIME_FORMATS: [&str; 4] = [
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum StoreAndFwdFlag {
+    Y,
+    #[default]
+    N,
+}
+
+impl fmt::Display for StoreAndFwdFlag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreAndFwdFlag::Y => write!(f, "Y"),
+            StoreAndFwdFlag::N => write!(f, "N"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Trip {
-    pub vendor_id: String,
-    pub tpep_pickup_datetime: String,
-    pub tpep_dropoff_datetime: String,
-    pub passenger_count: String,
-    pub trip_distance: String,
-    pub ratecode_id: String,
-    pub store_and_fwd_flag: String,
-    pub pu_location_id: String,
-    pub do_location_id: String,
-    pub payment_type: String,
-    pub fare_amount: String,
-    pub extra: String,
-    pub mta_tax: String,
-    pub tip_amount: String,
-    pub tolls_amount: String,
-    pub improvement_surcharge: String,
-    pub total_amount: String,
-    pub congestion_surcharge: String,
-    pub index: String,
+    pub vendor_id: u32,
+    pub tpep_pickup_datetime: NaiveDateTime,
+    pub tpep_dropoff_datetime: NaiveDateTime,
+    pub passenger_count: u32,
+    pub trip_distance: f64,
+    pub ratecode_id: u32,
+    pub store_and_fwd_flag: StoreAndFwdFlag,
+    pub pu_location_id: u32,
+    pub do_location_id: u32,
+    pub payment_type: u32,
+    pub fare_amount: Decimal,
+    pub extra: Decimal,
+    pub mta_tax: Decimal,
+    pub tip_amount: Decimal,
+    pub tolls_amount: Decimal,
+    pub improvement_surcharge: Decimal,
+    pub total_amount: Decimal,
+    pub congestion_surcharge: Decimal,
+    pub index: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldErrorKind {
+    Missing,
+    Integer,
+    Decimal,
+    Float,
+    Datetime,
+    Flag,
+}
+
+impl fmt::Display for FieldErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            FieldErrorKind::Missing => "campo vacío",
+            FieldErrorKind::Integer => "se esperaba un entero",
+            FieldErrorKind::Decimal => "se esperaba un decimal",
+            FieldErrorKind::Float => "se esperaba un número",
+            FieldErrorKind::Datetime => "se esperaba una fecha",
+            FieldErrorKind::Flag => "se esperaba Y o N",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub value: String,
+    pub kind: FieldErrorKind,
+}
+
+// Un registro puede fallar en varios campos a la vez, se reportan todos.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TripParseError {
+    pub errors: Vec<FieldError>,
+}
+
+impl fmt::Display for TripParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{} = {:?}: {}", error.field, error.value, error.kind)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for TripParseError {}
+
+struct FieldParser<'a> {
+    fields: &'a [&'a str],
+    wanted: &'a [bool; FIELD_NAMES.len()],
+    errors: Vec<FieldError>,
+}
+
+impl<'a> FieldParser<'a> {
+    fn raw(&mut self, pos: usize) -> Option<&'a str> {
+        let value = self.fields.get(pos).map(|v| v.trim()).unwrap_or("");
+        if value.is_empty() {
+            self.fail(pos, value, FieldErrorKind::Missing);
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    fn fail(&mut self, pos: usize, value: &str, kind: FieldErrorKind) {
+        self.errors.push(FieldError {
+            field: FIELD_NAMES[pos],
+            value: value.to_string(),
+            kind,
+        });
+    }
+
+    fn parse<T: Default>(
+        &mut self,
+        pos: usize,
+        kind: FieldErrorKind,
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> T {
+        // Los campos fuera de la proyección ni se validan
+        if !self.wanted[pos] {
+            return T::default();
+        }
+        let Some(value) = self.raw(pos) else {
+            return T::default();
+        };
+        match parse(value) {
+            Some(parsed) => parsed,
+            None => {
+                self.fail(pos, value, kind);
+                T::default()
+            }
+        }
+    }
+
+    fn integer<T: TryFrom<u64> + Default>(&mut self, pos: usize) -> T {
+        self.parse(pos, FieldErrorKind::Integer, |v| {
+            parse_integer(v).and_then(|n| T::try_from(n).ok())
+        })
+    }
+
+    fn decimal(&mut self, pos: usize) -> Decimal {
+        self.parse(pos, FieldErrorKind::Decimal, |v| {
+            Decimal::from_str(v)
+                .or_else(|_| Decimal::from_scientific(v))
+                .ok()
+        })
+    }
+
+    // Un campo opcional vacío vale cero
+    fn optional_decimal(&mut self, pos: usize) -> Decimal {
+        match self.fields.get(pos).map(|v| v.trim()) {
+            None | Some("") => Decimal::ZERO,
+            Some(_) => self.decimal(pos),
+        }
+    }
+
+    fn float(&mut self, pos: usize) -> f64 {
+        self.parse(pos, FieldErrorKind::Float, |v| {
+            v.parse::<f64>().ok().filter(|f| f.is_finite())
+        })
+    }
+
+    fn datetime(&mut self, pos: usize) -> NaiveDateTime {
+        self.parse(pos, FieldErrorKind::Datetime, parse_datetime)
+    }
+
+    fn flag(&mut self, pos: usize) -> StoreAndFwdFlag {
+        self.parse(pos, FieldErrorKind::Flag, |v| match v {
+            "Y" | "y" => Some(StoreAndFwdFlag::Y),
+            "N" | "n" => Some(StoreAndFwdFlag::N),
+            _ => None,
+        })
+    }
+}
+
+// Máscara de proyección a partir de nombres de campo.
+pub fn projection_mask(fields: &[&str]) -> [bool; FIELD_NAMES.len()] {
+    FIELD_NAMES.map(|name| fields.contains(&name))
+}
+
+// Algunos exports guardan los enteros como flotantes ("1.0").
+fn parse_integer(value: &str) -> Option<u64> {
+    value.parse::<u64>().ok().or_else(|| {
+        value
+            .parse::<f64>()
+            .ok()
+            .filter(|f| f.fract() == 0.0 && *f >= 0.0 && *f <= u64::MAX as f64)
+            .map(|f| f as u64)
+    })
+}
+
+pub fn parse_datetime(value: &str) -> Option<NaiveDateTime> {
+    DATETIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+}
+
+impl Trip {
+    // Construye un viaje a partir de los campos en el orden de FIELD_NAMES. Solo se
+    // validan los campos marcados en `wanted`, el resto queda en su valor por defecto.
+    pub fn from_fields_projected(
+        fields: &[&str],
+        wanted: &[bool; FIELD_NAMES.len()],
+    ) -> Result<Trip, TripParseError> {
+        let mut p = FieldParser {
+            fields,
+            wanted,
+            errors: Vec::new(),
+        };
+
+        let trip = Trip {
+            vendor_id: p.integer(0),
+            tpep_pickup_datetime: p.datetime(1),
+            tpep_dropoff_datetime: p.datetime(2),
+            passenger_count: p.integer(3),
+            trip_distance: p.float(4),
+            ratecode_id: p.integer(5),
+            store_and_fwd_flag: p.flag(6),
+            pu_location_id: p.integer(7),
+            do_location_id: p.integer(8),
+            payment_type: p.integer(9),
+            fare_amount: p.decimal(10),
+            extra: p.decimal(11),
+            mta_tax: p.decimal(12),
+            tip_amount: p.decimal(13),
+            tolls_amount: p.decimal(14),
+            improvement_surcharge: p.decimal(15),
+            total_amount: p.decimal(16),
+            congestion_surcharge: p.optional_decimal(17),
+            index: p.integer(INDEX_FIELD),
+        };
+
+        if p.errors.is_empty() {
+            Ok(trip)
+        } else {
+            Err(TripParseError { errors: p.errors })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROW: [&str; 19] = [
+        "2",
+        "2024-01-04 22:19:23",
+        "2024-01-04T22:30:40.5",
+        "1.0",
+        "9.95",
+        "1",
+        "n",
+        "44",
+        "38",
+        "2",
+        "21.5",
+        "0.5",
+        "0.5",
+        "2.1e0",
+        "0",
+        "0.3",
+        "24.8",
+        "",
+        "7",
+    ];
+
+    fn kinds(error: &TripParseError) -> Vec<(&'static str, FieldErrorKind)> {
+        error.errors.iter().map(|e| (e.field, e.kind)).collect()
+    }
+
+    #[test]
+    fn a_valid_row_parses_every_field() {
+        let trip = Trip::from_fields_projected(&ROW, &[true; FIELD_NAMES.len()]).unwrap();
+        assert_eq!(trip.vendor_id, 2);
+        assert_eq!(
+            trip.tpep_dropoff_datetime,
+            parse_datetime("2024-01-04 22:30:40.5").unwrap()
+        );
+        // Enteros escritos como flotante y decimales en notación científica
+        assert_eq!(trip.passenger_count, 1);
+        assert_eq!(trip.tip_amount, Decimal::new(21, 1));
+        assert_eq!(trip.store_and_fwd_flag, StoreAndFwdFlag::N);
+        // congestion_surcharge vacío vale cero
+        assert_eq!(trip.congestion_surcharge, Decimal::ZERO);
+        assert_eq!(trip.index, 7);
+    }
+
+    #[test]
+    fn every_bad_field_is_reported_with_its_kind() {
+        let mut row = ROW;
+        row[0] = "dos";
+        row[1] = "ayer";
+        row[3] = "1.5";
+        row[4] = "inf";
+        row[6] = "maybe";
+        row[10] = " ";
+        row[16] = "24,8";
+        row[18] = "-1";
+        let error = Trip::from_fields_projected(&row, &[true; FIELD_NAMES.len()]).unwrap_err();
+        assert_eq!(
+            kinds(&error),
+            vec![
+                ("vendor_id", FieldErrorKind::Integer),
+                ("tpep_pickup_datetime", FieldErrorKind::Datetime),
+                ("passenger_count", FieldErrorKind::Integer),
+                ("trip_distance", FieldErrorKind::Float),
+                ("store_and_fwd_flag", FieldErrorKind::Flag),
+                ("fare_amount", FieldErrorKind::Missing),
+                ("total_amount", FieldErrorKind::Decimal),
+                ("index", FieldErrorKind::Integer),
+            ]
+        );
+        assert!(
+            error
+                .to_string()
+                .starts_with("vendor_id = \"dos\": se esperaba un entero; ")
+        );
+
+        // Un entero que no entra en el tipo del campo también es un error
+        let mut row = ROW;
+        row[8] = "4294967296";
+        let error = Trip::from_fields_projected(&row, &[true; FIELD_NAMES.len()]).unwrap_err();
+        assert_eq!(
+            kinds(&error),
+            vec![("do_location_id", FieldErrorKind::Integer)]
+        );
+    }
+
+    #[test]
+    fn fields_outside_the_projection_are_not_validated() {
+        let mut row = ROW;
+        row[0] = "dos";
+        row[16] = "caro";
+        let wanted = projection_mask(&["do_location_id", "index"]);
+        let trip = Trip::from_fields_projected(&row, &wanted).unwrap();
+        assert_eq!(trip.do_location_id, 38);
+        assert_eq!(trip.total_amount, Decimal::ZERO);
+
+        // Una fila corta deja vacíos los campos que faltan
+        let error = Trip::from_fields_projected(&row[..9], &wanted).unwrap_err();
+        assert_eq!(kinds(&error), vec![("index", FieldErrorKind::Missing)]);
+    }
 }
@@ -0,0 +1,310 @@
+use super::disk_hash::{
+    DataStats, DiskHashTable, map_file, reindex_from_log, truncate_data, walk_log,
+};
+use super::record_codec::{decode_trip, encode_trip};
+use super::trip_struct::Trip;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+pub const WAL_FILE: &str = "wal.log";
+// Las entradas ya aplicadas se descartan recién cuando el WAL pasa de este tamaño, así
+// un lote normal no paga un fsync más por vaciarlo
+const WAL_TRUNCATE_BYTES: u64 = 4 * 1024 * 1024;
+
+// Una escritura ya decidida: con trip es un alta o reemplazo, sin trip un borrado. Las
+// condiciones (que la clave exista o no) se resuelven antes de registrarla, así que
+// reaplicar las entradas en orden desde cualquier estado intermedio deja el mismo
+// resultado.
+#[derive(Debug, Clone)]
+pub struct WalEntry {
+    pub lsn: u64,
+    pub key: String,
+    pub trip: Option<Trip>,
+}
+
+// [u32 tamaño][u32 crc32][u64 lsn][u16 largo de clave][clave][trip binario]. Igual que
+// en trip_data.bin, el tamaño cuenta lo que viene después de él y el crc32 cubre lo que
+// viene después del crc.
+fn encode_entry(entry: &WalEntry, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+    let key_len = u16::try_from(entry.key.len()).map_err(|_| "Clave demasiado larga")?;
+    let start = out.len();
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&entry.lsn.to_le_bytes());
+    out.extend_from_slice(&key_len.to_le_bytes());
+    out.extend_from_slice(entry.key.as_bytes());
+    if let Some(trip) = &entry.trip {
+        encode_trip(trip, out);
+    }
+    let size = (out.len() - start - 4) as u32;
+    let checksum = crc32fast::hash(&out[start + 8..]);
+    out[start..start + 4].copy_from_slice(&size.to_le_bytes());
+    out[start + 4..start + 8].copy_from_slice(&checksum.to_le_bytes());
+    Ok(())
+}
+
+// Registra las entradas y espera a que estén en disco. Es el único fsync que necesita
+// un lote antes de tocar los archivos del índice.
+pub fn append(dir_path: &Path, entries: &[WalEntry]) -> Result<(), Box<dyn Error>> {
+    let mut bytes = Vec::new();
+    for entry in entries {
+        encode_entry(entry, &mut bytes)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir_path.join(WAL_FILE))?;
+    file.write_all(&bytes)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+// Entradas íntegras del WAL, en orden. Lee hasta la primera entrada cortada o con
+// checksum inválido: es la escritura que estaba en curso y nunca se confirmó.
+pub fn read(dir_path: &Path) -> Result<Vec<WalEntry>, Box<dyn Error>> {
+    let mut bytes = Vec::new();
+    match File::open(dir_path.join(WAL_FILE)) {
+        Ok(file) => BufReader::new(file).read_to_end(&mut bytes)?,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    let mut entries = Vec::new();
+    let mut rest = bytes.as_slice();
+    while let Some((header, body)) = rest.split_first_chunk::<8>() {
+        let size = u32::from_le_bytes(header[..4].try_into()?) as usize;
+        let checksum = u32::from_le_bytes(header[4..].try_into()?);
+        let Some(entry) = size.checked_sub(4).and_then(|len| body.get(..len)) else {
+            break;
+        };
+        if entry.len() < 10 || crc32fast::hash(entry) != checksum {
+            break;
+        }
+        let lsn = u64::from_le_bytes(entry[..8].try_into()?);
+        let key_len = u16::from_le_bytes(entry[8..10].try_into()?) as usize;
+        let Some(key) = entry.get(10..10 + key_len) else {
+            break;
+        };
+        let trip_bytes = &entry[10 + key_len..];
+        entries.push(WalEntry {
+            lsn,
+            key: std::str::from_utf8(key)?.to_string(),
+            trip: if trip_bytes.is_empty() {
+                None
+            } else {
+                Some(decode_trip(trip_bytes)?)
+            },
+        });
+        rest = &body[entry.len()..];
+    }
+    Ok(entries)
+}
+
+// Descarta entradas ya aplicadas cuando el WAL creció lo suficiente
+pub fn maybe_truncate(dir_path: &Path) -> Result<(), Box<dyn Error>> {
+    let path = dir_path.join(WAL_FILE);
+    if path
+        .metadata()
+        .is_ok_and(|meta| meta.len() >= WAL_TRUNCATE_BYTES)
+    {
+        clear(dir_path)?;
+    }
+    Ok(())
+}
+
+fn clear(dir_path: &Path) -> Result<(), Box<dyn Error>> {
+    let path = dir_path.join(WAL_FILE);
+    if path.exists() {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(0)?;
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+// Entradas posteriores a la última guardada en data_stats.json
+pub fn pending(dir_path: &Path) -> Result<Vec<WalEntry>, Box<dyn Error>> {
+    let applied = DataStats::load(dir_path).wal_lsn;
+    let mut entries = read(dir_path)?;
+    entries.retain(|entry| entry.lsn > applied);
+    Ok(entries)
+}
+
+// Al arrancar: si el proceso murió con un lote a medias, sus escrituras pueden estar en
+// trip_data.bin pero no en los deltas de los índices secundarios. Se recorta una
+// escritura cortada, se rehacen la tabla y los índices con lo que quedó en el archivo
+// de datos y se reaplican las entradas pendientes. Devuelve cuántas se reaplicaron.
+pub fn replay(dir_path: &Path) -> Result<usize, Box<dyn Error>> {
+    let entries = pending(dir_path)?;
+    let Some(last) = entries.last().map(|entry| entry.lsn) else {
+        clear(dir_path)?;
+        return Ok(0);
+    };
+    println!("Reaplicando {} escrituras del WAL...", entries.len());
+
+    let unreadable_from = walk_log(&map_file(&dir_path.join("trip_data.bin"))?).unreadable_from;
+    if let Some(from) = unreadable_from {
+        let len = truncate_data(dir_path, from)?;
+        println!("trip_data.bin recortado de {} a {} bytes", len, from);
+    }
+    reindex_from_log(dir_path)?;
+
+    let mut hash_table = DiskHashTable::new(dir_path)?;
+    for entry in &entries {
+        match &entry.trip {
+            Some(trip) => hash_table.insert(entry.key.clone(), trip.clone())?,
+            None => {
+                hash_table.delete(&entry.key)?;
+            }
+        }
+    }
+    hash_table.set_wal_lsn(last);
+    hash_table.sync()?;
+    clear(dir_path)?;
+    Ok(entries.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::posting_index::LocationColumn;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // Directorio vacío y propio de cada prueba
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry(lsn: u64, index: u64, destination: Option<u32>) -> WalEntry {
+        WalEntry {
+            lsn,
+            key: index.to_string(),
+            trip: destination.map(|do_location_id| Trip {
+                index,
+                do_location_id,
+                ..Trip::default()
+            }),
+        }
+    }
+
+    fn destination(table: &DiskHashTable, index: u64) -> Option<u32> {
+        table
+            .get(&index.to_string())
+            .unwrap()
+            .map(|trip| trip.do_location_id)
+    }
+
+    // Índice con los viajes 1 y 2 confirmados y aplicados hasta el lsn 2
+    fn committed_index(name: &str) -> PathBuf {
+        let dir = test_dir(name);
+        let entries = [entry(1, 1, Some(10)), entry(2, 2, Some(20))];
+        append(&dir, &entries).unwrap();
+        let mut table = DiskHashTable::new(&dir).unwrap();
+        for entry in entries {
+            table.insert(entry.key, entry.trip.unwrap()).unwrap();
+        }
+        table.set_wal_lsn(2);
+        table.sync().unwrap();
+        dir
+    }
+
+    #[test]
+    fn read_returns_appended_entries_and_stops_at_a_torn_tail() {
+        let dir = test_dir("read");
+        append(&dir, &[entry(1, 10, Some(5)), entry(2, 11, None)]).unwrap();
+        let mut torn = Vec::new();
+        encode_entry(&entry(3, 12, Some(7)), &mut torn).unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(WAL_FILE))
+            .unwrap();
+        file.write_all(&torn[..torn.len() / 2]).unwrap();
+
+        let entries = read(&dir).unwrap();
+        assert_eq!(entries.iter().map(|e| e.lsn).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(entries[0].key, "10");
+        assert_eq!(entries[0].trip.as_ref().map(|t| t.do_location_id), Some(5));
+        assert!(entries[1].trip.is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn maybe_truncate_clears_only_past_the_limit() {
+        let dir = test_dir("truncate");
+        append(&dir, &[entry(1, 1, Some(1))]).unwrap();
+        maybe_truncate(&dir).unwrap();
+        assert_eq!(read(&dir).unwrap().len(), 1);
+
+        let batch: Vec<WalEntry> = (2..=40_000).map(|lsn| entry(lsn, lsn, Some(1))).collect();
+        append(&dir, &batch).unwrap();
+        assert!(fs::metadata(dir.join(WAL_FILE)).unwrap().len() >= WAL_TRUNCATE_BYTES);
+        maybe_truncate(&dir).unwrap();
+        assert!(read(&dir).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replay_applies_entries_logged_before_a_crash() {
+        let dir = committed_index("crash");
+        // El lote llegó al WAL pero el proceso murió antes de aplicarlo
+        append(
+            &dir,
+            &[
+                entry(3, 3, Some(10)),
+                entry(4, 1, None),
+                entry(5, 2, Some(30)),
+            ],
+        )
+        .unwrap();
+        assert_eq!(pending(&dir).unwrap().len(), 3);
+
+        assert_eq!(replay(&dir).unwrap(), 3);
+        let table = DiskHashTable::new(&dir).unwrap();
+        assert_eq!(destination(&table, 1), None);
+        assert_eq!(destination(&table, 2), Some(30));
+        assert_eq!(destination(&table, 3), Some(10));
+        assert_eq!(table.locations().count(LocationColumn::Dropoff, 10), 1);
+        assert_eq!(table.locations().count(LocationColumn::Dropoff, 20), 0);
+        assert_eq!(table.locations().count(LocationColumn::Dropoff, 30), 1);
+        assert_eq!(table.bitmaps().live().len(), 2);
+        assert_eq!(DataStats::load(&dir).wal_lsn, 5);
+        assert!(pending(&dir).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replay_recovers_a_batch_that_failed_halfway() {
+        let dir = committed_index("failed");
+        let batch = [entry(3, 3, Some(10)), entry(4, 2, Some(30))];
+        append(&dir, &batch).unwrap();
+        // La primera escritura llegó a trip_data.bin sin guardar los deltas ni el lsn y
+        // la segunda quedó cortada
+        let mut table = DiskHashTable::new(&dir).unwrap();
+        table
+            .insert(batch[0].key.clone(), batch[0].trip.clone().unwrap())
+            .unwrap();
+        drop(table);
+        OpenOptions::new()
+            .append(true)
+            .open(dir.join("trip_data.bin"))
+            .unwrap()
+            .write_all(&[7, 0, 0])
+            .unwrap();
+
+        assert_eq!(replay(&dir).unwrap(), 2);
+        let table = DiskHashTable::new(&dir).unwrap();
+        assert_eq!(destination(&table, 1), Some(10));
+        assert_eq!(destination(&table, 2), Some(30));
+        assert_eq!(destination(&table, 3), Some(10));
+        assert_eq!(table.locations().count(LocationColumn::Dropoff, 10), 2);
+        assert_eq!(table.locations().count(LocationColumn::Dropoff, 20), 0);
+        assert_eq!(table.locations().count(LocationColumn::Dropoff, 30), 1);
+        assert_eq!(table.bitmaps().live().len(), 3);
+        assert_eq!(DataStats::load(&dir).wal_lsn, 4);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
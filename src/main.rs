@@ -1,18 +1,59 @@
 use axum::serve;
+mod config;
 mod data;
 mod router_local;
 mod utils;
 use std::env;
+use std::process;
 use std::time::Instant;
 
-const CSV_PATH: &str = "src/data/data.csv";
+// `fsck [--repair]` revisa el índice sin levantar el servidor e imprime el reporte. Sale
+// con código 1 si el índice no quedó sano.
+fn run_fsck(args: &[String]) {
+    let repair = args.iter().any(|arg| arg == "--repair");
+    match data::check_index(repair) {
+        Ok(report) => {
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            if !report.clean {
+                process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("Error al verificar el índice hash: {}", e);
+            process::exit(2);
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args = match config::Config::load(&args) {
+        Ok((config, rest)) => {
+            config::init(config);
+            rest
+        }
+        Err(e) => {
+            eprintln!("Error al cargar la configuración: {}", e);
+            process::exit(2);
+        }
+    };
+    match args.first().map(String::as_str) {
+        Some("fsck") => {
+            run_fsck(&args[1..]);
+            return;
+        }
+        Some(arg) => {
+            eprintln!("Argumento desconocido: {}", arg);
+            process::exit(2);
+        }
+        None => {}
+    }
+
     // Inicializar índice hash
     let start = Instant::now();
-    let port = env::var("PORT").unwrap_or_else(|_| "3000".to_string());
-    let addr = format!("0.0.0.0:{}", port);
-    match data::filters::initialize_hash_index(CSV_PATH) {
+    let addr = &config::get().bind_address;
+    match data::filters::initialize_hash_index(data::dataset_path()) {
         Ok(count) => println!(
             "Índice hash inicializado con {} registros en {:?}",
             count,
@@ -20,7 +61,7 @@ async fn main() {
         ),
         Err(e) => {
             eprintln!("Error al inicializar índice hash: {}", e);
-            return;
+            process::exit(1);
         }
     }
 
@@ -28,7 +69,7 @@ async fn main() {
     let app = router_local::init();
 
     // Crear el listener con Tokio
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     println!("Server running on {}", addr);
 
     // En Axum 0.8.x, la función serve() funciona así:
@@ -0,0 +1,79 @@
+use crate::config::{self, Config};
+use crate::data::check_index;
+use crate::data::fsck::FsckReport;
+use crate::router_local::AppState;
+use axum::{
+    Json,
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::Response,
+    routing::{Router, get, post},
+};
+use std::sync::Arc;
+
+// Las rutas de administración exigen el token de la configuración en cada petición
+async fn require_token(
+    State(token): State<Arc<str>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, String)> {
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given {
+        Some(given) if same_token(given, &token) => Ok(next.run(request).await),
+        _ => Err((
+            StatusCode::UNAUTHORIZED,
+            "Falta el token de administración o no es válido".to_string(),
+        )),
+    }
+}
+
+// Compara sin cortar en el primer byte distinto, así el tiempo no delata el prefijo
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// La verificación recorre todo el índice y el dataset, va fuera del runtime
+async fn run_fsck(repair: bool) -> Result<Json<FsckReport>, (StatusCode, String)> {
+    let task = tokio::task::spawn_blocking(move || check_index(repair).map_err(|e| e.to_string()));
+    match task.await {
+        Ok(Ok(report)) => Ok(Json(report)),
+        Ok(Err(e)) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e))),
+    }
+}
+
+//Verificar el índice sin tocarlo
+async fn get_fsck() -> Result<Json<FsckReport>, (StatusCode, String)> {
+    run_fsck(false).await
+}
+
+//Verificar y reparar lo dañado
+async fn post_fsck_repair() -> Result<Json<FsckReport>, (StatusCode, String)> {
+    run_fsck(true).await
+}
+
+//Configuración con la que arrancó la instancia
+async fn get_config() -> Json<&'static Config> {
+    Json(config::get())
+}
+
+pub fn routes(token: &str) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/config", get(get_config))
+        .route("/fsck", get(get_fsck))
+        .route("/fsck/repair", post(post_fsck_repair))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::from(token),
+            require_token,
+        ))
+}
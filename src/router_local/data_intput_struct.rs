@@ -1,10 +1,13 @@
+#[allow(dead_code)]
 pub struct FilterByIndex {
     index: String,
 }
 
+#[allow(dead_code)]
 pub struct FitlerByFileRange {
     min_price: f64,
     max_price: f64,
 }
 
+#[allow(dead_code)]
 pub struct FilterByPricerange {}
@@ -1,13 +1,13 @@
-use crate::data::{get_trips_by_destination, get_trips_by_index, get_trips_by_price_range};
+use crate::config;
 use crate::utils;
 
+mod admin_routes;
 mod data_intput_struct;
 mod trip_rorutes;
 
 use axum::{Router, http::Method, response::IntoResponse, routing::get};
-use serde_json::json;
+use std::sync::Arc;
 use std::time::Instant;
-use std::{net::SocketAddr, sync::Arc};
 
 use tower_http::cors::{Any, CorsLayer};
 
@@ -17,6 +17,7 @@ use tower_http::cors::{Any, CorsLayer};
 * */
 
 pub struct AppState {
+    #[allow(dead_code)]
     start_time: Instant,
 }
 
@@ -37,13 +38,24 @@ pub fn init() -> Router {
 
     let cors = CorsLayer::new()
         .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
         .allow_headers(Any);
 
-    Router::new()
+    let mut router = Router::new()
         .route("/", get(hello_world))
         .route("/api", get(api_endpoint))
-        .nest("/trip", trip_rorutes::routes())
-        .with_state(state)
-        .layer(cors)
+        .nest("/trip", trip_rorutes::routes());
+    // Las rutas de administración reparan el índice y muestran la configuración, sin
+    // token no se exponen
+    match &config::get().admin_token {
+        Some(token) => router = router.nest("/admin", admin_routes::routes(token)),
+        None => println!("Sin admin_token, las rutas de /admin quedan deshabilitadas"),
+    }
+    router.with_state(state).layer(cors)
 }
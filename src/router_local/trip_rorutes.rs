@@ -1,13 +1,25 @@
+use crate::data::dataset::TimeRange;
+use crate::data::filter_dsl::parse_search;
+use crate::data::filter_params::parse_filter_params;
+use crate::data::filters::TripFilter;
 use crate::data::pagination::Pagination;
-use crate::data::{get_trips_by_destination, get_trips_by_index, get_trips_by_price_range};
+use crate::data::planner::ExplainMode;
+use crate::data::trip_struct::{Trip, parse_datetime};
+use crate::data::{
+    TimeField, create_trip, delete_trip, get_trips_by_destination, get_trips_by_index,
+    get_trips_by_price_range, get_trips_by_time_range, get_trips_with_complex_filter, search_trips,
+    update_trip,
+};
 use crate::router_local::AppState;
 use axum::{
     Json,
-    extract::{Path, Query, State},
+    extract::{Path, Query},
     http::StatusCode,
-    routing::{Router, get},
+    routing::{Router, get, post},
 };
-use serde::{Deserialize, Serialize};
+use chrono::NaiveDateTime;
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 #[derive(Debug, Deserialize)]
@@ -18,10 +30,7 @@ pub struct PaginationQuery {
 
 impl From<PaginationQuery> for Pagination {
     fn from(query: PaginationQuery) -> Self {
-        Self {
-            page: query.page.unwrap_or(1),
-            per_page: query.per_page.unwrap_or(50),
-        }
+        Self::from_query(query.page, query.per_page)
     }
 }
 
@@ -29,16 +38,79 @@ impl From<PaginationQuery> for Pagination {
 pub struct PriceRangeQuery {
     min: Option<f64>,
     max: Option<f64>,
+    sort: Option<String>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimeRangeQuery {
+    from: Option<String>,
+    to: Option<String>,
+    field: Option<TimeField>,
     page: Option<usize>,
     per_page: Option<usize>,
 }
 
+// Acepta los mismos formatos de fecha que el CSV
+fn parse_time_param(
+    name: &str,
+    value: Option<&str>,
+) -> Result<Option<NaiveDateTime>, (StatusCode, String)> {
+    value
+        .map(|v| {
+            parse_datetime(v).ok_or_else(|| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Fecha inválida en {}: {}", name, v),
+                )
+            })
+        })
+        .transpose()
+}
+
+// Condiciones extra de la query; `reserved` son los parámetros propios de la ruta
+fn filter_params(
+    params: &HashMap<String, String>,
+    reserved: &[&str],
+) -> Result<Option<TripFilter>, (StatusCode, String)> {
+    parse_filter_params(params, reserved).map_err(|e| (StatusCode::BAD_REQUEST, e))
+}
+
+// explain=true agrega el plan de la consulta a la respuesta, explain=only devuelve solo
+// el plan sin ejecutarla
+fn explain_param(params: &HashMap<String, String>) -> Result<ExplainMode, (StatusCode, String)> {
+    params
+        .get("explain")
+        .map_or(Ok(ExplainMode::Off), |value| value.parse())
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))
+}
+
+// sort=asc|desc ordena por importe; sin él queda el orden del archivo
+fn amount_sort_param(value: Option<&str>) -> Result<Option<bool>, (StatusCode, String)> {
+    match value {
+        None => Ok(None),
+        Some("asc") => Ok(Some(false)),
+        Some("desc") => Ok(Some(true)),
+        Some(other) => Err((
+            StatusCode::BAD_REQUEST,
+            format!("sort debe ser asc o desc, no {}", other),
+        )),
+    }
+}
+
 //Viaje por ID
 async fn get_trip_by_id(
     Path(id): Path<String>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
-    // Código existente sin cambios
-    match get_trips_by_index(&id) {
+    // Las lecturas bloquean en disco (mmap, CSV), van fuera del runtime como las escrituras
+    let task =
+        tokio::task::spawn_blocking(move || get_trips_by_index(&id).map_err(|e| e.to_string()));
+    match task
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result)
+    {
         Ok(Some(trip)) => {
             let json_trip = serde_json::to_value(trip).map_err(|e| {
                 (
@@ -53,18 +125,98 @@ async fn get_trip_by_id(
     }
 }
 
+//Agregar un viaje nuevo
+async fn post_trip(
+    Json(trip): Json<Trip>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, String)> {
+    // El commit espera el fsync del WAL y al resto del lote, va fuera del runtime
+    let task = tokio::task::spawn_blocking(move || create_trip(trip).map_err(|e| e.to_string()));
+    match task
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result)
+    {
+        Ok(Some(trip)) => {
+            let json_trip = serde_json::to_value(trip).map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Error de serialización: {}", e),
+                )
+            })?;
+            Ok((StatusCode::CREATED, Json(json_trip)))
+        }
+        Ok(None) => Err((
+            StatusCode::CONFLICT,
+            "Ya existe un viaje con ese índice".to_string(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e))),
+    }
+}
+
+//Corregir un viaje
+async fn put_trip_by_id(
+    Path(id): Path<u64>,
+    Json(trip): Json<Trip>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let task =
+        tokio::task::spawn_blocking(move || update_trip(id, trip).map_err(|e| e.to_string()));
+    match task
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result)
+    {
+        Ok(Some(trip)) => {
+            let json_trip = serde_json::to_value(trip).map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Error de serialización: {}", e),
+                )
+            })?;
+            Ok(Json(json_trip))
+        }
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Viaje no encontrado".to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e))),
+    }
+}
+
+//Borrar un viaje
+async fn delete_trip_by_id(Path(id): Path<u64>) -> Result<StatusCode, (StatusCode, String)> {
+    let task = tokio::task::spawn_blocking(move || delete_trip(id).map_err(|e| e.to_string()));
+    match task
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result)
+    {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((StatusCode::NOT_FOUND, "Viaje no encontrado".to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e))),
+    }
+}
+
 //Por rango de precio
 async fn get_trips_by_price(
     Query(query): Query<PriceRangeQuery>,
+    Query(params): Query<HashMap<String, String>>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let extra = filter_params(
+        &params,
+        &["min", "max", "sort", "page", "per_page", "explain"],
+    )?;
+    let explain = explain_param(&params)?;
+    let sort = amount_sort_param(query.sort.as_deref())?;
     let min = query.min.unwrap_or(0.0);
     let max = query.max.unwrap_or(f64::MAX);
-    let pagination = Pagination {
-        page: query.page.unwrap_or(1),
-        per_page: query.per_page.unwrap_or(50),
-    };
+    let pagination = Pagination::from_query(query.page, query.per_page);
 
-    match get_trips_by_price_range(min, max, pagination) {
+    let task = tokio::task::spawn_blocking(move || {
+        get_trips_by_price_range(min, max, extra, sort, pagination, explain)
+            .map_err(|e| e.to_string())
+    });
+    match task
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result)
+    {
         Ok(result) => {
             let json_result = serde_json::to_value(result).map_err(|e| {
                 (
@@ -80,12 +232,92 @@ async fn get_trips_by_price(
 
 //Por destino
 async fn get_trips_by_dest(
-    Path(destination): Path<String>,
+    Path(destination): Path<u32>,
+    Query(pagination): Query<PaginationQuery>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let extra = filter_params(&params, &["page", "per_page", "explain"])?;
+    let explain = explain_param(&params)?;
+    let pagination = Pagination::from(pagination);
+
+    let task = tokio::task::spawn_blocking(move || {
+        get_trips_by_destination(destination, extra, pagination, explain).map_err(|e| e.to_string())
+    });
+    match task
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result)
+    {
+        Ok(result) => {
+            let json_result = serde_json::to_value(result).map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Error de serialización: {}", e),
+                )
+            })?;
+            Ok(Json(json_result))
+        }
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e))),
+    }
+}
+
+//Por ventana de tiempo
+async fn get_trips_by_time(
+    Query(query): Query<TimeRangeQuery>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let extra = filter_params(
+        &params,
+        &["from", "to", "field", "page", "per_page", "explain"],
+    )?;
+    let explain = explain_param(&params)?;
+    let range = TimeRange {
+        from: parse_time_param("from", query.from.as_deref())?,
+        to: parse_time_param("to", query.to.as_deref())?,
+    };
+    let pagination = Pagination::from_query(query.page, query.per_page);
+
+    let field = query.field.unwrap_or_default();
+    let task = tokio::task::spawn_blocking(move || {
+        get_trips_by_time_range(field, range, extra, pagination, explain).map_err(|e| e.to_string())
+    });
+    match task
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result)
+    {
+        Ok(result) => {
+            let json_result = serde_json::to_value(result).map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Error de serialización: {}", e),
+                )
+            })?;
+            Ok(Json(json_result))
+        }
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e))),
+    }
+}
+
+//Por cualquier combinación de campos
+async fn search_trips_by_params(
     Query(pagination): Query<PaginationQuery>,
+    Query(params): Query<HashMap<String, String>>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    // Sin condiciones se listan todos los viajes
+    let filter = filter_params(&params, &["page", "per_page", "explain"])?
+        .unwrap_or(TripFilter::And(vec![]));
+    let explain = explain_param(&params)?;
     let pagination = Pagination::from(pagination);
 
-    match get_trips_by_destination(&destination, pagination) {
+    let task = tokio::task::spawn_blocking(move || {
+        get_trips_with_complex_filter(filter, pagination, explain).map_err(|e| e.to_string())
+    });
+    match task
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result)
+    {
         Ok(result) => {
             let json_result = serde_json::to_value(result).map_err(|e| {
                 (
@@ -99,9 +331,59 @@ async fn get_trips_by_dest(
     }
 }
 
+//Búsqueda con un árbol de condiciones en JSON, orden y proyección
+async fn post_search(
+    Json(body): Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let request = parse_search(&body).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    let fields = request.fields;
+    let task = tokio::task::spawn_blocking(move || {
+        search_trips(
+            request.filter,
+            request.sort,
+            request.pagination,
+            request.explain,
+        )
+        .map_err(|e| e.to_string())
+    });
+    match task
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result)
+    {
+        Ok(result) => {
+            let mut json_result = serde_json::to_value(result).map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Error de serialización: {}", e),
+                )
+            })?;
+            // La proyección deja en cada viaje solo los campos pedidos
+            if let Some(fields) = &fields
+                && let Some(items) = json_result["items"].as_array_mut()
+            {
+                for item in items.iter_mut().filter_map(|item| item.as_object_mut()) {
+                    item.retain(|key, _| fields.contains(&key.as_str()));
+                }
+            }
+            Ok(Json(json_result))
+        }
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e))),
+    }
+}
+
 pub fn routes() -> Router<Arc<AppState>> {
     Router::new()
-        .route("/{id}", get(get_trip_by_id))
+        .route("/", post(post_trip))
+        .route(
+            "/{id}",
+            get(get_trip_by_id)
+                .put(put_trip_by_id)
+                .delete(delete_trip_by_id),
+        )
         .route("/price", get(get_trips_by_price))
         .route("/destination/{dest}", get(get_trips_by_dest))
+        .route("/time", get(get_trips_by_time))
+        .route("/search", get(search_trips_by_params).post(post_search))
 }
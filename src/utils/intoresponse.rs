@@ -11,6 +11,7 @@ pub struct Message {
     message: String,
 }
 
+#[allow(dead_code)]
 pub enum ApiResponse {
     OK,
     Created,